@@ -0,0 +1,735 @@
+// 1541 floppy drive - a second 6502 running the DOS ROM with 2K of RAM and two VIAs: VIA1 talks to the
+// IEC bus, VIA2 controls the stepper motor, spindle and read/write head. The disk is modelled on byte level:
+// every few cycles the next GCR byte of the current track passes under the head.
+// The 1571 adds a second head (side select on VIA1), a 2 MHz mode, a CIA for the fast serial bus and a WD1770
+// for MFM disks. The 1581 is a 2 MHz 3.5" drive with 8K of RAM: a CIA talks to the IEC bus and a WD1770 reads
+// and writes the MFM sectors of the disk
+use c64::cia;
+use c64::cpu;
+use c64::d64;
+use c64::g64;
+use c64::gcr;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use c64::via;
+use c64::wd1770;
+use num::FromPrimitive;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+
+const CLOCK_FREQ: u32 = 1000000;
+
+// the head moves in half-tracks, track n is at half-track 2n
+const MIN_HALF_TRACK: u8 = 2;
+const MAX_HALF_TRACK: u8 = 84;
+
+
+// emulated drive model
+enum_from_primitive! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum DriveModel {
+        C1541, // D64 and G64 images
+        C1571, // D71 images as well, the second side can only be used in 1571 mode ("U0>M1")
+        C1581, // D81 images
+    }
+}
+
+impl Default for DriveModel {
+    fn default() -> DriveModel { DriveModel::C1541 }
+}
+
+impl DriveModel {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DriveModel::C1541 => "1541",
+            DriveModel::C1571 => "1571",
+            DriveModel::C1581 => "1581",
+        }
+    }
+
+
+    pub fn rom_file(&self) -> &'static str {
+        match *self {
+            DriveModel::C1541 => "rom/1541.rom",
+            DriveModel::C1571 => "rom/1571.rom",
+            DriveModel::C1581 => "rom/1581.rom",
+        }
+    }
+
+
+    fn rom_size(&self) -> usize {
+        match *self {
+            DriveModel::C1541 => 0x4000,
+            DriveModel::C1571 | DriveModel::C1581 => 0x8000,
+        }
+    }
+
+
+    fn ram_size(&self) -> usize {
+        match *self {
+            DriveModel::C1541 | DriveModel::C1571 => 0x0800,
+            DriveModel::C1581 => 0x2000,
+        }
+    }
+}
+
+
+// state of the IEC bus lines - true if the line is pulled low (asserted)
+#[derive(Clone, Copy)]
+pub struct IecLines {
+    pub atn:  bool,
+    pub clk:  bool,
+    pub data: bool,
+}
+
+
+// format of the inserted image - D64 and D71 images are GCR encoded on insertion and decoded again when written
+// back, the sectors of D81 images are accessed directly by the disk controller
+enum Image {
+    D64(d64::D64),
+    G64,
+    D81(d64::D64),
+}
+
+
+// everything the drive CPU can address
+struct DriveMemory {
+    model: DriveModel,
+    ram:  Vec<u8>,
+    rom:  Vec<u8>,
+    via1: via::VIA,         // 1541/1571 $1800: IEC bus
+    via2: via::VIA,         // 1541/1571 $1C00: disk controller
+    cia:  cia::DriveCIA,    // 1571 $4000: fast serial bus, 1581 $4000: IEC bus and drive control
+    fdc:  wd1770::WD1770,   // 1571 $2000, 1581 $6000
+    byte_ready: bool,       // 1571: BYTE READY on VIA1 PA7, cleared by reading the disk data from VIA2
+}
+
+impl cpu::Bus for DriveMemory {
+    fn read_byte(&mut self, addr: u16) -> u8 {
+        let ram_mask = self.ram.len() - 1;
+        let rom_mask = self.rom.len() - 1;
+
+        match (self.model, addr) {
+            (_, 0x8000...0xFFFF) => self.rom[(addr as usize) & rom_mask],
+            (DriveModel::C1581, 0x0000...0x1FFF) => self.ram[addr as usize],
+            (DriveModel::C1581, 0x4000...0x5FFF) => self.cia.read_register(addr as u8),
+            (DriveModel::C1581, 0x6000...0x7FFF) => self.fdc.read_register(addr as u8),
+            (DriveModel::C1581, _) => (addr >> 8) as u8, // open bus
+            (_, 0x0000...0x17FF) => self.ram[(addr as usize) & ram_mask],
+            (_, 0x1800...0x1BFF) => self.via1.read_register(addr as u8),
+            (_, 0x1C00...0x1FFF) => {
+                if (addr & 0x0F) == 0x01 || (addr & 0x0F) == 0x0F {
+                    self.byte_ready = false;
+                }
+                self.via2.read_register(addr as u8)
+            },
+            (DriveModel::C1571, 0x2000...0x3FFF) => self.fdc.read_register(addr as u8),
+            (DriveModel::C1571, 0x4000...0x7FFF) => self.cia.read_register(addr as u8),
+            _ => (addr >> 8) as u8,
+        }
+    }
+
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        let ram_mask = self.ram.len() - 1;
+
+        match (self.model, addr) {
+            (_, 0x8000...0xFFFF) => (),
+            (DriveModel::C1581, 0x0000...0x1FFF) => self.ram[addr as usize] = value,
+            (DriveModel::C1581, 0x4000...0x5FFF) => self.cia.write_register(addr as u8, value),
+            (DriveModel::C1581, 0x6000...0x7FFF) => self.fdc.write_register(addr as u8, value),
+            (DriveModel::C1581, _) => (),
+            (_, 0x0000...0x17FF) => self.ram[(addr as usize) & ram_mask] = value,
+            (_, 0x1800...0x1BFF) => self.via1.write_register(addr as u8, value),
+            (_, 0x1C00...0x1FFF) => self.via2.write_register(addr as u8, value),
+            (DriveModel::C1571, 0x2000...0x3FFF) => self.fdc.write_register(addr as u8, value),
+            (DriveModel::C1571, 0x4000...0x7FFF) => self.cia.write_register(addr as u8, value),
+            _ => (),
+        }
+    }
+}
+
+
+pub struct Drive {
+    model: DriveModel,
+    cpu: cpu::CPUShared,
+    mem: Rc<RefCell<DriveMemory>>,
+    cycle_count: u32,
+    clock_frac: u32, // drive clock accumulator, the drive runs at 1 or 2 MHz regardless of the C64 model
+    disk_frac: u32,  // the disk turns at the same speed at both clock rates, the mechanics are updated at 1 MHz
+    c64_clock_freq: u32,
+
+    // disk and mechanics
+    image: Option<(Image, String)>, // inserted image and the file modified tracks are written back to
+    disk: [g64::G64; 2],            // GCR data of each half-track on both sides (the 1541 only uses side 0)
+    dirty_tracks: [Vec<bool>; 2],
+    side: u8,
+    disk_changed: bool, // 1581: a disk was inserted or removed and the head hasn't been stepped since
+    half_track: u8,
+    head_pos: usize,   // offset of the byte under the head
+    byte_cycles: u8,   // cycles until the next byte passes the head
+    prev_byte: u8,
+    sync: bool,
+    stepper_phase: u8,
+    motor_on: bool,
+}
+
+impl Drive {
+    // fails if the DOS ROM can't be read
+    pub fn new(model: DriveModel, rom_file: &str, c64_clock_freq: u32) -> Result<Drive, String> {
+        let mut file = File::open(rom_file).map_err(|e| format!("{}: {}", rom_file, e))?;
+        let mut rom = Vec::<u8>::new();
+        file.read_to_end(&mut rom).map_err(|e| format!("{}: {}", rom_file, e))?;
+
+        if rom.len() != model.rom_size() {
+            return Err(format!("{} must be {} bytes", rom_file, model.rom_size()))
+        }
+
+        let mem = Rc::new(RefCell::new(DriveMemory {
+            model: model,
+            ram:  vec![0; model.ram_size()],
+            rom:  rom,
+            via1: via::VIA::new(),
+            via2: via::VIA::new(),
+            cia:  cia::DriveCIA::new(),
+            fdc:  wd1770::WD1770::new(),
+            byte_ready: false,
+        }));
+
+        let cpu = cpu::CPU::new_shared();
+        cpu.borrow_mut().set_bus(mem.clone());
+
+        let mut drive = Drive {
+            model: model,
+            cpu: cpu,
+            mem: mem,
+            cycle_count: 0,
+            clock_frac: 0,
+            disk_frac: 0,
+            c64_clock_freq: c64_clock_freq,
+            image: None,
+            disk: [g64::G64::new(), g64::G64::new()],
+            dirty_tracks: [vec![false; MAX_HALF_TRACK as usize + 1], vec![false; MAX_HALF_TRACK as usize + 1]],
+            side: 0,
+            disk_changed: false,
+            half_track: 36,
+            head_pos: 0,
+            byte_cycles: 0,
+            prev_byte: 0,
+            sync: false,
+            stepper_phase: 0,
+            motor_on: false,
+        };
+
+        drive.reset();
+        Ok(drive)
+    }
+
+
+    pub fn reset(&mut self) {
+        {
+            let mut mem = self.mem.borrow_mut();
+            mem.via1.reset();
+            mem.via2.reset();
+            mem.cia.reset();
+            mem.fdc.reset();
+            mem.byte_ready = false;
+        }
+
+        self.cpu.borrow_mut().reset();
+    }
+
+
+    // insert an image of a disk the drive model can read - the true drive can't use host directories
+    pub fn attach(&mut self, path: &str) -> Result<(), String> {
+        let (image, disk) = if path.to_lowercase().ends_with(".g64") {
+            if self.model == DriveModel::C1581 {
+                return Err("G64 images can't be used in a 1581".to_string())
+            }
+
+            (Image::G64, [g64::G64::from_filename(path)?, g64::G64::new()])
+        }
+        else {
+            let image = d64::D64::from_filename(path)?;
+            match (self.model, image.format()) {
+                (DriveModel::C1541, d64::Format::D64) | (DriveModel::C1571, d64::Format::D64) |
+                (DriveModel::C1571, d64::Format::D71) => {
+                    let disk = [g64::G64::from_d64(&image, 0), g64::G64::from_d64(&image, 1)];
+                    (Image::D64(image), disk)
+                },
+                (DriveModel::C1581, d64::Format::D81) => (Image::D81(image), [g64::G64::new(), g64::G64::new()]),
+                (model, format) => return Err(format!("{:?} images can't be used in a {}", format, model.name())),
+            }
+        };
+
+        self.detach();
+        self.disk = disk;
+        self.image = Some((image, path.to_string()));
+        Ok(())
+    }
+
+
+    pub fn detach(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Drive 8: can't write disk image: {}", e);
+        }
+
+        self.image = None;
+        self.disk = [g64::G64::new(), g64::G64::new()];
+        self.disk_changed = true;
+    }
+
+
+    // advance the drive by one C64 cycle - takes the lines pulled by the C64 and returns the resulting bus state
+    pub fn update(&mut self, c64_lines: IecLines) -> IecLines {
+        self.clock_frac += self.clock_freq();
+
+        while self.clock_frac >= self.c64_clock_freq {
+            self.clock_frac -= self.c64_clock_freq;
+            self.step_cycle(c64_lines);
+        }
+
+        let drive_lines = self.iec_outputs(c64_lines.atn);
+
+        IecLines {
+            atn:  c64_lines.atn,
+            clk:  c64_lines.clk  || drive_lines.clk,
+            data: c64_lines.data || drive_lines.data,
+        }
+    }
+
+
+    // ROMs and the disk contents are not part of the state
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.model as u8);
+        self.cpu.borrow().save_state(w);
+
+        {
+            let mem = self.mem.borrow();
+            w.write_bytes(&mem.ram);
+            mem.via1.save_state(w);
+            mem.via2.save_state(w);
+            mem.cia.save_state(w);
+            mem.fdc.save_state(w);
+            w.write_bool(mem.byte_ready);
+        }
+
+        w.write_u32(self.cycle_count);
+        w.write_u32(self.clock_frac);
+        w.write_u32(self.disk_frac);
+        w.write_u8(self.side);
+        w.write_bool(self.disk_changed);
+        w.write_u8(self.half_track);
+        w.write_u32(self.head_pos as u32);
+        w.write_u8(self.byte_cycles);
+        w.write_u8(self.prev_byte);
+        w.write_bool(self.sync);
+        w.write_u8(self.stepper_phase);
+        w.write_bool(self.motor_on);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let model = DriveModel::from_u8(r.read_u8()?).ok_or("Invalid drive model".to_string())?;
+        if model != self.model {
+            return Err(format!("Snapshot is for a different drive model ({})", model.name()))
+        }
+
+        self.cpu.borrow_mut().load_state(r)?;
+
+        {
+            let mut mem = self.mem.borrow_mut();
+            r.read_bytes(&mut mem.ram)?;
+            mem.via1.load_state(r)?;
+            mem.via2.load_state(r)?;
+            mem.cia.load_state(r)?;
+            mem.fdc.load_state(r)?;
+            mem.byte_ready = r.read_bool()?;
+        }
+
+        self.cycle_count = r.read_u32()?;
+        self.clock_frac  = r.read_u32()?;
+        self.disk_frac   = r.read_u32()?;
+        self.side        = r.read_u8()? & 0x01;
+        self.disk_changed = r.read_bool()?;
+        self.half_track  = r.read_u8()?;
+        self.head_pos    = r.read_u32()? as usize;
+        self.byte_cycles = r.read_u8()?;
+        self.prev_byte   = r.read_u8()?;
+        self.sync        = r.read_bool()?;
+        self.stepper_phase = r.read_u8()?;
+        self.motor_on      = r.read_bool()?;
+
+        if self.half_track < MIN_HALF_TRACK || self.half_track > MAX_HALF_TRACK {
+            return Err("Invalid drive head position".to_string())
+        }
+
+        Ok(())
+    }
+
+
+    // *** private functions *** //
+
+    // the 1571 switches to 2 MHz with VIA1 PA5, the 1581 always runs at 2 MHz
+    fn clock_freq(&self) -> u32 {
+        match self.model {
+            DriveModel::C1541 => CLOCK_FREQ,
+            DriveModel::C1571 if (self.mem.borrow().via1.port_a_output() & 0x20) == 0 => CLOCK_FREQ,
+            DriveModel::C1571 | DriveModel::C1581 => 2 * CLOCK_FREQ,
+        }
+    }
+
+
+    fn step_cycle(&mut self, c64_lines: IecLines) {
+        {
+            let drive_lines = self.iec_outputs(c64_lines.atn);
+            let clk  = c64_lines.clk  || drive_lines.clk;
+            let data = c64_lines.data || drive_lines.data;
+
+            // the bus lines are read inverted on VIA1 (CIA in the 1581) port B - device number jumpers (1541/1571
+            // PB5/PB6) are open for drive 8, the 1581 sees ATN on FLAG and the write protect sensor on PB6
+            let mut mem = self.mem.borrow_mut();
+            let lines = (data as u8) | ((clk as u8) << 2) | ((c64_lines.atn as u8) << 7);
+            if self.model == DriveModel::C1581 {
+                mem.cia.pb_in = lines | 0x40;
+                mem.cia.set_flag(!c64_lines.atn);
+            }
+            else {
+                mem.via1.pb_in = lines;
+                mem.via1.set_ca1(c64_lines.atn);
+            }
+        }
+
+        let clock_freq = self.clock_freq();
+        self.disk_frac += CLOCK_FREQ;
+        if self.disk_frac >= clock_freq {
+            self.disk_frac -= clock_freq;
+
+            if self.model == DriveModel::C1581 {
+                self.update_controller();
+            }
+            else {
+                self.rotate_disk();
+            }
+        }
+
+        {
+            let mut mem = self.mem.borrow_mut();
+            let irq = match self.model {
+                DriveModel::C1541 => {
+                    mem.via1.update();
+                    mem.via2.update();
+                    mem.via1.irq() || mem.via2.irq()
+                },
+                DriveModel::C1571 => {
+                    // VIA1 port A: track 0 sensor (PA0, low on track 1), BYTE READY (PA7, low while a byte is ready)
+                    let track_0 = if self.half_track == MIN_HALF_TRACK { 0x00 } else { 0x01 };
+                    let byte_ready = if mem.byte_ready { 0x00 } else { 0x80 };
+                    mem.via1.pa_in = 0x7E | track_0 | byte_ready;
+
+                    mem.via1.update();
+                    mem.via2.update();
+                    mem.cia.update();
+                    mem.via1.irq() || mem.via2.irq() || mem.cia.irq()
+                },
+                DriveModel::C1581 => {
+                    mem.cia.update();
+                    mem.cia.irq()
+                },
+            };
+            self.cpu.borrow_mut().via_irq = irq;
+        }
+
+        self.cpu.borrow_mut().update(self.cycle_count);
+        self.cycle_count = self.cycle_count.wrapping_add(1);
+    }
+
+
+    // lines pulled low by the drive - DATA is also pulled while ATN is asserted and not yet acknowledged (PB4)
+    fn iec_outputs(&self, atn: bool) -> IecLines {
+        let out = {
+            let mem = self.mem.borrow();
+            if self.model == DriveModel::C1581 { mem.cia.port_b_output() } else { mem.via1.port_b_output() }
+        };
+
+        IecLines {
+            atn:  false,
+            clk:  (out & 0x08) != 0,
+            data: (out & 0x02) != 0 || (((out & 0x10) != 0) != atn),
+        }
+    }
+
+
+    // VIA2 port B: stepper phase (PB0-1), motor (PB2), bit rate (PB5-6) - SYNC (PB7) and write protect (PB4) are inputs
+    // the 1571 selects the side with VIA1 PA2, its WD1770 only sees the index hole of the spinning disk
+    fn rotate_disk(&mut self) {
+        let out = self.mem.borrow().via2.port_b_output();
+
+        if self.model == DriveModel::C1571 {
+            self.side = (self.mem.borrow().via1.port_a_output() >> 2) & 0x01;
+        }
+
+        let phase = out & 0x03;
+        if phase == (self.stepper_phase + 1) & 0x03 && self.half_track < MAX_HALF_TRACK {
+            self.half_track += 1;
+        }
+        else if phase == (self.stepper_phase + 3) & 0x03 && self.half_track > MIN_HALF_TRACK {
+            self.half_track -= 1;
+        }
+        self.stepper_phase = phase;
+
+        let motor_on = (out & 0x04) != 0;
+        if self.motor_on && !motor_on {
+            if let Err(e) = self.flush() {
+                println!("Drive 8: can't write disk image: {}", e);
+            }
+        }
+        self.motor_on = motor_on;
+
+        if self.model == DriveModel::C1571 {
+            let spinning = self.motor_on && self.image.is_some();
+            self.mem.borrow_mut().fdc.update(None, self.side, spinning);
+        }
+
+        if self.motor_on {
+            if self.byte_cycles == 0 {
+                // 26 (zone 3) to 32 (zone 0) cycles per byte
+                self.byte_cycles = 31 - 2 * ((out >> 5) & 0x03);
+                self.next_byte();
+            }
+            else {
+                self.byte_cycles -= 1;
+            }
+        }
+
+        let mut mem = self.mem.borrow_mut();
+        mem.via2.pb_in = if self.sync { 0x10 } else { 0x90 };
+    }
+
+
+    // move the next byte under the head - read it or, in write mode (CB2 low), replace it with port A
+    fn next_byte(&mut self) {
+        let half_track = self.half_track as usize;
+        let side = self.side as usize;
+        let mut mem = self.mem.borrow_mut();
+
+        let track = self.disk[side].track_mut(self.half_track);
+        if track.is_empty() {
+            self.sync = false;
+            return;
+        }
+
+        self.head_pos = (self.head_pos + 1) % track.len();
+
+        if !mem.via2.cb2_output() {
+            track[self.head_pos] = mem.via2.port_a_output();
+            self.dirty_tracks[side][half_track] = true;
+            self.sync = false;
+        }
+        else {
+            let byte = track[self.head_pos];
+            self.sync = byte == 0xFF && self.prev_byte == 0xFF;
+            self.prev_byte = byte;
+            mem.via2.pa_in = byte;
+        }
+
+        // BYTE READY pulses CA1 and, if enabled by CA2, sets the overflow flag through the SO pin
+        if !self.sync {
+            mem.via2.set_ca1(false);
+            mem.via2.set_ca1(true);
+            mem.byte_ready = true;
+
+            if mem.via2.ca2_output() {
+                self.cpu.borrow_mut().set_status_flag(cpu::StatusFlag::Overflow, true);
+            }
+        }
+    }
+
+
+    // 1581 CIA port A: side select (PA0, low for the second head) and motor (PA2, low to turn it on) are outputs,
+    // /READY (PA1) and /DISK CHANGE (PA7) are inputs - the device number switches (PA3/PA4) are off for drive 8
+    fn update_controller(&mut self) {
+        let out = self.mem.borrow().cia.port_a_output();
+        self.side = if (out & 0x01) != 0 { 0 } else { 1 };
+
+        let motor_on = (out & 0x04) == 0;
+        if self.motor_on && !motor_on {
+            if let Err(e) = self.flush() {
+                println!("Drive 8: can't write disk image: {}", e);
+            }
+        }
+        self.motor_on = motor_on;
+
+        let mut mem = self.mem.borrow_mut();
+        let disk = match self.image {
+            Some((Image::D81(ref mut image), _)) => Some(image),
+            _ => None,
+        };
+        let has_disk = disk.is_some();
+        let spinning = self.motor_on && has_disk;
+        mem.fdc.update(disk, self.side, spinning);
+
+        if mem.fdc.take_step_pulse() && has_disk {
+            self.disk_changed = false;
+        }
+
+        let ready = if spinning { 0x00 } else { 0x02 };
+        let disk_change = if self.disk_changed { 0x00 } else { 0x80 };
+        mem.cia.pa_in = 0x65 | ready | disk_change;
+    }
+
+
+    // write modified tracks back to the image file - D64 and D71 images only keep what can be decoded from the
+    // full tracks
+    fn flush(&mut self) -> Result<(), String> {
+        let fdc_dirty = self.mem.borrow().fdc.dirty;
+        if !fdc_dirty && !self.dirty_tracks.iter().any(|side| side.iter().any(|&dirty| dirty)) {
+            return Ok(())
+        }
+
+        match self.image {
+            Some((Image::D64(ref mut image), ref filename)) => {
+                for track in 1..(image.num_tracks() + 1) {
+                    let (side, physical_track) = if image.format() == d64::Format::D71 && track > 35 {
+                        (1, track - 35)
+                    }
+                    else {
+                        (0, track)
+                    };
+
+                    if !self.dirty_tracks[side][2 * physical_track as usize] {
+                        continue;
+                    }
+
+                    for (sector, data) in gcr::decode_track(self.disk[side].track(2 * physical_track), track) {
+                        if sector < image.num_sectors(track) {
+                            image.write_sector(track, sector, &data)?;
+                        }
+                    }
+                }
+
+                image.save(filename)?;
+            },
+            Some((Image::G64, ref filename)) => self.disk[0].save(filename)?,
+            Some((Image::D81(ref image), ref filename)) => image.save(filename)?,
+            None => (),
+        }
+
+        for dirty in self.dirty_tracks.iter_mut().flat_map(|side| side.iter_mut()) {
+            *dirty = false;
+        }
+        self.mem.borrow_mut().fdc.dirty = false;
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    // a drive with a DOS ROM starting with the given program
+    fn drive(model: DriveModel, name: &str, program: &[u8]) -> Drive {
+        let mut rom = vec![0; model.rom_size()];
+        rom[..program.len()].copy_from_slice(program);
+        let len = rom.len();
+        rom[len - 4] = 0x00; // reset vector: $8000
+        rom[len - 3] = 0x80;
+
+        let path = env::temp_dir().join(format!("rust64_drive_{}.rom", name));
+        File::create(&path).and_then(|mut file| file.write_all(&rom)).unwrap();
+        let drive = Drive::new(model, path.to_str().unwrap(), 985248).unwrap();
+        fs::remove_file(&path).unwrap();
+        drive
+    }
+
+
+    // a D81 image in which each sector starts with its track and sector number
+    fn d81(name: &str) -> String {
+        let mut data = vec![0; 80 * 40 * d64::SECTOR_SIZE];
+        for (i, sector) in data.chunks_mut(d64::SECTOR_SIZE).enumerate() {
+            sector[0] = (i / 40) as u8 + 1;
+            sector[1] = (i % 40) as u8;
+        }
+
+        let path = env::temp_dir().join(format!("rust64_drive_{}.d81", name));
+        File::create(&path).and_then(|mut file| file.write_all(&data)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+
+    #[test]
+    fn images_match_the_drive_model() {
+        let path = d81("models");
+
+        let mut c1541 = drive(DriveModel::C1541, "models_1541", &[]);
+        assert_eq!(c1541.attach(&path), Err("D81 images can't be used in a 1541".to_string()));
+
+        let mut c1581 = drive(DriveModel::C1581, "models_1581", &[]);
+        assert_eq!(c1581.attach("protected.g64"), Err("G64 images can't be used in a 1581".to_string()));
+        assert!(c1581.attach(&path).is_ok());
+
+        let mut c1571 = drive(DriveModel::C1571, "models_1571", &[]);
+        assert_eq!(c1571.attach(&path), Err("D81 images can't be used in a 1571".to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+
+
+    #[test]
+    fn c1581_reads_a_sector() {
+        // turn the motor on, read sector 2 of the first track into $0300-$04FF, then wait
+        let program = [
+            0xA9, 0x65, 0x8D, 0x02, 0x40, // LDA #$65, STA $4002 (CIA DDRA)
+            0xA9, 0x01, 0x8D, 0x00, 0x40, // LDA #$01, STA $4000 (side 0, motor on)
+            0xA9, 0x02, 0x8D, 0x02, 0x60, // LDA #$02, STA $6002 (sector register)
+            0xA9, 0x00, 0x85, 0xFB,       // LDA #$00, STA $FB
+            0xA9, 0x03, 0x85, 0xFC,       // LDA #$03, STA $FC
+            0xA0, 0x00,                   // LDY #$00
+            0xA9, 0x88, 0x8D, 0x00, 0x60, // LDA #$88, STA $6000 (read sector)
+            0xAD, 0x00, 0x60,             // loop: LDA $6000
+            0x4A, 0x90, 0x0F,             // LSR, BCC done (not busy)
+            0x4A, 0x90, 0xF7,             // LSR, BCC loop (no DRQ)
+            0xAD, 0x03, 0x60,             // LDA $6003
+            0x91, 0xFB,                   // STA ($FB),Y
+            0xC8, 0xD0, 0xEF,             // INY, BNE loop
+            0xE6, 0xFC, 0xD0, 0xEB,       // INC $FC, BNE loop
+            0x4C, 0x33, 0x80,             // done: JMP done
+        ];
+
+        let path = d81("read");
+        let mut c1581 = drive(DriveModel::C1581, "read", &program);
+        c1581.attach(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines = IecLines { atn: false, clk: false, data: false };
+        for _ in 0..100000 {
+            c1581.update(lines);
+        }
+
+        {
+            let mem = c1581.mem.borrow();
+            assert_eq!((mem.ram[0x300], mem.ram[0x301], mem.ram[0x400], mem.ram[0x401]), (1, 2, 1, 3));
+            assert_eq!(mem.cia.pa_in & 0x82, 0x00); // ready, disk changed
+            assert!(c1581.motor_on);
+        }
+
+        // the state includes the controller and CIA, but only fits the same drive model
+        let mut w = SnapshotWriter::new();
+        c1581.save_state(&mut w);
+        let state = w.into_data();
+
+        let mut restored = drive(DriveModel::C1581, "read_restored", &program);
+        restored.load_state(&mut SnapshotReader::new(&state)).unwrap();
+        assert_eq!(restored.mem.borrow().ram, c1581.mem.borrow().ram);
+        assert_eq!(restored.mem.borrow_mut().fdc.read_register(2), 2);
+
+        let mut c1541 = drive(DriveModel::C1541, "read_1541", &[]);
+        assert_eq!(c1541.load_state(&mut SnapshotReader::new(&state)),
+                   Err("Snapshot is for a different drive model (1581)".to_string()));
+    }
+}
@@ -1,20 +1,26 @@
 // CIA chip
+use c64::c1541;
 use c64::cpu;
 use c64::memory;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
 use c64::vic;
+use num::FromPrimitive;
 use std::rc::Rc;
 use std::cell::RefCell;
 
 pub type CIAShared = Rc<RefCell<CIA>>;
 
-enum TimerState {
-    Stop,
-    WaitCount,
-    LoadStop,
-    LoadCount,
-    LoadWaitCount,
-    Count,
-    CountStop
+enum_from_primitive! {
+    #[derive(Clone, Copy)]
+    enum TimerState {
+        Stop,
+        WaitCount,
+        LoadStop,
+        LoadCount,
+        LoadWaitCount,
+        Count,
+        CountStop
+    }
 }
 
 
@@ -65,6 +71,35 @@ impl CIATimer {
     }
 
 
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.state as u8);
+        w.write_u16(self.value);
+        w.write_u16(self.latch);
+        w.write_u8(self.ctrl);
+        w.write_u8(self.new_ctrl);
+        w.write_bool(self.has_new_ctrl);
+        w.write_bool(self.is_cnt_phi2);
+        w.write_bool(self.irq_next_cycle);
+        w.write_bool(self.underflow);
+        w.write_bool(self.cnt_ta_underflow);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.state = TimerState::from_u8(r.read_u8()?).ok_or("Invalid CIA timer state".to_string())?;
+        self.value    = r.read_u16()?;
+        self.latch    = r.read_u16()?;
+        self.ctrl     = r.read_u8()?;
+        self.new_ctrl = r.read_u8()?;
+        self.has_new_ctrl     = r.read_bool()?;
+        self.is_cnt_phi2      = r.read_bool()?;
+        self.irq_next_cycle   = r.read_bool()?;
+        self.underflow        = r.read_bool()?;
+        self.cnt_ta_underflow = r.read_bool()?;
+        Ok(())
+    }
+
+
     pub fn update(&mut self, cia_icr: &mut u8, ta_underflow: bool) {
         match self.state {
             TimerState::Stop => (),
@@ -321,6 +356,85 @@ impl CIA {
     }
 
 
+    // IEC lines pulled low by CIA2 port A
+    pub fn iec_outputs(&self) -> c1541::IecLines {
+        let out = self.pra | !self.ddra;
+
+        c1541::IecLines {
+            atn:  (out & 0x08) != 0,
+            clk:  (out & 0x10) != 0,
+            data: (out & 0x20) != 0,
+        }
+    }
+
+
+    // state of the IEC bus as read back on CIA2 port A - a line reads 1 unless a device pulls it low
+    pub fn set_iec_inputs(&mut self, lines: c1541::IecLines) {
+        let clk  = if lines.clk  { 0x00 } else { 0x40 };
+        let data = if lines.data { 0x00 } else { 0x80 };
+        self.iec_lines = (self.iec_lines & 0x3F) | clk | data;
+    }
+
+
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        self.timer_a.save_state(w);
+        self.timer_b.save_state(w);
+        w.write_u8(self.irq_mask);
+        w.write_u8(self.icr);
+        w.write_u8(self.pra);
+        w.write_u8(self.prb);
+        w.write_u8(self.ddra);
+        w.write_u8(self.ddrb);
+        w.write_u8(self.sdr);
+        w.write_bool(self.tod_halt);
+        w.write_u16(self.tod_freq_div);
+        w.write_u8(self.tod_hour);
+        w.write_u8(self.tod_min);
+        w.write_u8(self.tod_sec);
+        w.write_u8(self.tod_dsec);
+        w.write_u8(self.alarm_hour);
+        w.write_u8(self.alarm_min);
+        w.write_u8(self.alarm_sec);
+        w.write_u8(self.alarm_dsec);
+        w.write_bytes(&self.key_matrix);
+        w.write_bytes(&self.rev_matrix);
+        w.write_u8(self.joystick_1);
+        w.write_u8(self.joystick_2);
+        w.write_u8(self.prev_lp);
+        w.write_u8(self.iec_lines);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.timer_a.load_state(r)?;
+        self.timer_b.load_state(r)?;
+        self.irq_mask = r.read_u8()?;
+        self.icr  = r.read_u8()?;
+        self.pra  = r.read_u8()?;
+        self.prb  = r.read_u8()?;
+        self.ddra = r.read_u8()?;
+        self.ddrb = r.read_u8()?;
+        self.sdr  = r.read_u8()?;
+        self.tod_halt     = r.read_bool()?;
+        self.tod_freq_div = r.read_u16()?;
+        self.tod_hour = r.read_u8()?;
+        self.tod_min  = r.read_u8()?;
+        self.tod_sec  = r.read_u8()?;
+        self.tod_dsec = r.read_u8()?;
+        self.alarm_hour = r.read_u8()?;
+        self.alarm_min  = r.read_u8()?;
+        self.alarm_sec  = r.read_u8()?;
+        self.alarm_dsec = r.read_u8()?;
+        r.read_bytes(&mut self.key_matrix)?;
+        r.read_bytes(&mut self.rev_matrix)?;
+        self.joystick_1 = r.read_u8()?;
+        self.joystick_2 = r.read_u8()?;
+        self.prev_lp    = r.read_u8()?;
+        self.iec_lines  = r.read_u8()?;
+        Ok(())
+    }
+
+
     pub fn update(&mut self) {
         self.timer_a.update(&mut self.icr, false);
         let ta_underflow = self.timer_a.underflow;
@@ -489,7 +603,7 @@ impl CIA {
             
             self.timer_a.irq_next_cycle = false
         }
-        if self.timer_a.irq_next_cycle {
+        if self.timer_b.irq_next_cycle {
             if self.trigger_irq(2) {
                 if self.is_cia1 {
                     as_mut!(self.cpu_ref).set_cia_irq(true);
@@ -499,7 +613,7 @@ impl CIA {
                 }
             }
             
-            self.timer_a.irq_next_cycle = false
+            self.timer_b.irq_next_cycle = false
         }
     }
 
@@ -587,6 +701,19 @@ impl CIA {
     }
 
 
+    // falling edge on the FLAG pin - on CIA1 it's connected to the cassette read line
+    pub fn trigger_flag(&mut self) {
+        if self.trigger_irq(0x10) {
+            if self.is_cia1 {
+                as_mut!(self.cpu_ref).set_cia_irq(true);
+            }
+            else {
+                as_mut!(self.cpu_ref).set_nmi(true);
+            }
+        }
+    }
+
+
     // true - irq triggered; false - not
     pub fn trigger_irq(&mut self, mask: u8) -> bool {
         self.icr |= mask;
@@ -670,7 +797,7 @@ impl CIA {
     fn read_cia2_register(&mut self, addr: u16) -> u8 {
         match addr {
             0xDD00 => {
-                // TODO
+                // bits 6 and 7 are the CLK and DATA lines of the IEC bus
                 (self.pra | !self.ddra) & 0x3f | self.iec_lines
             },
             0xDD01 => self.prb | !self.ddrb,
@@ -683,7 +810,7 @@ impl CIA {
     fn write_cia2_register(&mut self, addr: u16, value: u8, on_cia_write: &mut cpu::Callback) {
         match addr {
             0xDD00 => {
-                // TODO
+                // bits 3-5 pull the ATN, CLK and DATA lines of the IEC bus low (see iec_outputs)
                 self.pra = value;
                 as_mut!(self.vic_ref).on_va_change(!(self.pra | !self.ddra) & 3);
                 as_ref!(self.mem_ref).get_ram_bank(memory::MemType::Io).write(addr, value);
@@ -712,3 +839,223 @@ impl CIA {
         self.prev_lp = (self.prb | !self.ddrb) & 0x10;
     }
 }
+
+
+// 8520 CIA of the 1571 (fast serial bus) and the 1581 (IEC bus and drive control) - the timers work like in the
+// 6526, the TOD counter and the serial port are not used by the drives and only keep their register values
+pub struct DriveCIA {
+    timer_a: CIATimer,
+    timer_b: CIATimer,
+    irq_mask: u8,
+    icr:  u8,
+    pra:  u8,
+    prb:  u8,
+    ddra: u8,
+    ddrb: u8,
+    pub pa_in: u8, // levels of the port pins as driven by the outside world
+    pub pb_in: u8,
+    sdr:  u8,
+    tod:  [u8; 4],
+    flag: bool,    // current level of the FLAG input
+}
+
+impl DriveCIA {
+    pub fn new() -> DriveCIA {
+        DriveCIA {
+            timer_a: CIATimer::new(true),
+            timer_b: CIATimer::new(false),
+            irq_mask: 0,
+            icr:  0,
+            pra:  0,
+            prb:  0,
+            ddra: 0,
+            ddrb: 0,
+            pa_in: 0xFF,
+            pb_in: 0xFF,
+            sdr:  0,
+            tod:  [0; 4],
+            flag: true,
+        }
+    }
+
+
+    pub fn reset(&mut self) {
+        self.timer_a.reset();
+        self.timer_b.reset();
+        self.irq_mask = 0;
+        self.icr  = 0;
+        self.pra  = 0;
+        self.prb  = 0;
+        self.ddra = 0;
+        self.ddrb = 0;
+        self.sdr  = 0;
+        self.tod  = [0; 4];
+    }
+
+
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        self.timer_a.save_state(w);
+        self.timer_b.save_state(w);
+        w.write_u8(self.irq_mask);
+        w.write_u8(self.icr);
+        w.write_u8(self.pra);
+        w.write_u8(self.prb);
+        w.write_u8(self.ddra);
+        w.write_u8(self.ddrb);
+        w.write_u8(self.pa_in);
+        w.write_u8(self.pb_in);
+        w.write_u8(self.sdr);
+        w.write_bytes(&self.tod);
+        w.write_bool(self.flag);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.timer_a.load_state(r)?;
+        self.timer_b.load_state(r)?;
+        self.irq_mask = r.read_u8()?;
+        self.icr   = r.read_u8()?;
+        self.pra   = r.read_u8()?;
+        self.prb   = r.read_u8()?;
+        self.ddra  = r.read_u8()?;
+        self.ddrb  = r.read_u8()?;
+        self.pa_in = r.read_u8()?;
+        self.pb_in = r.read_u8()?;
+        self.sdr   = r.read_u8()?;
+        r.read_bytes(&mut self.tod)?;
+        self.flag  = r.read_bool()?;
+        Ok(())
+    }
+
+
+    // count down both timers by one cycle - like in the 6526, an underflow interrupt shows up one cycle later
+    pub fn update(&mut self) {
+        if self.timer_a.irq_next_cycle {
+            self.trigger_irq(1);
+            self.timer_a.irq_next_cycle = false;
+        }
+        if self.timer_b.irq_next_cycle {
+            self.trigger_irq(2);
+            self.timer_b.irq_next_cycle = false;
+        }
+
+        self.timer_a.update(&mut self.icr, false);
+        let ta_underflow = self.timer_a.underflow;
+        self.timer_b.update(&mut self.icr, ta_underflow);
+    }
+
+
+    // is the IRQ output active?
+    pub fn irq(&self) -> bool {
+        (self.icr & 0x80) != 0
+    }
+
+
+    pub fn read_register(&mut self, reg: u8) -> u8 {
+        match reg & 0x0F {
+            0x00 => (self.pra & self.ddra) | (self.pa_in & !self.ddra),
+            0x01 => (self.prb & self.ddrb) | (self.pb_in & !self.ddrb),
+            0x02 => self.ddra,
+            0x03 => self.ddrb,
+            0x04 =>  self.timer_a.value as u8,
+            0x05 => (self.timer_a.value >> 8) as u8,
+            0x06 =>  self.timer_b.value as u8,
+            0x07 => (self.timer_b.value >> 8) as u8,
+            0x08...0x0B => self.tod[(reg & 0x03) as usize],
+            0x0C => self.sdr,
+            0x0D => {
+                let curr_icr = self.icr;
+                self.icr = 0;
+                curr_icr
+            },
+            0x0E => self.timer_a.ctrl,
+            0x0F => self.timer_b.ctrl,
+            _ => unreachable!(),
+        }
+    }
+
+
+    pub fn write_register(&mut self, reg: u8, value: u8) {
+        match reg & 0x0F {
+            0x00 => self.pra = value,
+            0x01 => self.prb = value,
+            0x02 => self.ddra = value,
+            0x03 => self.ddrb = value,
+            0x04 => self.timer_a.latch = (self.timer_a.latch & 0xFF00) | value as u16,
+            0x05 => {
+                self.timer_a.latch = (self.timer_a.latch & 0x00FF) | ((value as u16) << 8);
+                if (self.timer_a.ctrl & 1) == 0 {
+                    self.timer_a.value = self.timer_a.latch;
+                }
+            },
+            0x06 => self.timer_b.latch = (self.timer_b.latch & 0xFF00) | value as u16,
+            0x07 => {
+                self.timer_b.latch = (self.timer_b.latch & 0x00FF) | ((value as u16) << 8);
+                if (self.timer_b.ctrl & 1) == 0 {
+                    self.timer_b.value = self.timer_b.latch;
+                }
+            },
+            0x08...0x0B => self.tod[(reg & 0x03) as usize] = value,
+            0x0C => {
+                self.sdr = value;
+                self.trigger_irq(8);
+            },
+            0x0D => {
+                if (value & 0x80) != 0 {
+                    self.irq_mask |= value & 0x7F;
+                }
+                else {
+                    self.irq_mask &= !value;
+                }
+
+                if (self.icr & self.irq_mask & 0x1F) != 0 {
+                    self.icr |= 0x80;
+                }
+            },
+            0x0E => {
+                self.timer_a.has_new_ctrl = true;
+                self.timer_a.new_ctrl = value;
+                self.timer_a.is_cnt_phi2 = (value & 0x20) == 0;
+            },
+            0x0F => {
+                self.timer_b.has_new_ctrl = true;
+                self.timer_b.new_ctrl = value;
+                self.timer_b.is_cnt_phi2 = (value & 0x60) == 0;
+                self.timer_b.cnt_ta_underflow = (value & 0x60) == 0x40;
+            },
+            _ => unreachable!(),
+        }
+    }
+
+
+    // port pins as seen from the outside - pins configured as inputs are pulled up
+    pub fn port_a_output(&self) -> u8 {
+        self.pra | !self.ddra
+    }
+
+
+    pub fn port_b_output(&self) -> u8 {
+        self.prb | !self.ddrb
+    }
+
+
+    // change the FLAG input level - a falling edge sets the FLAG interrupt
+    pub fn set_flag(&mut self, level: bool) {
+        if self.flag && !level {
+            self.trigger_irq(0x10);
+        }
+
+        self.flag = level;
+    }
+
+
+    // *** private functions *** //
+
+    fn trigger_irq(&mut self, mask: u8) {
+        self.icr |= mask;
+
+        if (self.irq_mask & mask) != 0 {
+            self.icr |= 0x80;
+        }
+    }
+}
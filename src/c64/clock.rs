@@ -1,32 +1,69 @@
-// timing clock structure
+// timing clock structure - paces the emulation to real time a whole frame at a time
 extern crate time;
 
+use std::thread;
+use std::time::Duration;
+
+// if the emulation falls behind by more than this (in seconds), don't try to catch up
+const MAX_LAG: f64 = 0.1;
+
 pub struct Clock {
-    curr_time: f64,
-    last_time: f64,
-    clock_period: f64,
+    next_time: f64, // wall clock time at which the emulated time is reached
+    freq: f64,      // nominal frequency at 100% speed
+    speed: u32,     // speed in percent of the nominal frequency
+    warp: bool,     // don't wait at all
 }
 
 impl Clock {
     pub fn new(freq: f64) -> Clock {
-        let mut clock = Clock {
-            curr_time: 0.0,
-            last_time: 0.0,
-            clock_period: 1.0 / freq,
-        };
-
-        clock.last_time = time::precise_time_s();
-        clock
+        Clock {
+            next_time: time::precise_time_s(),
+            freq: freq,
+            speed: 100,
+            warp: false,
+        }
     }
 
-    pub fn tick(&mut self) -> bool {
-        self.curr_time = time::precise_time_s();
 
-        if self.curr_time - self.last_time >= self.clock_period {
-            self.last_time = self.curr_time;
-            return true
+    // account for the given number of emulated cycles and sleep until they are due in real time
+    pub fn sync(&mut self, cycles: u32) {
+        let curr_time = time::precise_time_s();
+
+        if self.warp {
+            self.next_time = curr_time;
+            return;
+        }
+
+        self.next_time += cycles as f64 * 100.0 / (self.freq * self.speed as f64);
+
+        if self.next_time > curr_time {
+            let delay = self.next_time - curr_time;
+            thread::sleep(Duration::new(delay as u64, (delay.fract() * 1e9) as u32));
+        }
+        else if curr_time - self.next_time > MAX_LAG {
+            self.next_time = curr_time;
         }
+    }
+
+
+    pub fn set_speed(&mut self, speed: u32) {
+        self.speed = if speed > 0 { speed } else { 1 };
+        self.next_time = time::precise_time_s();
+    }
+
+
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+
+    pub fn set_warp(&mut self, warp: bool) {
+        self.warp = warp;
+        self.next_time = time::precise_time_s();
+    }
+
 
-        false
+    pub fn warp(&self) -> bool {
+        self.warp
     }
 }
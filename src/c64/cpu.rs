@@ -3,7 +3,9 @@ use c64::cia;
 use c64::memory;
 use c64::opcodes;
 use c64::sid;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
 use c64::vic;
+use num::FromPrimitive;
 use std::cell::RefCell;
 use std::rc::Rc;
 use utils;
@@ -38,13 +40,33 @@ pub enum Callback {
     ClearNMI
 }
 
-pub enum CPUState {
-    FetchOp,
-    FetchOperandAddr,
-    PerformRMW,
-    ProcessIRQ,
-    ProcessNMI,
-    ExecuteOp
+// memory map of a CPU outside of the C64 (the 1541 drive CPU) - replaces the C64 memory and chips
+pub trait Bus {
+    fn read_byte(&mut self, addr: u16) -> u8;
+    fn write_byte(&mut self, addr: u16, value: u8);
+}
+
+// CPU register values for inspection
+#[derive(Clone, Copy, Debug)]
+pub struct Registers {
+    pub pc: u16,
+    pub sp: u8,
+    pub p:  u8,
+    pub a:  u8,
+    pub x:  u8,
+    pub y:  u8,
+}
+
+enum_from_primitive! {
+    #[derive(Clone, Copy)]
+    pub enum CPUState {
+        FetchOp,
+        FetchOperandAddr,
+        PerformRMW,
+        ProcessIRQ,
+        ProcessNMI,
+        ExecuteOp
+    }
 }
 
 
@@ -60,10 +82,12 @@ pub struct CPU {
     pub cia1_ref: Option<cia::CIAShared>,
     pub cia2_ref: Option<cia::CIAShared>,
     pub sid_ref:  Option<sid::SIDShared>,
+    pub bus_ref:  Option<Rc<RefCell<dyn Bus>>>,
     pub instruction: opcodes::Instruction,
     pub ba_low:  bool,  // is BA low?
     pub cia_irq: bool,
     pub vic_irq: bool,
+    pub via_irq: bool, // level triggered IRQ line of the drive VIAs and CIA
     pub irq_cycles_left: u8,
     pub nmi_cycles_left: u8,
     pub first_nmi_cycle: u32,
@@ -90,9 +114,11 @@ impl CPU {
             cia1_ref: None,
             cia2_ref: None,
             sid_ref:  None,
+            bus_ref:  None,
             ba_low:  false,
             cia_irq: false,
             vic_irq: false,
+            via_irq: false,
             irq_cycles_left: 0,
             nmi_cycles_left: 0,
             first_nmi_cycle: 0,
@@ -114,7 +140,13 @@ impl CPU {
         self.cia1_ref = Some(cia1ref);
         self.cia2_ref = Some(cia2ref);
         self.sid_ref  = Some(sidref);
-    }    
+    }
+
+
+    // run the CPU on a different memory map than the C64's
+    pub fn set_bus(&mut self, bus: Rc<RefCell<dyn Bus>>) {
+        self.bus_ref = Some(bus);
+    }
     
 
     pub fn set_status_flag(&mut self, flag: StatusFlag, value: bool) {
@@ -135,6 +167,18 @@ impl CPU {
     }
     
 
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp,
+            p:  self.p,
+            a:  self.a,
+            x:  self.x,
+            y:  self.y,
+        }
+    }
+
+
     pub fn reset(&mut self) {
         let pc = self.read_word_le(RESET_VECTOR);
         self.pc = pc;
@@ -144,18 +188,63 @@ impl CPU {
     }
 
 
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u16(self.pc);
+        w.write_u8(self.sp);
+        w.write_u8(self.p);
+        w.write_u8(self.a);
+        w.write_u8(self.x);
+        w.write_u8(self.y);
+        self.instruction.save_state(w);
+        w.write_bool(self.ba_low);
+        w.write_bool(self.cia_irq);
+        w.write_bool(self.vic_irq);
+        w.write_u8(self.irq_cycles_left);
+        w.write_u8(self.nmi_cycles_left);
+        w.write_u32(self.first_nmi_cycle);
+        w.write_u32(self.first_irq_cycle);
+        w.write_u8(self.state as u8);
+        w.write_bool(self.nmi);
+        w.write_u16(self.prev_pc);
+        w.write_u8(self.dfff_byte);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u8()?;
+        self.p  = r.read_u8()?;
+        self.a  = r.read_u8()?;
+        self.x  = r.read_u8()?;
+        self.y  = r.read_u8()?;
+        self.instruction.load_state(r)?;
+        self.ba_low  = r.read_bool()?;
+        self.cia_irq = r.read_bool()?;
+        self.vic_irq = r.read_bool()?;
+        self.irq_cycles_left = r.read_u8()?;
+        self.nmi_cycles_left = r.read_u8()?;
+        self.first_nmi_cycle = r.read_u32()?;
+        self.first_irq_cycle = r.read_u32()?;
+        self.state = CPUState::from_u8(r.read_u8()?).ok_or("Invalid CPU state".to_string())?;
+        self.nmi = r.read_bool()?;
+        self.prev_pc   = r.read_u16()?;
+        self.dfff_byte = r.read_u8()?;
+        Ok(())
+    }
+
+
     pub fn update(&mut self, c64_cycle_cnt: u32) {
         // check for irq and nmi
         match self.state {
             CPUState::FetchOp => {
-                if self.nmi && self.nmi_cycles_left == 0 && (c64_cycle_cnt - (self.first_nmi_cycle as u32) >= 2) {
+                if self.nmi && self.nmi_cycles_left == 0 && (c64_cycle_cnt.wrapping_sub(self.first_nmi_cycle) >= 2) {
                     self.nmi_cycles_left = 7;
                     self.state = CPUState::ProcessNMI;
                 }
                 else if !self.get_status_flag(StatusFlag::InterruptDisable) {
-                    let irq_ready = (self.cia_irq || self.vic_irq) && self.irq_cycles_left == 0;
+                    let irq_ready = (self.cia_irq || self.vic_irq || self.via_irq) && self.irq_cycles_left == 0;
 
-                    if irq_ready && (c64_cycle_cnt - (self.first_irq_cycle as u32) >= 2) {
+                    if irq_ready && (c64_cycle_cnt.wrapping_sub(self.first_irq_cycle) >= 2) {
                         self.irq_cycles_left = 7;
                         self.state = CPUState::ProcessIRQ;
                     }
@@ -283,6 +372,11 @@ impl CPU {
 
 
     pub fn write_byte(&mut self, addr: u16, value: u8) -> bool {
+        if let Some(ref bus) = self.bus_ref {
+            bus.borrow_mut().write_byte(addr, value);
+            return true;
+        }
+
         let mut on_write = Callback::None;
         let mut mem_write_ok = true;
         let io_enabled = as_ref!(self.mem_ref).io_on;
@@ -294,6 +388,7 @@ impl CPU {
  /* color RAM */ 0xD800...0xDBFF => mem_write_ok = as_mut!(self.mem_ref).write_byte(addr, value & 0x0F),
  /*    CIA1   */ 0xDC00...0xDCFF => as_mut!(self.cia1_ref).write_register(addr, value, &mut on_write),
  /*    CIA2   */ 0xDD00...0xDDFF => as_mut!(self.cia2_ref).write_register(addr, value, &mut on_write),
+ /* I/O1, I/O2 */ 0xDE00...0xDFFF => as_mut!(self.mem_ref).write_io_expansion(addr, value, &mut on_write),
                  _               => mem_write_ok = as_mut!(self.mem_ref).write_byte(addr, value),
             }
         }
@@ -317,6 +412,10 @@ impl CPU {
     
 
     pub fn read_byte(&mut self, addr: u16) -> u8 {
+        if let Some(ref bus) = self.bus_ref {
+            return bus.borrow_mut().read_byte(addr);
+        }
+
         let byte: u8;
         let mut on_read = Callback::None;
         let io_enabled = as_ref!(self.mem_ref).io_on;
@@ -328,6 +427,10 @@ impl CPU {
    /* color RAM */ 0xD800...0xDBFF => byte = (as_ref!(self.mem_ref).read_byte(addr) & 0x0F) | (as_ref!(self.vic_ref).last_byte & 0xF0),
    /*   CIA1    */ 0xDC00...0xDCFF => byte = as_mut!(self.cia1_ref).read_register(addr, &mut on_read),
    /*   CIA2    */ 0xDD00...0xDDFF => byte = as_mut!(self.cia2_ref).read_register(addr, &mut on_read),
+   /* I/O1, I/O2 */ 0xDE00...0xDFFF if as_ref!(self.mem_ref).has_cartridge() => {
+                       let value = as_mut!(self.mem_ref).read_io_expansion(addr);
+                       byte = value.unwrap_or(as_ref!(self.vic_ref).last_byte);
+                   },
                    0xDF00...0xDF9F => byte = as_ref!(self.vic_ref).last_byte,
                    0xDFFF => {
                        self.dfff_byte = !self.dfff_byte;
@@ -353,6 +456,11 @@ impl CPU {
 
 
     pub fn read_word_le(&self, addr: u16) -> u16 {
+        if let Some(ref bus) = self.bus_ref {
+            let mut bus = bus.borrow_mut();
+            return (bus.read_byte(addr) as u16) | ((bus.read_byte(addr.wrapping_add(1)) as u16) << 8);
+        }
+
         as_ref!(self.mem_ref).read_word_le(addr)
     }
 
@@ -527,8 +635,8 @@ impl CPU {
             },
             2 => {
                 if !self.instruction.zp_crossed {
-                    self.first_irq_cycle += 1;
-                    self.first_nmi_cycle += 1;
+                    self.first_irq_cycle = self.first_irq_cycle.wrapping_add(1);
+                    self.first_nmi_cycle = self.first_nmi_cycle.wrapping_add(1);
                 }
                 if self.ba_low { return false; }
                 
@@ -579,7 +687,7 @@ impl CPU {
             },
             1 => {
                 if self.ba_low { return false; }
-                self.pc = as_ref!(self.mem_ref).read_word_le(new_pc);
+                self.pc = self.read_word_le(new_pc);
             }
             _ => panic!("Invalid IRQ/NMI cycle")
         }
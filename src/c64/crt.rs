@@ -1,13 +1,28 @@
-use std;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::str;
 use std::fmt;
-use c64::memory;
+use c64::cpu;
+use c64::flash;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
 
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use num::FromPrimitive;
 
+const HEADER_LEN: u32 = 0x40;
+const CHIP_HEADER_LEN: u32 = 0x10;
+
+// EasyFlash: 64 banks in each of the two flash chips, the modified chips are written back to the *.crt
+// once no writes happened for this many frames
+const EASYFLASH_BANKS: usize = 64;
+const EASYFLASH_RAM_SIZE: usize = 0x100;
+const FLUSH_DELAY_FRAMES: u32 = 50;
+
+// freezers: RAM of the Action Replay and the Retro Replay, the I/O2 area shows its last page or that of the ROM bank
+const ACTION_REPLAY_RAM_SIZE: usize = 0x2000;
+const RETRO_REPLAY_RAM_SIZE: usize = 0x8000;
+const IO2_OFFSET: usize = 0x1F00;
+
 #[derive(Debug)]
 pub struct Crt {
     header: Header,
@@ -28,8 +43,8 @@ impl Crt {
         let mut version = [0u8;2];
         file.read(&mut version).map_err(|e| e.to_string())?;
         let hw_type = file.read_u16::<BigEndian>().map_err(|e| e.to_string())?;
-        if hw_type != 0 {
-            return Err("Unsupported cartridge type".to_string())
+        if HwType::from_u16(hw_type).is_none() {
+            return Err(format!("Unsupported cartridge type {}", hw_type))
         }
         let exrom = file.read_u8().map_err(|e| e.to_string())?;
         let game = file.read_u8().map_err(|e| e.to_string())?;
@@ -81,15 +96,446 @@ impl Crt {
         })
     }
 
-    pub fn load_into_memory(&self, mut memory: std::cell::RefMut<memory::Memory>) {
-        memory.exrom = self.header.exrom == 1;
-        memory.game = self.header.game == 1;
-        for chip in self.chips.iter() {
-            let base_addr = chip.load_addr;
-            for (offset, byte) in chip.data.iter().enumerate() {
-                memory.write_byte(base_addr+offset as u16, *byte);
+}
+
+
+// hardware types with bank switching - the type number is stored in the header of the *.crt file
+enum_from_primitive! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum HwType {
+        Normal = 0,
+        ActionReplay = 1,
+        FinalCartridge3 = 3,
+        Ocean = 5,
+        FunPlay = 7,
+        SuperGames = 8,
+        C64GameSystem = 15, // also used by System 3
+        Dinamic = 17,
+        MagicDesk = 19,
+        EasyFlash = 32,
+        RetroReplay = 36,
+    }
+}
+
+
+// a cartridge plugged into the expansion port - its ROM banks and the bank switching logic of its type
+// EXROM and GAME are true while the lines are high (inactive)
+pub struct Cartridge {
+    hw_type: HwType,
+    roml: Vec<Vec<u8>>, // 8K banks at $8000
+    romh: Vec<Vec<u8>>, // 8K banks at $A000
+    initial_exrom: bool,
+    initial_game: bool,
+    bank: usize,
+    exrom: bool,
+    game: bool,
+    locked: bool, // Super Games: the bank register can't be written until the next reset, freezers: disabled
+    header: Header,   // kept to write modified flash back to the *.crt
+    filename: String,
+    ram: Vec<u8>,     // EasyFlash: 256 bytes at $DF00, Action Replay/Retro Replay: 8K/32K
+    ram_on: bool,     // Action Replay/Retro Replay: RAM instead of ROM at ROML and I/O2
+    ram_banked: bool, // Retro Replay: the bank bits select the RAM bank as well
+    no_freeze: bool,  // Retro Replay: the freeze button is ignored
+    configured: bool, // Retro Replay: the extended control register has been written
+    flash_low: flash::Flash,  // EasyFlash: chip behind ROML
+    flash_high: flash::Flash, // EasyFlash: chip behind ROMH
+    dirty: bool,      // flash modified since the *.crt was last written
+    idle_frames: u32, // frames since the last flash write
+}
+
+impl Cartridge {
+    // split the chips into 8K banks - 16K chips fill ROML and ROMH of their bank
+    // the EasyFlash chips are complete, with erased flash where the image has no data
+    pub fn new(crt: Crt, filename: &str) -> Cartridge {
+        let hw_type = HwType::from_u16(crt.header.hw_type).unwrap_or(HwType::Normal);
+        let mut num_banks = crt.chips.iter().map(|chip| chip.bank_number as usize + 1).max().unwrap_or(1);
+        if hw_type == HwType::EasyFlash {
+            num_banks = num_banks.max(EASYFLASH_BANKS);
+        }
+
+        let mut roml = vec![Vec::<u8>::new(); num_banks];
+        let mut romh = vec![Vec::<u8>::new(); num_banks];
+
+        for chip in crt.chips.into_iter() {
+            let bank = chip.bank_number as usize;
+            match chip.load_addr {
+                0x8000 if chip.data.len() > 0x2000 => {
+                    roml[bank] = chip.data[..0x2000].to_vec();
+                    romh[bank] = chip.data[0x2000..].to_vec();
+                },
+                0x8000 => roml[bank] = chip.data,
+                _ => romh[bank] = chip.data,
+            }
+        }
+
+        if hw_type == HwType::EasyFlash {
+            for data in roml.iter_mut().chain(romh.iter_mut()) {
+                data.resize(flash::BANK_SIZE, 0xFF);
+            }
+        }
+
+        let mut cartridge = Cartridge {
+            hw_type: hw_type,
+            roml: roml,
+            romh: romh,
+            initial_exrom: crt.header.exrom != 0,
+            initial_game: crt.header.game != 0,
+            bank: 0,
+            exrom: true,
+            game: true,
+            locked: false,
+            header: crt.header,
+            filename: filename.to_string(),
+            ram: vec![0; match hw_type {
+                HwType::EasyFlash => EASYFLASH_RAM_SIZE,
+                HwType::ActionReplay => ACTION_REPLAY_RAM_SIZE,
+                HwType::RetroReplay => RETRO_REPLAY_RAM_SIZE,
+                _ => 0,
+            }],
+            ram_on: false,
+            ram_banked: false,
+            no_freeze: false,
+            configured: false,
+            flash_low: flash::Flash::new(),
+            flash_high: flash::Flash::new(),
+            dirty: false,
+            idle_frames: 0,
+        };
+
+        cartridge.reset();
+        cartridge
+    }
+
+
+    pub fn reset(&mut self) {
+        self.bank = 0;
+        self.exrom = self.initial_exrom;
+        self.game = self.initial_game;
+        self.locked = false;
+        self.ram_on = false;
+        self.ram_banked = false;
+        self.no_freeze = false;
+        self.configured = false;
+        self.flash_low.reset();
+        self.flash_high.reset();
+    }
+
+
+    // the freeze button - switches a freezer to Ultimax mode with its first bank, returns false if the cartridge
+    // has none, the NMI is up to the caller
+    pub fn freeze(&mut self) -> bool {
+        match self.hw_type {
+            HwType::ActionReplay | HwType::FinalCartridge3 => (),
+            HwType::RetroReplay if !self.no_freeze => (),
+            _ => return false,
+        }
+
+        self.bank = 0;
+        self.exrom = true;
+        self.game = false;
+        self.locked = false;
+        self.ram_on = false;
+        true
+    }
+
+
+    pub fn hw_type(&self) -> HwType {
+        self.hw_type
+    }
+
+
+    pub fn exrom(&self) -> bool {
+        self.exrom
+    }
+
+
+    pub fn game(&self) -> bool {
+        self.game
+    }
+
+
+    // selected bank - changes with the bank switching registers
+    pub fn bank(&self) -> usize {
+        self.bank
+    }
+
+
+    // byte of the selected ROML bank ($8000-$9FFF), unused ROM reads as $FF
+    pub fn read_roml(&self, addr: u16) -> u8 {
+        match self.hw_type {
+            HwType::EasyFlash => self.flash_low.read(&self.roml, self.bank, (addr & 0x1FFF) as usize),
+            HwType::ActionReplay | HwType::RetroReplay if self.ram_on => self.ram[self.ram_offset(addr)],
+            _ => read_rom(&self.roml, self.bank, (addr & 0x1FFF) as usize),
+        }
+    }
+
+
+    // byte of the selected ROMH bank ($A000-$BFFF, $E000-$FFFF in Ultimax mode)
+    pub fn read_romh(&self, addr: u16) -> u8 {
+        match self.hw_type {
+            HwType::EasyFlash => self.flash_high.read(&self.romh, self.bank, (addr & 0x1FFF) as usize),
+            // a single 8K ROM shows up at ROML and ROMH
+            HwType::ActionReplay | HwType::RetroReplay => read_rom(&self.roml, self.bank, (addr & 0x1FFF) as usize),
+            _ => read_rom(&self.romh, self.bank, (addr & 0x1FFF) as usize),
+        }
+    }
+
+
+    // write to the cartridge ROM area ($8000-$9FFF, $E000-$FFFF in Ultimax mode) - flash chips and RAM at ROML
+    // take it, returns true if the ROM banks read differently afterwards
+    pub fn write_rom(&mut self, addr: u16, value: u8) -> bool {
+        match self.hw_type {
+            HwType::ActionReplay | HwType::RetroReplay if self.ram_on && addr >= 0x8000 && addr <= 0x9FFF => {
+                let offset = self.ram_offset(addr);
+                self.ram[offset] = value;
+                return true
+            },
+            // the flash chips can only be written in Ultimax mode
+            HwType::EasyFlash if self.exrom && !self.game => (),
+            _ => return false,
+        }
+
+        let offset = (addr & 0x1FFF) as usize;
+        let changed = match addr {
+            0x8000...0x9FFF => self.flash_low.write(&mut self.roml, self.bank, offset, value),
+            0xE000...0xFFFF => self.flash_high.write(&mut self.romh, self.bank, offset, value),
+            _ => return false,
+        };
+
+        self.idle_frames = 0;
+        if changed {
+            self.dirty = true;
+        }
+
+        changed
+    }
+
+
+    // called once per frame - modified flash is written back when the software is done with it
+    pub fn update(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        self.idle_frames += 1;
+        if self.idle_frames >= FLUSH_DELAY_FRAMES {
+            self.flush();
+        }
+    }
+
+
+    // write the flash contents back to the *.crt
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let result = File::create(&self.filename)
+            .and_then(|mut file| file.write_all(&self.to_bytes()))
+            .map_err(|e| e.to_string());
+
+        if let Err(e) = result {
+            println!("Cartridge: can't write {}: {}", self.filename, e);
+        }
+
+        self.dirty = false;
+    }
+
+
+    // access to I/O1 ($DE00-$DEFF) or I/O2 ($DF00-$DFFF) - returns None if the cartridge doesn't drive the bus
+    pub fn read_io(&mut self, addr: u16) -> Option<u8> {
+        match (self.hw_type, addr) {
+            // reading any I/O1 address selects the bank given by the address
+            (HwType::Dinamic, 0xDE00...0xDEFF) => self.bank = (addr & 0x0F) as usize,
+            (HwType::C64GameSystem, 0xDE00...0xDEFF) => self.bank = 0,
+            (HwType::EasyFlash, 0xDF00...0xDFFF) => return Some(self.ram[(addr & 0xFF) as usize]),
+            (HwType::ActionReplay, 0xDF00...0xDFFF) | (HwType::RetroReplay, 0xDF00...0xDFFF) if !self.locked => {
+                let io2_addr = IO2_OFFSET as u16 | (addr & 0xFF);
+                return Some(self.read_roml(io2_addr))
+            },
+            // the ROM bank shows through both I/O areas, the code of the cartridge runs from there
+            (HwType::FinalCartridge3, 0xDE00...0xDFFF) => {
+                return Some(read_rom(&self.roml, self.bank, (addr & 0x1FFF) as usize))
+            },
+            _ => (),
+        }
+
+        None
+    }
+
+
+    // returns true if the ROM banks read differently afterwards although bank and lines are the same
+    // (RAM written or switched in) - a cartridge pulling NMI sets the callback
+    pub fn write_io(&mut self, addr: u16, value: u8, on_write: &mut cpu::Callback) -> bool {
+        let ram_on = self.ram_on;
+
+        match (self.hw_type, addr) {
+            (HwType::Ocean, 0xDE00...0xDEFF) => self.bank = (value & 0x3F) as usize,
+            (HwType::MagicDesk, 0xDE00...0xDEFF) => {
+                // bit 7 switches the cartridge off
+                self.bank = (value & 0x7F) as usize;
+                self.exrom = (value & 0x80) != 0;
+            },
+            (HwType::FunPlay, 0xDE00...0xDEFF) => {
+                // the bank number is scrambled, $86 switches the cartridge off
+                self.bank = (((value >> 3) & 0x07) | ((value & 0x01) << 3)) as usize;
+                match value & 0xC6 {
+                    0x00 => self.exrom = false,
+                    0x86 => self.exrom = true,
+                    _ => (),
+                }
+            },
+            (HwType::SuperGames, 0xDF00...0xDFFF) if !self.locked => {
+                // 16K banks, bit 2 switches the cartridge off and bit 3 locks the register
+                self.bank = (value & 0x03) as usize;
+                self.exrom = (value & 0x04) != 0;
+                self.game = (value & 0x04) != 0;
+                self.locked = (value & 0x08) != 0;
+            },
+            (HwType::C64GameSystem, 0xDE00...0xDEFF) => self.bank = (addr & 0x3F) as usize,
+            (HwType::EasyFlash, 0xDE00) => self.bank = (value & 0x3F) as usize,
+            (HwType::EasyFlash, 0xDE02) => {
+                // bit 1 pulls EXROM, bit 0 pulls GAME if bit 2 is set - otherwise the boot jumper pulls GAME
+                self.exrom = (value & 0x02) == 0;
+                self.game = (value & 0x04) != 0 && (value & 0x01) == 0;
+            },
+            (HwType::EasyFlash, 0xDF00...0xDFFF) => self.ram[(addr & 0xFF) as usize] = value,
+            (HwType::ActionReplay, 0xDE00...0xDEFF) if !self.locked => self.write_control(value),
+            (HwType::RetroReplay, 0xDE00) if !self.locked => self.write_control(value),
+            (HwType::RetroReplay, 0xDE01) if !self.locked => {
+                // RAM banking and the freeze button can only be configured once after reset
+                if !self.configured {
+                    self.ram_banked = (value & 0x02) != 0;
+                    self.no_freeze = (value & 0x04) != 0;
+                    self.configured = true;
+                }
+                self.bank = (((value >> 3) & 0x03) | ((value >> 5) & 0x04)) as usize;
+            },
+            (HwType::ActionReplay, 0xDF00...0xDFFF) | (HwType::RetroReplay, 0xDF00...0xDFFF)
+                if !self.locked && self.ram_on => {
+                let offset = self.ram_offset(IO2_OFFSET as u16 | (addr & 0xFF));
+                self.ram[offset] = value;
+                return true
+            },
+            (HwType::FinalCartridge3, 0xDFFF) if !self.locked => {
+                // bit 6 low pulls NMI, bit 7 hides the register until the next reset
+                self.bank = (value & 0x03) as usize;
+                self.exrom = (value & 0x10) != 0;
+                self.game = (value & 0x20) != 0;
+                self.locked = (value & 0x80) != 0;
+                if (value & 0x40) == 0 {
+                    *on_write = cpu::Callback::TriggerNMI;
+                }
+            },
+            _ => (),
+        }
+
+        self.ram_on != ram_on
+    }
+
+
+    // the ROM contents are not part of the state, only the bank switching registers, the cartridge RAM and the
+    // command state of the flash chips
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u16(self.hw_type as u16);
+        w.write_u16(self.bank as u16);
+        w.write_bool(self.exrom);
+        w.write_bool(self.game);
+        w.write_bool(self.locked);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.ram_on);
+        w.write_bool(self.ram_banked);
+        w.write_bool(self.no_freeze);
+        w.write_bool(self.configured);
+        self.flash_low.save_state(w);
+        self.flash_high.save_state(w);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        if r.read_u16()? != self.hw_type as u16 {
+            return Err("Snapshot is for a different cartridge".to_string())
+        }
+
+        self.bank   = r.read_u16()? as usize;
+        self.exrom  = r.read_bool()?;
+        self.game   = r.read_bool()?;
+        self.locked = r.read_bool()?;
+        r.read_bytes(&mut self.ram)?;
+        self.ram_on     = r.read_bool()?;
+        self.ram_banked = r.read_bool()?;
+        self.no_freeze  = r.read_bool()?;
+        self.configured = r.read_bool()?;
+        self.flash_low.load_state(r)?;
+        self.flash_high.load_state(r)?;
+        Ok(())
+    }
+
+
+    // *** private functions *** //
+
+    // control register of the Action Replay and the Retro Replay (bit 7 is the third bank bit of the latter)
+    fn write_control(&mut self, value: u8) {
+        self.game = (value & 0x01) == 0;
+        self.exrom = (value & 0x02) != 0;
+        self.bank = (((value >> 3) & 0x03) | ((value >> 5) & 0x04)) as usize;
+        self.ram_on = (value & 0x20) != 0;
+
+        // bit 2 switches the cartridge off until the next reset or freeze
+        if (value & 0x04) != 0 {
+            self.exrom = true;
+            self.game = true;
+            self.ram_on = false;
+            self.locked = true;
+        }
+    }
+
+
+    // RAM address for an access to ROML - only the Retro Replay can bank its RAM
+    fn ram_offset(&self, addr: u16) -> usize {
+        let bank = if self.ram_banked { self.bank & 0x03 } else { 0 };
+        (bank * 0x2000 + (addr & 0x1FFF) as usize) % self.ram.len()
+    }
+
+
+    // the image in *.crt format - the flash banks are stored as ROML at $8000 and ROMH at $A000, erased banks are
+    // left out
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::<u8>::new();
+        data.extend_from_slice(&self.header.signature);
+        data.write_u32::<BigEndian>(HEADER_LEN).unwrap();
+        data.extend_from_slice(&self.header.version);
+        data.write_u16::<BigEndian>(self.header.hw_type).unwrap();
+        data.push(self.header.exrom);
+        data.push(self.header.game);
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&self.header.name);
+
+        let chips = self.roml.iter().map(|bank| (bank, 0x8000)).enumerate()
+            .chain(self.romh.iter().map(|bank| (bank, 0xA000)).enumerate());
+
+        for (bank_number, (bank, load_addr)) in chips {
+            if bank.iter().all(|&byte| byte == 0xFF) {
+                continue;
             }
+
+            data.extend_from_slice(b"CHIP");
+            data.write_u32::<BigEndian>(CHIP_HEADER_LEN + bank.len() as u32).unwrap();
+            data.write_u16::<BigEndian>(ChipType::Flash as u16).unwrap();
+            data.write_u16::<BigEndian>(bank_number as u16).unwrap();
+            data.write_u16::<BigEndian>(load_addr).unwrap();
+            data.write_u16::<BigEndian>(bank.len() as u16).unwrap();
+            data.extend_from_slice(bank);
         }
+
+        data
+    }
+}
+
+// modified flash that hasn't been written back yet is saved when the cartridge is removed or the emulator quits
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        self.flush();
     }
 }
 
@@ -168,3 +614,169 @@ enum_from_primitive! {
         Flash,
     }
 }
+
+
+// *** private functions *** //
+
+// bank numbers beyond the last bank wrap around like on carts with fewer chips than address lines
+fn read_rom(banks: &[Vec<u8>], bank: usize, offset: usize) -> u8 {
+    banks[bank % banks.len()].get(offset).cloned().unwrap_or(0xFF)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // a cartridge of the given type with an 8K chip at ROML for each bank, filled with the bank number -
+    // EXROM and GAME are given as stored in the header (0: active)
+    fn cartridge(hw_type: HwType, num_banks: usize, exrom: u8, game: u8) -> Cartridge {
+        let chips = (0..num_banks).map(|bank| Chip {
+            signature: *b"CHIP",
+            length: CHIP_HEADER_LEN + 0x2000,
+            chip_type: ChipType::ROM,
+            bank_number: bank as u16,
+            load_addr: 0x8000,
+            data_size: 0x2000,
+            data: vec![bank as u8; 0x2000],
+        }).collect();
+
+        let crt = Crt {
+            header: Header {
+                signature: *b"C64 CARTRIDGE   ",
+                header_len: HEADER_LEN,
+                version: [1, 0],
+                hw_type: hw_type as u16,
+                exrom: exrom,
+                game: game,
+                name: [0; 32],
+            },
+            chips: chips,
+        };
+
+        Cartridge::new(crt, "")
+    }
+
+
+    fn write_io(cartridge: &mut Cartridge, addr: u16, value: u8) {
+        let mut on_write = cpu::Callback::None;
+        cartridge.write_io(addr, value, &mut on_write);
+    }
+
+
+    #[test]
+    fn ocean() {
+        let mut cartridge = cartridge(HwType::Ocean, 16, 0, 0);
+        write_io(&mut cartridge, 0xDE00, 0x85);
+        assert_eq!(cartridge.bank(), 5);
+        assert_eq!(cartridge.read_roml(0x8000), 5);
+
+        // banks beyond the last one wrap around
+        write_io(&mut cartridge, 0xDE00, 17);
+        assert_eq!(cartridge.read_roml(0x9FFF), 1);
+    }
+
+
+    #[test]
+    fn magic_desk() {
+        let mut cartridge = cartridge(HwType::MagicDesk, 16, 0, 1);
+        write_io(&mut cartridge, 0xDE00, 3);
+        assert_eq!((cartridge.bank(), cartridge.exrom()), (3, false));
+
+        write_io(&mut cartridge, 0xDE00, 0x80);
+        assert!(cartridge.exrom());
+
+        cartridge.reset();
+        assert_eq!((cartridge.bank(), cartridge.exrom()), (0, false));
+    }
+
+
+    #[test]
+    fn fun_play() {
+        let mut cartridge = cartridge(HwType::FunPlay, 16, 0, 1);
+        write_io(&mut cartridge, 0xDE00, 0x09);
+        assert_eq!((cartridge.bank(), cartridge.exrom()), (9, false));
+
+        write_io(&mut cartridge, 0xDE00, 0x86);
+        assert!(cartridge.exrom());
+    }
+
+
+    #[test]
+    fn super_games() {
+        let mut cartridge = cartridge(HwType::SuperGames, 4, 0, 0);
+        write_io(&mut cartridge, 0xDF00, 0x02);
+        assert_eq!((cartridge.bank(), cartridge.exrom(), cartridge.game()), (2, false, false));
+
+        // switched off and locked until reset
+        write_io(&mut cartridge, 0xDF00, 0x0C);
+        write_io(&mut cartridge, 0xDF00, 0x01);
+        assert_eq!((cartridge.bank(), cartridge.exrom(), cartridge.game()), (0, true, true));
+
+        cartridge.reset();
+        write_io(&mut cartridge, 0xDF00, 0x01);
+        assert_eq!(cartridge.bank(), 1);
+    }
+
+
+    #[test]
+    fn address_selects_the_bank() {
+        let mut game_system = cartridge(HwType::C64GameSystem, 64, 0, 1);
+        write_io(&mut game_system, 0xDE07, 0xFF);
+        assert_eq!(game_system.bank(), 7);
+        assert_eq!(game_system.read_io(0xDE05), None);
+        assert_eq!(game_system.bank(), 0);
+
+        let mut dinamic = cartridge(HwType::Dinamic, 16, 0, 1);
+        dinamic.read_io(0xDE0C);
+        assert_eq!(dinamic.bank(), 12);
+    }
+
+
+    #[test]
+    fn normal_cartridge_ignores_io() {
+        let mut cartridge = cartridge(HwType::Normal, 1, 0, 1);
+        write_io(&mut cartridge, 0xDE00, 0x01);
+        assert_eq!(cartridge.read_io(0xDF00), None);
+        assert_eq!((cartridge.bank(), cartridge.exrom(), cartridge.game()), (0, false, true));
+        assert_eq!(cartridge.read_romh(0xA000), 0xFF);
+    }
+
+
+    #[test]
+    fn easyflash_is_written_back() {
+        let path = env::temp_dir().join("rust64_crt_test.crt");
+        let _ = fs::remove_file(&path);
+
+        let mut easyflash = cartridge(HwType::EasyFlash, 1, 1, 0);
+        easyflash.filename = path.to_str().unwrap().to_string();
+
+        // flash can't be programmed outside of Ultimax mode
+        write_io(&mut easyflash, 0xDE02, 0x07);
+        assert!(!easyflash.write_rom(0x8555, 0xAA));
+
+        write_io(&mut easyflash, 0xDE02, 0x05);
+        write_io(&mut easyflash, 0xDE00, 0x02);
+        for &(addr, value) in [(0x8555, 0xAA), (0x82AA, 0x55), (0x8555, 0xA0)].iter() {
+            easyflash.write_rom(addr, value);
+        }
+        assert!(easyflash.write_rom(0x8010, 0x42));
+
+        // nothing is written until the software leaves the flash alone for a while
+        for _ in 0..(FLUSH_DELAY_FRAMES - 1) {
+            easyflash.update();
+        }
+        assert!(!path.exists());
+
+        // or the cartridge goes away
+        drop(easyflash);
+        let crt = Crt::from_filename(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let chip = crt.chips.iter().find(|chip| chip.bank_number == 2).unwrap();
+        assert_eq!((chip.load_addr, chip.data[0x10]), (0x8000, 0x42));
+        assert_eq!(crt.chips.len(), 2);
+    }
+}
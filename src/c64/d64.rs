@@ -0,0 +1,1112 @@
+// D64 disk images - 35 or 40 tracks of 256 byte sectors, optionally followed by one error byte per sector
+// the same layout is used by the D71 (double-sided 1571) and D81 (1581, 80 tracks of 40 sectors) images
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use num::FromPrimitive;
+
+pub const SECTOR_SIZE: usize = 256;
+pub const DIR_TRACK: u8 = 18; // BAM is in sector 0, the directory starts in sector 1
+
+const SIZE_35_TRACKS: usize = 174848; // 683 sectors
+const SIZE_40_TRACKS: usize = 196608; // 768 sectors
+const SIZE_D71: usize = 349696;       // 1366 sectors
+const SIZE_D81: usize = 819200;       // 3200 sectors
+
+// the 1581 keeps its header in sector 0 of the directory track, the BAM in sectors 1-2 and the directory after it
+const D81_DIR_TRACK: u8 = 40;
+
+// the directory and file chains are limited to the number of sectors on disk - anything longer is a loop
+const MAX_CHAIN_LEN: usize = 3200;
+
+
+// image type, selected by the file size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    D64,
+    D71,
+    D81,
+}
+
+
+enum_from_primitive! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum FileType {
+        Del,
+        Seq,
+        Prg,
+        Usr,
+        Rel,
+        Cbm, // 1581 partition - a contiguous area of whole tracks instead of a sector chain
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            FileType::Del => "DEL",
+            FileType::Seq => "SEQ",
+            FileType::Prg => "PRG",
+            FileType::Usr => "USR",
+            FileType::Rel => "REL",
+            FileType::Cbm => "CBM",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+
+// a single entry of the directory
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub file_type: FileType,
+    pub closed: bool,       // unclosed files are shown as "*PRG" etc.
+    pub locked: bool,       // shown as "PRG<"
+    pub name: [u8; 16],     // PETSCII, padded with $A0
+    pub track: u8,          // first sector of the file
+    pub sector: u8,
+    pub side_track: u8,     // first side sector (REL files only)
+    pub side_sector: u8,
+    pub record_len: u8,     // REL files only
+    pub size: u16,          // size in sectors
+}
+
+impl DirEntry {
+    // the file name without $A0 padding
+    pub fn name(&self) -> &[u8] {
+        &self.name[..name_len(&self.name)]
+    }
+
+
+    // match the name against a pattern which may contain the '*' and '?' wildcards
+    pub fn matches(&self, pattern: &[u8]) -> bool {
+        name_matches(self.name(), pattern)
+    }
+}
+
+
+impl fmt::Display for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = format!("\"{}\"", petscii_to_ascii(self.name()));
+        write!(f, "{:<5}{:<18} {}{}{}", self.size, name,
+               if self.closed { " " } else { "*" }, self.file_type, if self.locked { "<" } else { "" })
+    }
+}
+
+
+// where the BAM of a track is stored: a free sector count and a bitmap with one bit per free sector
+struct BamEntry {
+    count_track: u8,
+    count_sector: u8,
+    count: usize,  // offset of the count within its sector
+    track: u8,
+    sector: u8,
+    bitmap: usize, // offset of the bitmap within its sector
+}
+
+
+pub struct D64 {
+    data: Vec<u8>,
+    format: Format,
+    num_tracks: u8,
+    error_bytes: Option<Vec<u8>>, // one error code per sector, 1 means no error
+}
+
+impl D64 {
+    // a freshly formatted 35 track image with an empty directory, as created by the "N:NAME,ID" disk command
+    pub fn new(name: &[u8], id: &[u8]) -> D64 {
+        let mut image = D64 {
+            data: vec![0; SIZE_35_TRACKS],
+            format: Format::D64,
+            num_tracks: 35,
+            error_bytes: None,
+        };
+
+        {
+            let bam = image.sector_mut(DIR_TRACK, 0).unwrap();
+            bam[0] = DIR_TRACK;
+            bam[1] = 1;
+            bam[2] = 0x41; // DOS version 'A'
+            for byte in bam[0x90..0xAB].iter_mut() {
+                *byte = 0xA0;
+            }
+
+            let name_len = name.len().min(16);
+            bam[0x90..(0x90 + name_len)].copy_from_slice(&name[..name_len]);
+            let id_len = id.len().min(2);
+            bam[0xA2..(0xA2 + id_len)].copy_from_slice(&id[..id_len]);
+            bam[0xA5] = b'2';
+            bam[0xA6] = b'A';
+        }
+
+        for track in 1..36 {
+            for sector in 0..D64::sectors_per_track(track) {
+                image.free_sector(track, sector);
+            }
+        }
+
+        image.sector_mut(DIR_TRACK, 1).unwrap()[1] = 0xFF;
+        image.allocate_sector(DIR_TRACK, 0);
+        image.allocate_sector(DIR_TRACK, 1);
+        image
+    }
+
+
+    pub fn from_filename(filename: &str) -> Result<D64, String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        D64::from_bytes(data)
+    }
+
+
+    // D71 and D81 images are recognized as well
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<D64, String> {
+        let (format, num_tracks, image_size) = match data.len() {
+            SIZE_35_TRACKS | 175531 => (Format::D64, 35, SIZE_35_TRACKS),
+            SIZE_40_TRACKS | 197376 => (Format::D64, 40, SIZE_40_TRACKS),
+            SIZE_D71 | 351062 => (Format::D71, 70, SIZE_D71),
+            SIZE_D81 | 822400 => (Format::D81, 80, SIZE_D81),
+            _ => return Err("Invalid disk image size".to_string())
+        };
+
+        let error_bytes = if data.len() > image_size { Some(data.split_off(image_size)) } else { None };
+
+        Ok(D64 {
+            data: data,
+            format: format,
+            num_tracks: num_tracks,
+            error_bytes: error_bytes,
+        })
+    }
+
+
+    // the raw image, including the error bytes if the image had them
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        if let Some(ref errors) = self.error_bytes {
+            data.extend_from_slice(errors);
+        }
+
+        data
+    }
+
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let mut file = File::create(filename).map_err(|e| e.to_string())?;
+        file.write_all(&self.to_bytes()).map_err(|e| e.to_string())
+    }
+
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+
+    pub fn num_tracks(&self) -> u8 {
+        self.num_tracks
+    }
+
+
+    // tracks are 1-based, sectors 0-based (1541 layout)
+    pub fn sectors_per_track(track: u8) -> u8 {
+        match track {
+            1...17  => 21,
+            18...24 => 19,
+            25...30 => 18,
+            _       => 17,
+        }
+    }
+
+
+    // number of sectors of a track in this image - the second side of a D71 repeats the layout of the first
+    pub fn num_sectors(&self, track: u8) -> u8 {
+        match self.format {
+            Format::D64 => D64::sectors_per_track(track),
+            Format::D71 => D64::sectors_per_track(if track > 35 { track - 35 } else { track }),
+            Format::D81 => 40,
+        }
+    }
+
+
+    // track holding the header, BAM and directory
+    pub fn dir_track(&self) -> u8 {
+        match self.format {
+            Format::D64 | Format::D71 => DIR_TRACK,
+            Format::D81 => D81_DIR_TRACK,
+        }
+    }
+
+
+    pub fn read_sector(&self, track: u8, sector: u8) -> Result<&[u8], String> {
+        let offset = self.sector_offset(track, sector)?;
+        Ok(&self.data[offset..(offset + SECTOR_SIZE)])
+    }
+
+
+    // replace the contents of a sector - the BAM is not updated
+    pub fn write_sector(&mut self, track: u8, sector: u8, data: &[u8]) -> Result<(), String> {
+        if data.len() != SECTOR_SIZE {
+            return Err("Invalid sector size".to_string())
+        }
+
+        self.sector_mut(track, sector)?.copy_from_slice(data);
+        Ok(())
+    }
+
+
+    // error code stored for the sector (1: OK, 2-11: read errors as reported by the drive)
+    pub fn sector_error(&self, track: u8, sector: u8) -> u8 {
+        match (self.error_bytes.as_ref(), self.sector_offset(track, sector)) {
+            (Some(errors), Ok(offset)) => errors[offset / SECTOR_SIZE],
+            _ => 1,
+        }
+    }
+
+
+    // disk name as stored in the header (PETSCII, padded with $A0)
+    pub fn disk_name(&self) -> [u8; 16] {
+        let offset = if self.format == Format::D81 { 0x04 } else { 0x90 };
+        let mut name = [0xA0; 16];
+        name.copy_from_slice(&self.header()[offset..(offset + 16)]);
+        name
+    }
+
+
+    // disk ID and DOS type as shown in the directory header, e.g. "01 2A" ("01 3D" on a D81)
+    pub fn disk_id(&self) -> [u8; 5] {
+        let offset = if self.format == Format::D81 { 0x16 } else { 0xA2 };
+        let mut id = [0x20; 5];
+        id.copy_from_slice(&self.header()[offset..(offset + 5)]);
+        id
+    }
+
+
+    pub fn is_sector_free(&self, track: u8, sector: u8) -> bool {
+        match self.bam_entry(track) {
+            Some(entry) => (self.read_sector(entry.track, entry.sector).unwrap()[entry.bitmap + (sector as usize >> 3)] & (1 << (sector & 7))) != 0,
+            None => false,
+        }
+    }
+
+
+    // number of free blocks as reported in the directory listing (the directory track and the BAM track
+    // of the second side of a D71 are not counted)
+    pub fn free_sectors(&self) -> u16 {
+        let mut free = 0;
+
+        for track in 1..(self.num_tracks + 1) {
+            if track == self.dir_track() || (self.format == Format::D71 && track == 53) {
+                continue;
+            }
+
+            if let Some(entry) = self.bam_entry(track) {
+                free += self.read_sector(entry.count_track, entry.count_sector).unwrap()[entry.count] as u16;
+            }
+        }
+
+        free
+    }
+
+
+    // walk the directory chain - scratched entries are skipped
+    pub fn directory(&self) -> Result<Vec<DirEntry>, String> {
+        let mut entries = Vec::<DirEntry>::new();
+
+        for (track, sector, i) in self.dir_slots()? {
+            let entry = &self.read_sector(track, sector)?[(i * 32)..(i * 32 + 32)];
+
+            if entry[2] == 0 {
+                continue;
+            }
+
+            // an entry of unknown type doesn't hide the rest of the directory
+            let file_type = match FileType::from_u8(entry[2] & 0x0F) {
+                Some(file_type) => file_type,
+                None => continue,
+            };
+
+            let mut name = [0xA0; 16];
+            name.copy_from_slice(&entry[5..21]);
+
+            entries.push(DirEntry {
+                file_type:   file_type,
+                closed:      (entry[2] & 0x80) != 0,
+                locked:      (entry[2] & 0x40) != 0,
+                name:        name,
+                track:       entry[3],
+                sector:      entry[4],
+                side_track:  entry[21],
+                side_sector: entry[22],
+                record_len:  entry[23],
+                size:        entry_size(entry),
+            });
+        }
+
+        Ok(entries)
+    }
+
+
+    // first file which matches the pattern (see DirEntry::matches)
+    pub fn find_file(&self, pattern: &[u8]) -> Result<Option<DirEntry>, String> {
+        Ok(self.directory()?.into_iter().find(|entry| entry.file_type != FileType::Del && entry.matches(pattern)))
+    }
+
+
+    // read the contents of a file by following its sector chain - PRG files include the load address,
+    // partitions are returned as their raw sectors
+    pub fn read_file(&self, entry: &DirEntry) -> Result<Vec<u8>, String> {
+        let mut contents = Vec::<u8>::new();
+
+        if entry.file_type == FileType::Cbm {
+            for (track, sector) in self.partition(entry.track, entry.sector, entry.size)? {
+                contents.extend_from_slice(self.read_sector(track, sector)?);
+            }
+
+            return Ok(contents)
+        }
+
+        for (track, sector) in self.chain(entry.track, entry.sector)? {
+            let data = self.read_sector(track, sector)?;
+
+            // the last sector stores the index of the last used byte instead of the next sector
+            let end = if data[0] == 0 { (data[1] as usize + 1).max(2) } else { SECTOR_SIZE };
+            contents.extend_from_slice(&data[2..end]);
+        }
+
+        Ok(contents)
+    }
+
+
+    // store a file in the first free directory slot - the image is left untouched if there is no room
+    pub fn write_file(&mut self, name: &[u8], file_type: FileType, contents: &[u8]) -> Result<(), String> {
+        if name.is_empty() || name.len() > 16 {
+            return Err("Invalid file name".to_string())
+        }
+
+        let num_sectors = ((contents.len() + SECTOR_SIZE - 3) / (SECTOR_SIZE - 2)).max(1);
+        if num_sectors > self.free_sectors() as usize {
+            return Err("Disk full".to_string())
+        }
+
+        let (dir_track, dir_sector, slot) = self.free_dir_slot()?;
+
+        // allocate the whole chain first, so the links are known when the sectors are written
+        let mut sectors = Vec::<(u8, u8)>::new();
+        for _ in 0..num_sectors {
+            let (track, sector) = self.find_free_sector(sectors.last().cloned()).ok_or("Disk full".to_string())?;
+            self.allocate_sector(track, sector);
+            sectors.push((track, sector));
+        }
+
+        for (i, &(track, sector)) in sectors.iter().enumerate() {
+            let chunk = contents.chunks(SECTOR_SIZE - 2).nth(i).unwrap_or(&[]);
+            let data = self.sector_mut(track, sector)?;
+
+            match sectors.get(i + 1) {
+                Some(&(next_track, next_sector)) => {
+                    data[0] = next_track;
+                    data[1] = next_sector;
+                },
+                None => {
+                    data[0] = 0;
+                    data[1] = (chunk.len() + 1) as u8;
+                },
+            }
+
+            data[2..(chunk.len() + 2)].copy_from_slice(chunk);
+            for byte in data[(chunk.len() + 2)..].iter_mut() {
+                *byte = 0;
+            }
+        }
+
+        let entry = &mut self.sector_mut(dir_track, dir_sector)?[(slot * 32)..(slot * 32 + 32)];
+        entry[2] = 0x80 | file_type as u8;
+        entry[3] = sectors[0].0;
+        entry[4] = sectors[0].1;
+        entry[5..21].copy_from_slice(&[0xA0; 16]);
+        entry[5..(5 + name.len())].copy_from_slice(name);
+        for byte in entry[21..30].iter_mut() {
+            *byte = 0;
+        }
+        entry[30] = num_sectors as u8;
+        entry[31] = (num_sectors >> 8) as u8;
+
+        Ok(())
+    }
+
+
+    // scratch all files matching the pattern and free their sectors - returns the number of files scratched
+    pub fn delete_file(&mut self, pattern: &[u8]) -> Result<usize, String> {
+        let mut num_deleted = 0;
+
+        for (track, sector, i) in self.dir_slots()? {
+            let (file_type, file_track, file_sector, size, matches) = {
+                let entry = &self.read_sector(track, sector)?[(i * 32)..(i * 32 + 32)];
+                let name = &entry[5..21];
+                (entry[2], entry[3], entry[4], entry_size(entry), name_matches(&name[..name_len(name)], pattern))
+            };
+
+            if file_type & 0x0F == FileType::Del as u8 || !matches {
+                continue;
+            }
+
+            for (t, s) in self.file_sectors(file_type, file_track, file_sector, size)? {
+                self.free_sector(t, s);
+            }
+
+            self.sector_mut(track, sector)?[i * 32 + 2] = 0;
+            num_deleted += 1;
+        }
+
+        Ok(num_deleted)
+    }
+
+
+    // rename the first file matching the pattern
+    pub fn rename_file(&mut self, pattern: &[u8], new_name: &[u8]) -> Result<(), String> {
+        if new_name.is_empty() || new_name.len() > 16 || new_name.iter().any(|&c| c == b'*' || c == b'?') {
+            return Err("Invalid file name".to_string())
+        }
+
+        if self.find_file(new_name)?.is_some() {
+            return Err("File exists".to_string())
+        }
+
+        for (track, sector, i) in self.dir_slots()? {
+            let matches = {
+                let entry = &self.read_sector(track, sector)?[(i * 32)..(i * 32 + 32)];
+                let name = &entry[5..21];
+                entry[2] & 0x0F != FileType::Del as u8 && name_matches(&name[..name_len(name)], pattern)
+            };
+
+            if matches {
+                let name = &mut self.sector_mut(track, sector)?[(i * 32 + 5)..(i * 32 + 21)];
+                name.copy_from_slice(&[0xA0; 16]);
+                name[..new_name.len()].copy_from_slice(new_name);
+                return Ok(())
+            }
+        }
+
+        Err("File not found".to_string())
+    }
+
+
+    // rebuild the BAM from the directory and the file chains like the VALIDATE disk command does -
+    // unclosed files are scratched, returns the number of sectors whose BAM state was wrong
+    pub fn validate(&mut self) -> Result<usize, String> {
+        let mut used = Vec::<(u8, u8)>::new();
+
+        // header, BAM and the whole BAM track of the second side of a D71 are always in use
+        let (dir_start_track, dir_start_sector) = self.dir_start();
+        for sector in 0..dir_start_sector {
+            used.push((dir_start_track, sector));
+        }
+
+        if self.format == Format::D71 {
+            for sector in 0..self.num_sectors(53) {
+                used.push((53, sector));
+            }
+        }
+
+        for (track, sector, i) in self.dir_slots()? {
+            if i == 0 {
+                used.push((track, sector));
+            }
+
+            let (file_type, file_track, file_sector, side_track, side_sector, size) = {
+                let entry = &self.read_sector(track, sector)?[(i * 32)..(i * 32 + 32)];
+                (entry[2], entry[3], entry[4], entry[21], entry[22], entry_size(entry))
+            };
+
+            if file_type & 0x0F == FileType::Del as u8 {
+                continue;
+            }
+
+            if file_type & 0x80 == 0 {
+                self.sector_mut(track, sector)?[i * 32 + 2] = 0;
+                continue;
+            }
+
+            used.extend(self.file_sectors(file_type, file_track, file_sector, size)?);
+            if file_type & 0x0F == FileType::Rel as u8 {
+                used.extend(self.chain(side_track, side_sector)?);
+            }
+        }
+
+        let mut num_fixed = 0;
+
+        for track in 1..(self.num_tracks + 1) {
+            // tracks without BAM entry (36-40 of a 35 track D64) don't take part
+            let entry = match self.bam_entry(track) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            // the bitmap and the count are written from scratch, so bits of nonexistent sectors are cleared as well
+            let num_sectors = self.num_sectors(track);
+            let mut bitmap = vec![0; (num_sectors as usize + 7) / 8];
+            let mut count = 0;
+
+            for sector in 0..num_sectors {
+                let free = !used.contains(&(track, sector));
+                if self.is_sector_free(track, sector) != free {
+                    num_fixed += 1;
+                }
+
+                if free {
+                    bitmap[sector as usize >> 3] |= 1 << (sector & 7);
+                    count += 1;
+                }
+            }
+
+            self.sector_mut(entry.track, entry.sector)?[entry.bitmap..(entry.bitmap + bitmap.len())].copy_from_slice(&bitmap);
+            self.sector_mut(entry.count_track, entry.count_sector)?[entry.count] = count;
+        }
+
+        Ok(num_fixed)
+    }
+
+
+    // *** private functions *** //
+
+    fn sector_offset(&self, track: u8, sector: u8) -> Result<usize, String> {
+        if track == 0 || track > self.num_tracks || sector >= self.num_sectors(track) {
+            return Err(format!("Illegal track or sector ({}/{})", track, sector))
+        }
+
+        let mut offset = 0;
+        for t in 1..track {
+            offset += self.num_sectors(t) as usize * SECTOR_SIZE;
+        }
+
+        Ok(offset + sector as usize * SECTOR_SIZE)
+    }
+
+
+    fn sector_mut(&mut self, track: u8, sector: u8) -> Result<&mut [u8], String> {
+        let offset = self.sector_offset(track, sector)?;
+        Ok(&mut self.data[offset..(offset + SECTOR_SIZE)])
+    }
+
+
+    fn header(&self) -> &[u8] {
+        self.read_sector(self.dir_track(), 0).unwrap()
+    }
+
+
+    // the directory chain starts behind the BAM
+    fn dir_start(&self) -> (u8, u8) {
+        match self.format {
+            Format::D64 | Format::D71 => (DIR_TRACK, 1),
+            Format::D81 => (D81_DIR_TRACK, 3),
+        }
+    }
+
+
+    // sector interleave used when allocating file and directory sectors (as done by the DOS of each drive)
+    fn interleave(&self) -> (u8, u8) {
+        match self.format {
+            Format::D64 => (10, 3),
+            Format::D71 => (6, 3),
+            Format::D81 => (1, 1),
+        }
+    }
+
+
+    fn allocate_sector(&mut self, track: u8, sector: u8) {
+        self.set_sector_free(track, sector, false);
+    }
+
+
+    fn free_sector(&mut self, track: u8, sector: u8) {
+        self.set_sector_free(track, sector, true);
+    }
+
+
+    // update the BAM bitmap and the free sector count of the track
+    fn set_sector_free(&mut self, track: u8, sector: u8, free: bool) {
+        if let Some(entry) = self.bam_entry(track) {
+            if self.is_sector_free(track, sector) == free {
+                return;
+            }
+
+            {
+                let bam = self.sector_mut(entry.track, entry.sector).unwrap();
+                let mask = 1 << (sector & 7);
+                let index = entry.bitmap + (sector as usize >> 3);
+                if free { bam[index] |= mask; } else { bam[index] &= !mask; }
+            }
+
+            // the count may disagree with the bitmap on corrupt images - it's corrected by validate()
+            let count = &mut self.sector_mut(entry.count_track, entry.count_sector).unwrap()[entry.count];
+            *count = if free { count.saturating_add(1) } else { count.saturating_sub(1) };
+        }
+    }
+
+
+    // next sector for a file - tracks are filled starting next to the directory track and moving outwards,
+    // within a track the sectors are spread out by the interleave
+    fn find_free_sector(&self, prev: Option<(u8, u8)>) -> Option<(u8, u8)> {
+        let dir_track = self.dir_track();
+        let mut tracks = Vec::<u8>::new();
+        for distance in 1..self.num_tracks {
+            if distance < dir_track {
+                tracks.push(dir_track - distance);
+            }
+
+            if dir_track + distance <= self.num_tracks {
+                tracks.push(dir_track + distance);
+            }
+        }
+
+        let (first_track, first_sector) = match prev {
+            Some((track, sector)) => (tracks.iter().position(|&t| t == track).unwrap_or(0), sector + self.interleave().0),
+            None => (0, 0),
+        };
+
+        for (i, &track) in tracks.iter().enumerate().skip(first_track) {
+            let num_sectors = self.num_sectors(track);
+            let start = if i == first_track { first_sector % num_sectors } else { 0 };
+
+            for j in 0..num_sectors {
+                let sector = (start + j) % num_sectors;
+                if self.is_sector_free(track, sector) {
+                    return Some((track, sector))
+                }
+            }
+        }
+
+        None
+    }
+
+
+    // all directory slots as (track, sector, index within the sector)
+    fn dir_slots(&self) -> Result<Vec<(u8, u8, usize)>, String> {
+        let mut slots = Vec::<(u8, u8, usize)>::new();
+
+        let (dir_track, dir_sector) = self.dir_start();
+        for (track, sector) in self.chain(dir_track, dir_sector)? {
+            for i in 0..8 {
+                slots.push((track, sector, i));
+            }
+        }
+
+        Ok(slots)
+    }
+
+
+    // find an unused directory slot - the directory is extended by a sector if all slots are taken
+    fn free_dir_slot(&mut self) -> Result<(u8, u8, usize), String> {
+        let slots = self.dir_slots()?;
+
+        for &(track, sector, i) in slots.iter() {
+            if self.read_sector(track, sector)?[i * 32 + 2] == 0 {
+                return Ok((track, sector, i))
+            }
+        }
+
+        let (last_track, last_sector, _) = *slots.last().unwrap();
+        let dir_track = self.dir_track();
+        let num_sectors = self.num_sectors(dir_track);
+
+        for j in 0..num_sectors {
+            let sector = (last_sector + self.interleave().1 + j) % num_sectors;
+            if self.is_sector_free(dir_track, sector) {
+                self.allocate_sector(dir_track, sector);
+
+                let data = self.sector_mut(dir_track, sector)?;
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+                data[1] = 0xFF;
+
+                let prev = self.sector_mut(last_track, last_sector)?;
+                prev[0] = dir_track;
+                prev[1] = sector;
+                return Ok((dir_track, sector, 0))
+            }
+        }
+
+        Err("Directory full".to_string())
+    }
+
+
+    // location of the free sector count and bitmap of a track
+    // tracks 36-40 of a D64 are not part of the original DOS - the SpeedDOS layout is used for them
+    fn bam_entry(&self, track: u8) -> Option<BamEntry> {
+        let (count_track, count_sector, count, bam_track, bam_sector, bitmap) = match (self.format, track) {
+            (Format::D64, 1...35) | (Format::D71, 1...35) => (DIR_TRACK, 0, 4 * track as usize, DIR_TRACK, 0, 4 * track as usize + 1),
+            (Format::D64, 36...40) if self.num_tracks == 40 => {
+                let offset = 0xC0 + 4 * (track as usize - 36);
+                (DIR_TRACK, 0, offset, DIR_TRACK, 0, offset + 1)
+            },
+            // the counts of the second side are stored behind the BAM of the first one, its bitmaps on track 53
+            (Format::D71, 36...70) => (DIR_TRACK, 0, 0xDD + (track as usize - 36), 53, 0, 3 * (track as usize - 36)),
+            (Format::D81, 1...80) => {
+                let offset = 0x10 + 6 * ((track as usize - 1) % 40);
+                let sector = if track <= 40 { 1 } else { 2 };
+                (D81_DIR_TRACK, sector, offset, D81_DIR_TRACK, sector, offset + 1)
+            },
+            _ => return None,
+        };
+
+        Some(BamEntry {
+            count_track: count_track,
+            count_sector: count_sector,
+            count: count,
+            track: bam_track,
+            sector: bam_sector,
+            bitmap: bitmap,
+        })
+    }
+
+
+    // sectors of a file given the type byte of its directory entry - partitions are not chained
+    fn file_sectors(&self, file_type: u8, track: u8, sector: u8, size: u16) -> Result<Vec<(u8, u8)>, String> {
+        if file_type & 0x0F == FileType::Cbm as u8 {
+            self.partition(track, sector, size)
+        }
+        else {
+            self.chain(track, sector)
+        }
+    }
+
+
+    // all sectors of a partition - it takes the given number of sectors in a row, continuing on the next track
+    fn partition(&self, track: u8, sector: u8, size: u16) -> Result<Vec<(u8, u8)>, String> {
+        let mut sectors = Vec::<(u8, u8)>::new();
+        let (mut t, mut s) = (track, sector);
+
+        for _ in 0..size {
+            self.sector_offset(t, s).map_err(|_| "Partition beyond end of disk".to_string())?;
+            sectors.push((t, s));
+
+            s += 1;
+            if s >= self.num_sectors(t) {
+                t += 1;
+                s = 0;
+            }
+        }
+
+        Ok(sectors)
+    }
+
+
+    // list of all sectors of a chain - links to illegal sectors and loops are reported as errors
+    fn chain(&self, track: u8, sector: u8) -> Result<Vec<(u8, u8)>, String> {
+        let mut sectors = Vec::<(u8, u8)>::new();
+        let (mut t, mut s) = (track, sector);
+
+        while t != 0 {
+            if sectors.len() >= MAX_CHAIN_LEN {
+                return Err("Sector chain loops".to_string())
+            }
+
+            let data = self.read_sector(t, s)?;
+            sectors.push((t, s));
+            t = data[0];
+            s = data[1];
+        }
+
+        Ok(sectors)
+    }
+}
+
+
+// convert PETSCII to printable ASCII - unprintable characters are shown as '?'
+pub fn petscii_to_ascii(petscii: &[u8]) -> String {
+    petscii.iter().map(|&c| {
+        match c {
+            0x20...0x40 | 0x5B | 0x5D => c as char,
+            0x41...0x5A => (c + 0x20) as char, // as shown with the lowercase charset
+            0xC1...0xDA => (c - 0x80) as char,
+            0xA0 => ' ',
+            _ => '?',
+        }
+    }).collect()
+}
+
+
+// convert host file names and typed text to PETSCII - letters become uppercase, characters without a PETSCII
+// equivalent are replaced by '?'
+pub fn ascii_to_petscii(text: &str) -> Vec<u8> {
+    text.chars().map(|c| {
+        match c {
+            'a'...'z' => c.to_ascii_uppercase() as u8,
+            ' '...']' => c as u8,
+            _ => b'?',
+        }
+    }).collect()
+}
+
+// *** private functions *** //
+
+// size in sectors stored in a directory entry
+fn entry_size(entry: &[u8]) -> u16 {
+    (entry[30] as u16) | ((entry[31] as u16) << 8)
+}
+
+
+fn name_len(padded_name: &[u8]) -> usize {
+    padded_name.iter().position(|&c| c == 0xA0).unwrap_or(padded_name.len())
+}
+
+
+fn name_matches(name: &[u8], pattern: &[u8]) -> bool {
+    for (i, &c) in pattern.iter().enumerate() {
+        match c {
+            b'*' => return true,
+            b'?' => if i >= name.len() { return false; },
+            _    => if i >= name.len() || name[i] != c { return false; },
+        }
+    }
+
+    pattern.len() == name.len()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+
+    #[test]
+    fn read_directory_and_file() {
+        let mut image = D64::new(b"TEST", b"01");
+
+        // a closed, locked PRG file of two sectors: 254 bytes in 17/0 and 10 bytes in 17/10
+        let mut dir = image.read_sector(DIR_TRACK, 1).unwrap().to_vec();
+        dir[2..5].copy_from_slice(&[0xC2, 17, 0]);
+        dir[5..21].copy_from_slice(b"FILE\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0");
+        dir[30] = 2;
+        image.write_sector(DIR_TRACK, 1, &dir).unwrap();
+
+        let mut first = vec![17, 10];
+        first.extend(contents(254));
+        image.write_sector(17, 0, &first).unwrap();
+        let mut last = vec![0, 11];
+        last.extend(contents(10));
+        last.resize(SECTOR_SIZE, 0);
+        image.write_sector(17, 10, &last).unwrap();
+
+        let directory = image.directory().unwrap();
+        assert_eq!(directory.len(), 1);
+        assert_eq!(directory[0].file_type, FileType::Prg);
+        assert!(directory[0].closed && directory[0].locked);
+        assert_eq!(directory[0].name(), b"FILE");
+        assert_eq!(format!("{}", directory[0]), "2    \"file\"              PRG<");
+
+        let mut expected = contents(254);
+        expected.extend(contents(10));
+        assert_eq!(image.read_file(&directory[0]).unwrap(), expected);
+    }
+
+
+    #[test]
+    fn chain_loops_are_errors() {
+        let mut image = D64::new(b"TEST", b"01");
+        let mut data = vec![0; SECTOR_SIZE];
+        data[0..2].copy_from_slice(&[17, 0]);
+        image.write_sector(17, 0, &data).unwrap();
+
+        assert!(image.chain(17, 0).is_err());
+        assert!(image.chain(36, 0).is_err());
+    }
+
+
+    #[test]
+    fn error_bytes_are_kept() {
+        let mut data = D64::new(b"TEST", b"01").to_bytes();
+        data.extend(vec![1; 683]);
+        data[SIZE_35_TRACKS + 5] = 5;
+
+        let image = D64::from_bytes(data.clone()).unwrap();
+        assert_eq!(image.sector_error(1, 5), 5);
+        assert_eq!(image.sector_error(1, 6), 1);
+        assert_eq!(image.to_bytes(), data);
+    }
+
+
+    #[test]
+    fn d71_free_sectors() {
+        let mut image = D64::from_bytes(vec![0; SIZE_D71]).unwrap();
+        image.validate().unwrap();
+        assert_eq!(image.free_sectors(), 1328);
+
+        // track 53 is never reported as free, even if its count says so
+        let mut bam = image.read_sector(DIR_TRACK, 0).unwrap().to_vec();
+        bam[0xDD + 17] = 19;
+        image.write_sector(DIR_TRACK, 0, &bam).unwrap();
+        assert_eq!(image.free_sectors(), 1328);
+    }
+
+
+    #[test]
+    fn unknown_file_types_are_skipped() {
+        let mut image = D64::new(b"TEST", b"01");
+        image.write_file(b"FILE1", FileType::Prg, &contents(10)).unwrap();
+        image.write_file(b"FILE2", FileType::Prg, &contents(10)).unwrap();
+
+        let mut dir = image.read_sector(DIR_TRACK, 1).unwrap().to_vec();
+        dir[2] = 0x87;
+        image.write_sector(DIR_TRACK, 1, &dir).unwrap();
+
+        let directory = image.directory().unwrap();
+        assert_eq!(directory.len(), 1);
+        assert_eq!(directory[0].name(), b"FILE2");
+    }
+
+
+    #[test]
+    fn write_and_read_file() {
+        let mut image = D64::new(b"TEST", b"01");
+        image.write_file(b"FILE", FileType::Prg, &contents(1000)).unwrap();
+        image.write_file(b"EMPTY", FileType::Seq, &[]).unwrap();
+
+        let image = D64::from_bytes(image.to_bytes()).unwrap();
+        let entry = image.find_file(b"FILE").unwrap().unwrap();
+        assert_eq!(entry.file_type, FileType::Prg);
+        assert_eq!(entry.size, 4);
+        assert_eq!(image.read_file(&entry).unwrap(), contents(1000));
+
+        let entry = image.find_file(b"EM*").unwrap().unwrap();
+        assert_eq!(entry.file_type, FileType::Seq);
+        assert!(image.read_file(&entry).unwrap().is_empty());
+        assert_eq!(image.free_sectors(), 659);
+    }
+
+
+    #[test]
+    fn directory_grows_beyond_one_sector() {
+        let mut image = D64::new(b"TEST", b"01");
+        for i in 0..20 {
+            image.write_file(format!("FILE{}", i).as_bytes(), FileType::Prg, &contents(10)).unwrap();
+        }
+
+        assert_eq!(image.directory().unwrap().len(), 20);
+        assert_eq!(image.free_sectors(), 644);
+    }
+
+
+    #[test]
+    fn delete_frees_sectors() {
+        let mut image = D64::new(b"TEST", b"01");
+        image.write_file(b"FILE", FileType::Prg, &contents(5000)).unwrap();
+        assert_eq!(image.delete_file(b"F*").unwrap(), 1);
+        assert_eq!(image.free_sectors(), 664);
+        assert!(image.find_file(b"FILE").unwrap().is_none());
+    }
+
+
+    #[test]
+    fn name_patterns() {
+        assert!(name_matches(b"GAME", b"GAME"));
+        assert!(name_matches(b"GAME", b"G*"));
+        assert!(name_matches(b"GAME", b"G?ME"));
+        assert!(!name_matches(b"GAME", b"GAM"));
+        assert!(!name_matches(b"GAM", b"GAM?"));
+    }
+
+
+    #[test]
+    fn new_image_is_empty() {
+        let image = D64::new(b"TEST", b"01");
+        assert_eq!(image.to_bytes().len(), SIZE_35_TRACKS);
+        assert_eq!(image.free_sectors(), 664);
+        assert!(image.directory().unwrap().is_empty());
+        assert_eq!(petscii_to_ascii(&image.disk_name()), "test            ");
+    }
+
+
+    #[test]
+    fn rename_keeps_names_unique() {
+        let mut image = D64::new(b"TEST", b"01");
+        image.write_file(b"FILE1", FileType::Prg, &contents(10)).unwrap();
+        image.write_file(b"FILE2", FileType::Prg, &contents(10)).unwrap();
+
+        assert_eq!(image.rename_file(b"FILE1", b"FILE2"), Err("File exists".to_string()));
+        image.rename_file(b"FILE1", b"GAME").unwrap();
+        assert!(image.find_file(b"GAME").unwrap().is_some());
+        assert!(image.find_file(b"FILE1").unwrap().is_none());
+    }
+
+
+    #[test]
+    fn validate_rebuilds_bam() {
+        let mut image = D64::new(b"TEST", b"01");
+        image.write_file(b"FILE", FileType::Prg, &contents(1000)).unwrap();
+        assert_eq!(image.validate().unwrap(), 0);
+
+        // scratch the directory entry without freeing its sectors
+        let mut dir = image.read_sector(DIR_TRACK, 1).unwrap().to_vec();
+        dir[2] = 0;
+        image.write_sector(DIR_TRACK, 1, &dir).unwrap();
+
+        assert_eq!(image.validate().unwrap(), 4);
+        assert_eq!(image.free_sectors(), 664);
+    }
+
+
+    #[test]
+    fn free_count_out_of_sync() {
+        // the bitmap of track 17 (the first one used for files) has free sectors but its count says there are none
+        let mut image = D64::new(b"TEST", b"01");
+        let mut bam = image.read_sector(DIR_TRACK, 0).unwrap().to_vec();
+        bam[4 * 17] = 0;
+        image.write_sector(DIR_TRACK, 0, &bam).unwrap();
+
+        image.write_file(b"FILE", FileType::Prg, &contents(30000)).unwrap();
+        assert_eq!(image.read_sector(DIR_TRACK, 0).unwrap()[4 * 17], 0);
+
+        image.validate().unwrap();
+        assert_eq!(image.free_sectors(), 664 - 119);
+    }
+
+
+
+    #[test]
+    fn d81_partition() {
+        let mut image = D64::from_bytes(vec![0; SIZE_D81]).unwrap();
+
+        let mut header = [0; SECTOR_SIZE];
+        header[0] = D81_DIR_TRACK;
+        header[1] = 3;
+        image.write_sector(D81_DIR_TRACK, 0, &header).unwrap();
+
+        // a partition of tracks 1 and 2 in the first directory slot
+        let mut dir = [0; SECTOR_SIZE];
+        dir[1] = 0xFF;
+        dir[2] = 0x80 | FileType::Cbm as u8;
+        dir[3] = 1;
+        dir[4] = 0;
+        dir[5..21].copy_from_slice(b"PART\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0");
+        dir[30] = 80;
+        image.write_sector(D81_DIR_TRACK, 3, &dir).unwrap();
+        image.write_sector(2, 39, &[0x42; SECTOR_SIZE]).unwrap();
+
+        // the BAM is built from the directory, a partition is not a sector chain
+        image.validate().unwrap();
+        assert_eq!(image.free_sectors(), 3200 - 40 - 80);
+
+        image.write_file(b"FILE", FileType::Prg, &contents(3000)).unwrap();
+        let entry = image.find_file(b"FILE").unwrap().unwrap();
+        assert!(entry.track > 2);
+        assert_eq!(image.read_file(&entry).unwrap(), contents(3000));
+
+        let partition = image.find_file(b"PART").unwrap().unwrap();
+        assert_eq!(partition.file_type, FileType::Cbm);
+        let data = image.read_file(&partition).unwrap();
+        assert_eq!(data.len(), 80 * SECTOR_SIZE);
+        assert_eq!(data[80 * SECTOR_SIZE - 1], 0x42);
+
+        assert_eq!(image.validate().unwrap(), 0);
+        assert_eq!(image.delete_file(b"PART").unwrap(), 1);
+        assert_eq!(image.free_sectors(), 3200 - 40 - 12);
+    }
+}
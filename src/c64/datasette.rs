@@ -0,0 +1,252 @@
+// datasette - plays the pulses of a TAP image into the CIA1 FLAG input while the C64 runs the motor,
+// or records the cassette write line of the 6510 port
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use c64::tap;
+
+
+pub struct Datasette {
+    tape: Option<(tap::Tap, String)>, // inserted tape and the file recordings are written to
+    dirty: bool,       // pulses recorded since the file was last written
+    pos: usize,        // index of the next pulse
+    pulse_cycles: u32, // cycles left until the current pulse ends
+    playing: bool,     // PLAY is pressed
+    recording: bool,   // RECORD is pressed along with PLAY
+    motor_on: bool,
+    write_level: bool,
+    write_cycles: u32, // cycles since the last rising edge of the write line
+}
+
+impl Datasette {
+    pub fn new() -> Datasette {
+        Datasette {
+            tape: None,
+            dirty: false,
+            pos: 0,
+            pulse_cycles: 0,
+            playing: false,
+            recording: false,
+            motor_on: false,
+            write_level: false,
+            write_cycles: 0,
+        }
+    }
+
+
+    // insert a *.tap image - recording on it overwrites the file
+    pub fn attach(&mut self, path: &str) -> Result<(), String> {
+        let tape = tap::Tap::from_filename(path)?;
+        self.detach();
+        self.tape = Some((tape, path.to_string()));
+        Ok(())
+    }
+
+
+    // insert a blank tape - the file is created once something is recorded
+    pub fn create(&mut self, path: &str) {
+        self.detach();
+        self.tape = Some((tap::Tap::new(), path.to_string()));
+    }
+
+
+    pub fn detach(&mut self) {
+        self.rewind();
+        self.tape = None;
+    }
+
+
+    pub fn is_attached(&self) -> bool {
+        self.tape.is_some()
+    }
+
+
+    pub fn play(&mut self) {
+        self.playing = self.tape.is_some();
+        self.recording = false;
+    }
+
+
+    pub fn record(&mut self) {
+        self.playing = self.tape.is_some();
+        self.recording = self.playing;
+    }
+
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.recording = false;
+        self.flush();
+    }
+
+
+    // stops playback and winds the tape back to the start
+    pub fn rewind(&mut self) {
+        self.stop();
+        self.pos = 0;
+        self.pulse_cycles = 0;
+        self.write_cycles = 0;
+    }
+
+
+    // the cassette sense line reports a pressed button to the 6510 port
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+
+    // advance the tape by one cycle - returns true when a pulse ends, which is a falling edge on FLAG
+    pub fn update(&mut self, motor_on: bool, write_line: bool) -> bool {
+        // like the drive, the recording is written to disk whenever the motor stops
+        let motor_stopped = self.motor_on && !motor_on;
+        self.motor_on = motor_on;
+
+        if self.recording {
+            if motor_stopped {
+                self.flush();
+            }
+
+            if motor_on {
+                self.record_pulse(write_line);
+            }
+
+            return false;
+        }
+
+        if !self.playing || !motor_on {
+            return false;
+        }
+
+        if self.pulse_cycles > 1 {
+            self.pulse_cycles -= 1;
+            return false;
+        }
+
+        let edge = self.pulse_cycles == 1;
+
+        // at the end of the tape the button pops up like on the real thing
+        match self.tape.as_ref().and_then(|&(ref tape, _)| tape.pulses.get(self.pos).cloned()) {
+            Some(cycles) => {
+                self.pulse_cycles = cycles.max(1);
+                self.pos += 1;
+            },
+            None => {
+                self.pulse_cycles = 0;
+                self.playing = false;
+            }
+        }
+
+        edge
+    }
+
+
+    // the tape contents are not part of the state, only the position
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u32(self.pos as u32);
+        w.write_u32(self.pulse_cycles);
+        w.write_bool(self.playing);
+        w.write_bool(self.recording);
+        w.write_bool(self.motor_on);
+        w.write_bool(self.write_level);
+        w.write_u32(self.write_cycles);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.pos          = r.read_u32()? as usize;
+        self.pulse_cycles = r.read_u32()?;
+        self.playing      = r.read_bool()?;
+        self.recording    = r.read_bool()?;
+        self.motor_on     = r.read_bool()?;
+        self.write_level  = r.read_bool()?;
+        self.write_cycles = r.read_u32()?;
+
+        let num_pulses = self.tape.as_ref().map_or(0, |&(ref tape, _)| tape.pulses.len());
+        if self.pos > num_pulses {
+            return Err("Invalid tape position".to_string())
+        }
+
+        Ok(())
+    }
+
+
+    // *** private functions *** //
+
+    // a pulse ends with each rising edge of the write line - recording overwrites the rest of the tape
+    fn record_pulse(&mut self, write_line: bool) {
+        self.write_cycles = self.write_cycles.saturating_add(1);
+
+        if write_line && !self.write_level {
+            if let Some((ref mut tape, _)) = self.tape {
+                tape.pulses.truncate(self.pos);
+                tape.pulses.push(self.write_cycles);
+                self.pos += 1;
+                self.dirty = true;
+            }
+
+            self.write_cycles = 0;
+        }
+
+        self.write_level = write_line;
+    }
+
+
+    fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        if let Some((ref tape, ref filename)) = self.tape {
+            if let Err(e) = tape.save(filename) {
+                println!("Datasette: can't write {}: {}", filename, e);
+            }
+        }
+
+        self.dirty = false;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn pulses_end_with_flag_edges() {
+        let mut datasette = Datasette::new();
+        datasette.play();
+        assert!(!datasette.is_playing());
+
+        datasette.tape = Some((tap::Tap { version: 1, pulses: vec![3, 2] }, String::new()));
+        datasette.play();
+
+        // the tape only moves while the motor runs
+        assert!(!datasette.update(false, false));
+        let edges: Vec<bool> = (0..8).map(|_| datasette.update(true, false)).collect();
+        assert_eq!(edges, vec![false, false, false, true, false, true, false, false]);
+        assert!(!datasette.is_playing());
+    }
+
+
+    #[test]
+    fn write_line_is_recorded() {
+        let path = env::temp_dir().join("rust64_datasette_test.tap");
+        let mut datasette = Datasette::new();
+        datasette.create(path.to_str().unwrap());
+        datasette.record();
+
+        // each pulse ends with a rising edge of the write line
+        for &cycles in [368, 512, 368].iter() {
+            for _ in 1..cycles {
+                datasette.update(true, false);
+            }
+            datasette.update(true, true);
+        }
+        assert!(!path.exists());
+
+        // the recording is written when the motor stops
+        datasette.update(false, false);
+        assert_eq!(tap::Tap::from_filename(path.to_str().unwrap()).unwrap().pulses, vec![368, 512, 368]);
+        fs::remove_file(path).unwrap();
+    }
+}
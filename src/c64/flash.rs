@@ -0,0 +1,230 @@
+// AM29F040 flash chip (512K) as used on the EasyFlash - the command state machine on top of the chip contents,
+// which are stored as 8K banks by the cartridge
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use num::FromPrimitive;
+
+pub const BANK_SIZE: usize = 0x2000;
+const SECTOR_SIZE: usize = 0x10000; // smallest erasable unit, 8 banks
+
+// values returned in autoselect mode
+const MANUFACTURER_ID: u8 = 0x01;
+const DEVICE_ID: u8 = 0xA4;
+
+
+enum_from_primitive! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum FlashState {
+        Read,
+        Magic1,      // $AA written to $555
+        Magic2,      // $55 written to $2AA - a command follows
+        Program,     // the next write programs a byte
+        EraseSetup,  // erase command started, another unlock sequence follows
+        EraseMagic1,
+        EraseMagic2, // sector or chip erase follows
+        Autoselect,  // reads return the chip IDs until reset
+    }
+}
+
+
+pub struct Flash {
+    state: FlashState,
+}
+
+impl Flash {
+    pub fn new() -> Flash {
+        Flash {
+            state: FlashState::Read,
+        }
+    }
+
+
+    pub fn reset(&mut self) {
+        self.state = FlashState::Read;
+    }
+
+
+    // byte at the given offset of a bank - program and erase complete at once, so the status polling of the
+    // software sees the final data right away
+    pub fn read(&self, banks: &[Vec<u8>], bank: usize, offset: usize) -> u8 {
+        if self.state == FlashState::Autoselect {
+            return match offset & 0xFF {
+                0 => MANUFACTURER_ID,
+                1 => DEVICE_ID,
+                _ => 0x00, // sectors are not protected
+            }
+        }
+
+        banks.get(bank).and_then(|data| data.get(offset)).cloned().unwrap_or(0xFF)
+    }
+
+
+    // write to the chip - returns true if reads return something else afterwards (contents changed, autoselect
+    // mode entered or left)
+    pub fn write(&mut self, banks: &mut [Vec<u8>], bank: usize, offset: usize, value: u8) -> bool {
+        // the commands are decoded from the lower 11 address lines only
+        let command_addr = offset & 0x07FF;
+        let was_autoselect = self.state == FlashState::Autoselect;
+        let mut changed = false;
+
+        self.state = match (self.state, command_addr, value) {
+            // the byte is programmed even if it looks like a command - programming only clears bits, erasing sets them
+            (FlashState::Program, _, _) => {
+                if let Some(byte) = banks.get_mut(bank).and_then(|data| data.get_mut(offset)) {
+                    changed = (*byte & value) != *byte;
+                    *byte &= value;
+                }
+                FlashState::Read
+            },
+            (_, _, 0xF0) => FlashState::Read,
+            (FlashState::Read, 0x555, 0xAA) | (FlashState::Autoselect, 0x555, 0xAA) => FlashState::Magic1,
+            (FlashState::Magic1, 0x2AA, 0x55) => FlashState::Magic2,
+            (FlashState::Magic2, 0x555, 0xA0) => FlashState::Program,
+            (FlashState::Magic2, 0x555, 0x80) => FlashState::EraseSetup,
+            (FlashState::Magic2, 0x555, 0x90) => FlashState::Autoselect,
+            (FlashState::EraseSetup, 0x555, 0xAA) => FlashState::EraseMagic1,
+            (FlashState::EraseMagic1, 0x2AA, 0x55) => FlashState::EraseMagic2,
+            (FlashState::EraseMagic2, _, 0x30) => {
+                let sector = (bank * BANK_SIZE + offset) / SECTOR_SIZE;
+                let banks_per_sector = SECTOR_SIZE / BANK_SIZE;
+                for data in banks.iter_mut().skip(sector * banks_per_sector).take(banks_per_sector) {
+                    changed |= erase(data);
+                }
+                FlashState::Read
+            },
+            (FlashState::EraseMagic2, 0x555, 0x10) => {
+                for data in banks.iter_mut() {
+                    changed |= erase(data);
+                }
+                FlashState::Read
+            },
+            (FlashState::Autoselect, _, _) => FlashState::Autoselect,
+            _ => FlashState::Read,
+        };
+
+        changed || was_autoselect != (self.state == FlashState::Autoselect)
+    }
+
+
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.state as u8);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.state = FlashState::from_u8(r.read_u8()?).ok_or("Invalid flash state".to_string())?;
+        Ok(())
+    }
+}
+
+
+// *** private functions *** //
+
+fn erase(data: &mut Vec<u8>) -> bool {
+    let changed = data.iter().any(|&byte| byte != 0xFF);
+    for byte in data.iter_mut() {
+        *byte = 0xFF;
+    }
+
+    changed
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn banks() -> Vec<Vec<u8>> {
+        vec![vec![0xFF; BANK_SIZE]; 16]
+    }
+
+
+    // the unlock sequence followed by a command
+    fn command(flash: &mut Flash, banks: &mut [Vec<u8>], command: u8) -> bool {
+        flash.write(banks, 0, 0x555, 0xAA);
+        flash.write(banks, 0, 0x2AA, 0x55);
+        flash.write(banks, 0, 0x555, command)
+    }
+
+
+    #[test]
+    fn program() {
+        let mut flash = Flash::new();
+        let mut banks = banks();
+
+        assert!(!command(&mut flash, &mut banks, 0xA0));
+        assert!(flash.write(&mut banks, 3, 0x1234, 0x5A));
+        assert_eq!(flash.read(&banks, 3, 0x1234), 0x5A);
+
+        // a plain write doesn't change anything
+        assert!(!flash.write(&mut banks, 3, 0x1234, 0x00));
+        assert_eq!(flash.read(&banks, 3, 0x1234), 0x5A);
+
+        // programming can only clear bits
+        command(&mut flash, &mut banks, 0xA0);
+        assert!(flash.write(&mut banks, 3, 0x1234, 0xA5));
+        assert_eq!(flash.read(&banks, 3, 0x1234), 0x00);
+        command(&mut flash, &mut banks, 0xA0);
+        assert!(!flash.write(&mut banks, 3, 0x1234, 0xFF));
+    }
+
+
+    #[test]
+    fn commands_use_the_lower_address_lines() {
+        let mut flash = Flash::new();
+        let mut banks = banks();
+
+        flash.write(&mut banks, 5, 0x1D55, 0xAA);
+        flash.write(&mut banks, 5, 0x0AAA, 0x55);
+        flash.write(&mut banks, 5, 0x0555, 0xA0);
+        flash.write(&mut banks, 5, 0x0000, 0x00);
+        assert_eq!(flash.read(&banks, 5, 0x0000), 0x00);
+
+        // a wrong sequence is ignored
+        flash.write(&mut banks, 0, 0x555, 0xAA);
+        flash.write(&mut banks, 0, 0x555, 0x55);
+        flash.write(&mut banks, 0, 0x555, 0xA0);
+        assert!(!flash.write(&mut banks, 0, 0x0000, 0x00));
+        assert_eq!(flash.read(&banks, 0, 0x0000), 0xFF);
+    }
+
+
+    #[test]
+    fn autoselect() {
+        let mut flash = Flash::new();
+        let mut banks = banks();
+
+        assert!(command(&mut flash, &mut banks, 0x90));
+        assert_eq!(flash.read(&banks, 7, 0x0000), MANUFACTURER_ID);
+        assert_eq!(flash.read(&banks, 7, 0x0101), DEVICE_ID);
+        assert_eq!(flash.read(&banks, 7, 0x0002), 0x00);
+
+        // the mode is left with the reset command
+        assert!(!flash.write(&mut banks, 0, 0x0000, 0x00));
+        assert!(flash.write(&mut banks, 0, 0x0000, 0xF0));
+        assert_eq!(flash.read(&banks, 7, 0x0000), 0xFF);
+    }
+
+
+    #[test]
+    fn erase() {
+        let mut flash = Flash::new();
+        let mut banks = banks();
+        for bank in banks.iter_mut() {
+            bank[0] = 0x00;
+        }
+
+        // a sector erase clears the 8 banks of the 64K sector the address is in
+        command(&mut flash, &mut banks, 0x80);
+        flash.write(&mut banks, 0, 0x555, 0xAA);
+        flash.write(&mut banks, 0, 0x2AA, 0x55);
+        assert!(flash.write(&mut banks, 9, 0x0000, 0x30));
+        assert_eq!(banks.iter().map(|bank| bank[0]).collect::<Vec<u8>>(),
+                   vec![0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        command(&mut flash, &mut banks, 0x80);
+        flash.write(&mut banks, 0, 0x555, 0xAA);
+        flash.write(&mut banks, 0, 0x2AA, 0x55);
+        assert!(flash.write(&mut banks, 0, 0x555, 0x10));
+        assert!(banks.iter().all(|bank| bank.iter().all(|&byte| byte == 0xFF)));
+    }
+}
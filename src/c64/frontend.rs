@@ -0,0 +1,55 @@
+// frontend interface - the emulator core never touches a window or an audio device directly
+use c64::cpu;
+use c64::memory;
+use c64::vic;
+
+
+// host keys understood by the emulator (see io.rs for the C64 keyboard mapping)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    Down, Up, Right, Left,
+    Space, Comma, Period, Slash, Enter, Backspace, Backquote,
+    LeftShift, RightShift, LeftCtrl, RightCtrl, LeftAlt, RightAlt, Escape, Tab,
+    Minus, Equal, Insert, Home, End, Delete, PageUp, PageDown,
+    LeftBracket, RightBracket, Semicolon, Apostrophe, Backslash,
+    NumLock,
+    NumPad1, NumPad2, NumPad3, NumPad4, NumPad5, NumPad6, NumPad7, NumPad8, NumPad9,
+}
+
+
+// frames and audio go out, keyboard state comes in
+pub trait Frontend {
+    // returns false once the emulation should stop
+    fn is_open(&self) -> bool;
+
+    // present a finished SCREEN_WIDTH x SCREEN_HEIGHT frame
+    fn draw_frame(&mut self, buffer: &[u32]);
+
+    // queue mono samples (sid::SAMPLE_FREQ Hz) generated since the previous frame
+    fn queue_audio(&mut self, samples: &[i16]);
+
+    // is the key currently held down?
+    fn is_key_down(&self, key: Key) -> bool;
+
+    // was the key pressed since the previous frame? (no key repeat)
+    fn is_key_pressed(&self, key: Key) -> bool;
+
+    // debugger hooks: called after every cycle and on every VBlank
+    fn on_cycle(&mut self, _vic: &mut vic::VICShared) {}
+    fn on_frame(&mut self, _cpu: &mut cpu::CPUShared, _memory: &mut memory::MemShared) {}
+}
+
+
+// frontend without a window or sound - everything produced by the core is discarded
+pub struct Headless;
+
+impl Frontend for Headless {
+    fn is_open(&self) -> bool { true }
+    fn draw_frame(&mut self, _buffer: &[u32]) {}
+    fn queue_audio(&mut self, _samples: &[i16]) {}
+    fn is_key_down(&self, _key: Key) -> bool { false }
+    fn is_key_pressed(&self, _key: Key) -> bool { false }
+}
@@ -0,0 +1,229 @@
+// G64 disk images - the raw GCR data of each (half-)track as the drive head sees it, so copy protections
+// relying on non-standard sectors, track lengths or half-tracks survive
+use byteorder::{ByteOrder, LittleEndian};
+use c64::d64;
+use c64::gcr;
+use std::fs::File;
+use std::io::{Read, Write};
+
+const SIGNATURE: &'static [u8; 8] = b"GCR-1541";
+const HEADER_SIZE: usize = 0x0C;
+
+// half-tracks are numbered like the drive does it: track n is half-track 2n, the image starts with track 1
+pub const MIN_HALF_TRACK: u8 = 2;
+pub const MAX_HALF_TRACK: u8 = 85;
+const NUM_HALF_TRACKS: usize = 84;
+
+// track size stored in images written by the 1541 tools, shorter tracks are padded
+const DEFAULT_MAX_TRACK_SIZE: usize = 7928;
+
+
+pub struct G64 {
+    tracks: Vec<Vec<u8>>, // GCR data indexed by half-track, empty if there is none
+    speed_zones: Vec<u8>, // bit rate of each half-track (0: slowest, 3: fastest)
+}
+
+impl G64 {
+    // an unformatted disk
+    pub fn new() -> G64 {
+        G64 {
+            tracks: vec![Vec::<u8>::new(); MAX_HALF_TRACK as usize + 1],
+            speed_zones: vec![0; MAX_HALF_TRACK as usize + 1],
+        }
+    }
+
+
+    // the GCR encoded sectors of one side of a D64 or D71 image - the second side of a D71 holds tracks 36-70,
+    // a D64 leaves it unformatted
+    pub fn from_d64(image: &d64::D64, side: u8) -> G64 {
+        let mut g64 = G64::new();
+        let (num_tracks, first_track) = match (image.format(), side) {
+            (d64::Format::D71, _) => (35, 35 * side),
+            (_, 0) => (image.num_tracks(), 0),
+            _ => return g64,
+        };
+
+        for track in 1..(num_tracks + 1) {
+            g64.tracks[2 * track as usize] = gcr::encode_track(image, first_track + track);
+            g64.speed_zones[2 * track as usize] = gcr::speed_zone(track);
+        }
+
+        g64
+    }
+
+
+    pub fn from_filename(filename: &str) -> Result<G64, String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        G64::from_bytes(&data)
+    }
+
+
+    pub fn from_bytes(data: &[u8]) -> Result<G64, String> {
+        if data.len() < HEADER_SIZE || &data[0..8] != SIGNATURE {
+            return Err("Invalid G64 signature".to_string())
+        }
+
+        if data[8] != 0 {
+            return Err(format!("Unsupported G64 version {}", data[8]))
+        }
+
+        let num_half_tracks = (data[9] as usize).min(NUM_HALF_TRACKS);
+        let zones_pos = HEADER_SIZE + 4 * data[9] as usize;
+        if data.len() < zones_pos + 4 * data[9] as usize {
+            return Err("G64 track table truncated".to_string())
+        }
+
+        let mut g64 = G64::new();
+
+        for i in 0..num_half_tracks {
+            let half_track = MIN_HALF_TRACK as usize + i;
+            let offset = LittleEndian::read_u32(&data[(HEADER_SIZE + 4 * i)..]) as usize;
+            if offset == 0 {
+                continue;
+            }
+
+            if offset + 2 > data.len() {
+                return Err(format!("G64 half-track {} beyond end of image", half_track))
+            }
+
+            let len = LittleEndian::read_u16(&data[offset..]) as usize;
+            if offset + 2 + len > data.len() {
+                return Err(format!("G64 half-track {} beyond end of image", half_track))
+            }
+
+            g64.tracks[half_track] = data[(offset + 2)..(offset + 2 + len)].to_vec();
+
+            // offsets to per-byte speed maps are not supported, the standard zone of the track is used instead
+            let zone = LittleEndian::read_u32(&data[(zones_pos + 4 * i)..]);
+            g64.speed_zones[half_track] = if zone <= 3 { zone as u8 } else { gcr::speed_zone((half_track / 2) as u8) };
+        }
+
+        Ok(g64)
+    }
+
+
+    // the image in G64 format - all tracks are stored with the size of the longest one
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let max_track_size = self.tracks.iter().map(|track| track.len()).max().unwrap_or(0).max(DEFAULT_MAX_TRACK_SIZE);
+
+        let mut data = vec![0; HEADER_SIZE + 8 * NUM_HALF_TRACKS];
+        data[0..8].copy_from_slice(SIGNATURE);
+        data[8] = 0;
+        data[9] = NUM_HALF_TRACKS as u8;
+        LittleEndian::write_u16(&mut data[10..12], max_track_size as u16);
+
+        for i in 0..NUM_HALF_TRACKS {
+            let half_track = MIN_HALF_TRACK as usize + i;
+            let track = &self.tracks[half_track];
+            if track.is_empty() {
+                continue;
+            }
+
+            let offset = data.len();
+            LittleEndian::write_u32(&mut data[(HEADER_SIZE + 4 * i)..], offset as u32);
+            LittleEndian::write_u32(&mut data[(HEADER_SIZE + 4 * (NUM_HALF_TRACKS + i))..], self.speed_zones[half_track] as u32);
+
+            let mut len = [0; 2];
+            LittleEndian::write_u16(&mut len, track.len() as u16);
+            data.extend_from_slice(&len);
+            data.extend_from_slice(track);
+            data.extend(vec![0; max_track_size - track.len()]);
+        }
+
+        data
+    }
+
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let mut file = File::create(filename).map_err(|e| e.to_string())?;
+        file.write_all(&self.to_bytes()).map_err(|e| e.to_string())
+    }
+
+
+    // GCR data of a half-track (MIN_HALF_TRACK to MAX_HALF_TRACK), empty if it's unformatted
+    pub fn track(&self, half_track: u8) -> &[u8] {
+        &self.tracks[half_track as usize]
+    }
+
+
+    // bytes can be changed in place, the track length stays the same
+    pub fn track_mut(&mut self, half_track: u8) -> &mut [u8] {
+        &mut self.tracks[half_track as usize]
+    }
+
+
+    // replace a half-track with new data of any length
+    pub fn set_track(&mut self, half_track: u8, data: Vec<u8>, speed_zone: u8) -> Result<(), String> {
+        if half_track < MIN_HALF_TRACK || half_track > MAX_HALF_TRACK {
+            return Err(format!("Invalid half-track {}", half_track))
+        }
+
+        if data.len() > 0xFFFF || speed_zone > 3 {
+            return Err(format!("Invalid data for half-track {}", half_track))
+        }
+
+        self.tracks[half_track as usize] = data;
+        self.speed_zones[half_track as usize] = speed_zone;
+        Ok(())
+    }
+
+
+    pub fn speed_zone(&self, half_track: u8) -> u8 {
+        self.speed_zones[half_track as usize]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut g64 = G64::from_d64(&d64::D64::new(b"G64", b"01"), 0);
+        g64.set_track(37, vec![0x55; 6000], 3).unwrap();
+
+        let loaded = G64::from_bytes(&g64.to_bytes()).unwrap();
+        for half_track in MIN_HALF_TRACK..(MAX_HALF_TRACK + 1) {
+            assert_eq!(loaded.track(half_track), g64.track(half_track));
+            if !g64.track(half_track).is_empty() {
+                assert_eq!(loaded.speed_zone(half_track), g64.speed_zone(half_track));
+            }
+        }
+    }
+
+
+    #[test]
+    fn d71_sides() {
+        let mut data = vec![0; 1366 * 256];
+        for (i, sector) in data.chunks_mut(256).enumerate() {
+            sector[0] = (i / 256) as u8;
+            sector[1] = i as u8;
+        }
+        let image = d64::D64::from_bytes(data).unwrap();
+
+        // the second side repeats the layout of the first, its headers keep the track numbers of the image
+        let back = G64::from_d64(&image, 1);
+        assert_eq!((back.track(2).len(), back.speed_zone(2)), (gcr::track_size(1), 3));
+        assert!(back.track(72).is_empty());
+
+        let sectors = gcr::decode_track(back.track(2), 36);
+        assert_eq!(sectors.len(), 21);
+        for (sector, data) in sectors {
+            assert_eq!(data, image.read_sector(36, sector).unwrap());
+        }
+
+        assert!(G64::from_d64(&d64::D64::new(b"G64", b"01"), 1).track(2).is_empty());
+    }
+
+
+    #[test]
+    fn invalid_tracks() {
+        let mut g64 = G64::new();
+        assert!(g64.set_track(1, vec![0x55; 10], 0).is_err());
+        assert!(g64.set_track(2, vec![0x55; 10], 4).is_err());
+        assert!(G64::from_bytes(b"GCR-1541").is_err());
+    }
+}
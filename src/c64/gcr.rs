@@ -0,0 +1,210 @@
+// GCR (group code recording) - the on-disk format written by the 1541, 4 data bits are stored as 5 bits
+// so that there are never more than two consecutive 0 bits and ten consecutive 1 bits only occur in syncs
+use c64::d64;
+
+const GCR_ENCODE: [u8; 16] = [
+    0x0A, 0x0B, 0x12, 0x13, 0x0E, 0x0F, 0x16, 0x17,
+    0x09, 0x19, 0x1A, 0x1B, 0x0D, 0x1D, 0x1E, 0x15,
+];
+
+const SYNC_LEN: usize = 5;       // 0xFF bytes in front of each header and data block
+const HEADER_GAP_LEN: usize = 9; // 0x55 bytes between header and data block
+const SECTOR_GAP_LEN: usize = 8; // 0x55 bytes after each data block
+
+const HEADER_ID: u8 = 0x08;
+const DATA_ID:   u8 = 0x07;
+
+
+// number of bytes passing under the head in one revolution (300 rpm) for each speed zone
+pub fn track_size(track: u8) -> usize {
+    match track {
+        1...17  => 7692,
+        18...24 => 7142,
+        25...30 => 6666,
+        _       => 6250,
+    }
+}
+
+
+// bit rate selected by the DOS for each track (3: fastest, used on the outer tracks)
+pub fn speed_zone(track: u8) -> u8 {
+    match track {
+        1...17  => 3,
+        18...24 => 2,
+        25...30 => 1,
+        _       => 0,
+    }
+}
+
+
+// the GCR data of a whole track of a D64 or D71 image: header and data block of each sector, separated by syncs
+// and gaps - the tracks on the second side of a D71 have the layout of the first side, their headers keep the
+// track number of the image
+pub fn encode_track(image: &d64::D64, track: u8) -> Vec<u8> {
+    let physical_track = if image.format() == d64::Format::D71 && track > 35 { track - 35 } else { track };
+    let mut gcr = Vec::<u8>::with_capacity(track_size(physical_track));
+    let id = image.disk_id();
+
+    for sector in 0..image.num_sectors(track) {
+        let checksum = sector ^ track ^ id[1] ^ id[0];
+        let header = [HEADER_ID, checksum, sector, track, id[1], id[0], 0x0F, 0x0F];
+
+        gcr.extend_from_slice(&[0xFF; SYNC_LEN]);
+        gcr.extend(encode(&header));
+        gcr.extend_from_slice(&[0x55; HEADER_GAP_LEN]);
+
+        let data = image.read_sector(track, sector).unwrap();
+        let mut block = Vec::<u8>::with_capacity(260);
+        block.push(DATA_ID);
+        block.extend_from_slice(data);
+        block.push(data.iter().fold(0, |sum, &b| sum ^ b));
+        block.extend_from_slice(&[0, 0]);
+
+        gcr.extend_from_slice(&[0xFF; SYNC_LEN]);
+        gcr.extend(encode(&block));
+        gcr.extend_from_slice(&[0x55; SECTOR_GAP_LEN]);
+    }
+
+    // the remaining space is filled with gap bytes
+    while gcr.len() < track_size(physical_track) {
+        gcr.push(0x55);
+    }
+
+    gcr
+}
+
+
+// extract all sectors with a valid header and data block from a GCR track - returns (sector number, data)
+pub fn decode_track(gcr: &[u8], track: u8) -> Vec<(u8, Vec<u8>)> {
+    let mut sectors = Vec::<(u8, Vec<u8>)>::new();
+    let mut current_sector: Option<u8> = None;
+
+    // the track is circular, so a block may wrap around its end
+    let mut data = gcr.to_vec();
+    data.extend_from_slice(&gcr[..gcr.len().min(400)]);
+
+    let mut i = 0;
+    while i < gcr.len() {
+        // a block starts after the last 0xFF byte of a sync
+        if data[i] != 0xFF || data[i + 1] == 0xFF {
+            i += 1;
+            continue;
+        }
+
+        i += 1;
+        let header = decode(&data[i..(i + 10).min(data.len())]);
+
+        if header.len() == 8 && header[0] == HEADER_ID && header[3] == track &&
+           header[1] == header[2] ^ header[3] ^ header[4] ^ header[5] {
+            current_sector = Some(header[2]);
+        }
+        else if let Some(sector) = current_sector.take() {
+            let block = decode(&data[i..(i + 325).min(data.len())]);
+
+            if block.len() == 260 && block[0] == DATA_ID && block[257] == block[1..257].iter().fold(0, |sum, &b| sum ^ b) {
+                sectors.push((sector, block[1..257].to_vec()));
+            }
+        }
+    }
+
+    sectors
+}
+
+
+// *** private functions *** //
+
+// encode groups of 4 bytes into 5 GCR bytes
+fn encode(bytes: &[u8]) -> Vec<u8> {
+    let mut gcr = Vec::<u8>::with_capacity(bytes.len() * 5 / 4);
+
+    for group in bytes.chunks(4) {
+        let mut bits: u64 = 0;
+        for &b in group.iter() {
+            bits = (bits << 10) | ((GCR_ENCODE[(b >> 4) as usize] as u64) << 5) | GCR_ENCODE[(b & 0x0F) as usize] as u64;
+        }
+
+        for j in 0..5 {
+            gcr.push((bits >> (32 - j * 8)) as u8);
+        }
+    }
+
+    gcr
+}
+
+
+// decode groups of 5 GCR bytes into 4 bytes - stops at the first invalid code
+fn decode(gcr: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::<u8>::with_capacity(gcr.len() * 4 / 5);
+
+    for group in gcr.chunks(5) {
+        if group.len() < 5 {
+            break;
+        }
+
+        let bits = group.iter().fold(0u64, |bits, &b| (bits << 8) | b as u64);
+
+        for j in 0..4 {
+            let hi = GCR_ENCODE.iter().position(|&c| c as u64 == (bits >> (35 - j * 10)) & 0x1F);
+            let lo = GCR_ENCODE.iter().position(|&c| c as u64 == (bits >> (30 - j * 10)) & 0x1F);
+
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => bytes.push(((hi << 4) | lo) as u8),
+                _ => return bytes,
+            }
+        }
+    }
+
+    bytes
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_bytes() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let gcr = encode(&bytes);
+        assert_eq!(gcr.len(), 320);
+        assert_eq!(decode(&gcr), bytes);
+    }
+
+
+    #[test]
+    fn no_long_runs_of_zero_bits() {
+        let gcr = encode(&[0x00, 0x00, 0x00, 0x00]);
+        let bits: String = gcr.iter().map(|b| format!("{:08b}", b)).collect();
+        assert!(!bits.contains("000"));
+    }
+
+
+    #[test]
+    fn encode_decode_tracks() {
+        let mut image = d64::D64::new(b"GCR", b"42");
+        let contents: Vec<u8> = (0..20000).map(|i| (i * 13) as u8).collect();
+        image.write_file(b"FILE", d64::FileType::Prg, &contents).unwrap();
+
+        for &track in [1, 17, 18, 19, 25, 35].iter() {
+            let gcr = encode_track(&image, track);
+            assert_eq!(gcr.len(), track_size(track));
+
+            let sectors = decode_track(&gcr, track);
+            assert_eq!(sectors.len(), d64::D64::sectors_per_track(track) as usize);
+            for (sector, data) in sectors {
+                assert_eq!(&data[..], image.read_sector(track, sector).unwrap());
+            }
+        }
+    }
+
+
+    #[test]
+    fn decode_track_wrapping_around() {
+        let image = d64::D64::new(b"GCR", b"42");
+        let mut gcr = encode_track(&image, 18);
+        gcr.rotate_left(100);
+
+        assert_eq!(decode_track(&gcr, 18).len(), 19);
+        assert!(decode_track(&gcr, 17).is_empty());
+    }
+}
@@ -1,8 +1,6 @@
 // keyboard and joystick support
-extern crate minifb;
-
-use minifb::*;
 use c64::cia;
+use c64::frontend::{Frontend, Key};
 
 /*
  C64 keyboard map:
@@ -35,97 +33,97 @@ impl IO {
     }
 
 
-    pub fn update(&mut self, window: &Window, cia1: &mut cia::CIAShared) {
+    pub fn update(&mut self, frontend: &dyn Frontend, cia1: &mut cia::CIAShared) {
         // keyboard processing
         // iterating over all keys is crawling-slow, so check individual keys
-        self.process_key(window.is_key_down(Key::Key0), Key::Key0, cia1);
-        self.process_key(window.is_key_down(Key::Key1), Key::Key1, cia1);
-        self.process_key(window.is_key_down(Key::Key2), Key::Key2, cia1);
-        self.process_key(window.is_key_down(Key::Key3), Key::Key3, cia1);
-        self.process_key(window.is_key_down(Key::Key4), Key::Key4, cia1);
-        self.process_key(window.is_key_down(Key::Key5), Key::Key5, cia1);
-        self.process_key(window.is_key_down(Key::Key6), Key::Key6, cia1);
-        self.process_key(window.is_key_down(Key::Key7), Key::Key7, cia1);
-        self.process_key(window.is_key_down(Key::Key8), Key::Key8, cia1);
-        self.process_key(window.is_key_down(Key::Key9), Key::Key9, cia1);
-
-        self.process_key(window.is_key_down(Key::A), Key::A, cia1);
-        self.process_key(window.is_key_down(Key::B), Key::B, cia1);
-        self.process_key(window.is_key_down(Key::C), Key::C, cia1);
-        self.process_key(window.is_key_down(Key::D), Key::D, cia1);
-        self.process_key(window.is_key_down(Key::E), Key::E, cia1);
-        self.process_key(window.is_key_down(Key::F), Key::F, cia1);
-        self.process_key(window.is_key_down(Key::G), Key::G, cia1);
-        self.process_key(window.is_key_down(Key::H), Key::H, cia1);
-        self.process_key(window.is_key_down(Key::I), Key::I, cia1);
-        self.process_key(window.is_key_down(Key::J), Key::J, cia1);
-        self.process_key(window.is_key_down(Key::K), Key::K, cia1);
-        self.process_key(window.is_key_down(Key::L), Key::L, cia1);
-        self.process_key(window.is_key_down(Key::M), Key::M, cia1);
-        self.process_key(window.is_key_down(Key::N), Key::N, cia1);
-        self.process_key(window.is_key_down(Key::O), Key::O, cia1);
-        self.process_key(window.is_key_down(Key::P), Key::P, cia1);
-        self.process_key(window.is_key_down(Key::Q), Key::Q, cia1);
-        self.process_key(window.is_key_down(Key::R), Key::R, cia1);
-        self.process_key(window.is_key_down(Key::S), Key::S, cia1);
-        self.process_key(window.is_key_down(Key::T), Key::T, cia1);
-        self.process_key(window.is_key_down(Key::U), Key::U, cia1);
-        self.process_key(window.is_key_down(Key::V), Key::V, cia1);
-        self.process_key(window.is_key_down(Key::W), Key::W, cia1);
-        self.process_key(window.is_key_down(Key::X), Key::X, cia1);
-        self.process_key(window.is_key_down(Key::Y), Key::Y, cia1);
-        self.process_key(window.is_key_down(Key::Z), Key::Z, cia1);
-        self.process_key(window.is_key_down(Key::F1), Key::F1, cia1);
-        self.process_key(window.is_key_down(Key::F2), Key::F2, cia1);
-        self.process_key(window.is_key_down(Key::F3), Key::F3, cia1);
-        self.process_key(window.is_key_down(Key::F4), Key::F4, cia1);
-        self.process_key(window.is_key_down(Key::F5), Key::F5, cia1);
-        self.process_key(window.is_key_down(Key::F6), Key::F6, cia1);
-        self.process_key(window.is_key_down(Key::F7), Key::F7, cia1);
-        self.process_key(window.is_key_down(Key::F8), Key::F8, cia1);
-
-        self.process_key(window.is_key_down(Key::Down),  Key::Down,  cia1);
-        self.process_key(window.is_key_down(Key::Up),    Key::Up,    cia1);
-        self.process_key(window.is_key_down(Key::Right), Key::Right, cia1);
-        self.process_key(window.is_key_down(Key::Left),  Key::Left,  cia1);
-        self.process_key(window.is_key_down(Key::Space),  Key::Space,  cia1);
-        self.process_key(window.is_key_down(Key::Comma),  Key::Comma,  cia1);
-        self.process_key(window.is_key_down(Key::Period), Key::Period, cia1);
-        self.process_key(window.is_key_down(Key::Slash),  Key::Slash,  cia1);
-        self.process_key(window.is_key_down(Key::Enter),  Key::Enter,  cia1);
-        self.process_key(window.is_key_down(Key::Backspace),  Key::Backspace,  cia1);
-        self.process_key(window.is_key_down(Key::Backquote),  Key::Backquote,  cia1);
-        self.process_key(window.is_key_down(Key::LeftShift),  Key::LeftShift,  cia1);
-        self.process_key(window.is_key_down(Key::RightShift), Key::RightShift, cia1);
-        self.process_key(window.is_key_down(Key::Escape),     Key::Escape,     cia1);
-        self.process_key(window.is_key_down(Key::Minus),  Key::Minus,  cia1);
-        self.process_key(window.is_key_down(Key::Equal),  Key::Equal,  cia1);
-        self.process_key(window.is_key_down(Key::Insert), Key::Insert, cia1);
-        self.process_key(window.is_key_down(Key::Home),   Key::Home,   cia1);
-        self.process_key(window.is_key_down(Key::LeftBracket),  Key::LeftBracket,  cia1);
-        self.process_key(window.is_key_down(Key::RightBracket), Key::RightBracket, cia1);
-        self.process_key(window.is_key_down(Key::Delete),     Key::Delete,     cia1);
-        self.process_key(window.is_key_down(Key::Semicolon),  Key::Semicolon,  cia1);
-        self.process_key(window.is_key_down(Key::Apostrophe), Key::Apostrophe, cia1);
-        self.process_key(window.is_key_down(Key::Backslash),  Key::Backslash,  cia1);
-        self.process_key(window.is_key_down(Key::Tab),        Key::Tab,        cia1);
-        self.process_key(window.is_key_down(Key::LeftCtrl),   Key::LeftCtrl,   cia1);
+        self.process_key(frontend.is_key_down(Key::Key0), Key::Key0, cia1);
+        self.process_key(frontend.is_key_down(Key::Key1), Key::Key1, cia1);
+        self.process_key(frontend.is_key_down(Key::Key2), Key::Key2, cia1);
+        self.process_key(frontend.is_key_down(Key::Key3), Key::Key3, cia1);
+        self.process_key(frontend.is_key_down(Key::Key4), Key::Key4, cia1);
+        self.process_key(frontend.is_key_down(Key::Key5), Key::Key5, cia1);
+        self.process_key(frontend.is_key_down(Key::Key6), Key::Key6, cia1);
+        self.process_key(frontend.is_key_down(Key::Key7), Key::Key7, cia1);
+        self.process_key(frontend.is_key_down(Key::Key8), Key::Key8, cia1);
+        self.process_key(frontend.is_key_down(Key::Key9), Key::Key9, cia1);
+
+        self.process_key(frontend.is_key_down(Key::A), Key::A, cia1);
+        self.process_key(frontend.is_key_down(Key::B), Key::B, cia1);
+        self.process_key(frontend.is_key_down(Key::C), Key::C, cia1);
+        self.process_key(frontend.is_key_down(Key::D), Key::D, cia1);
+        self.process_key(frontend.is_key_down(Key::E), Key::E, cia1);
+        self.process_key(frontend.is_key_down(Key::F), Key::F, cia1);
+        self.process_key(frontend.is_key_down(Key::G), Key::G, cia1);
+        self.process_key(frontend.is_key_down(Key::H), Key::H, cia1);
+        self.process_key(frontend.is_key_down(Key::I), Key::I, cia1);
+        self.process_key(frontend.is_key_down(Key::J), Key::J, cia1);
+        self.process_key(frontend.is_key_down(Key::K), Key::K, cia1);
+        self.process_key(frontend.is_key_down(Key::L), Key::L, cia1);
+        self.process_key(frontend.is_key_down(Key::M), Key::M, cia1);
+        self.process_key(frontend.is_key_down(Key::N), Key::N, cia1);
+        self.process_key(frontend.is_key_down(Key::O), Key::O, cia1);
+        self.process_key(frontend.is_key_down(Key::P), Key::P, cia1);
+        self.process_key(frontend.is_key_down(Key::Q), Key::Q, cia1);
+        self.process_key(frontend.is_key_down(Key::R), Key::R, cia1);
+        self.process_key(frontend.is_key_down(Key::S), Key::S, cia1);
+        self.process_key(frontend.is_key_down(Key::T), Key::T, cia1);
+        self.process_key(frontend.is_key_down(Key::U), Key::U, cia1);
+        self.process_key(frontend.is_key_down(Key::V), Key::V, cia1);
+        self.process_key(frontend.is_key_down(Key::W), Key::W, cia1);
+        self.process_key(frontend.is_key_down(Key::X), Key::X, cia1);
+        self.process_key(frontend.is_key_down(Key::Y), Key::Y, cia1);
+        self.process_key(frontend.is_key_down(Key::Z), Key::Z, cia1);
+        self.process_key(frontend.is_key_down(Key::F1), Key::F1, cia1);
+        self.process_key(frontend.is_key_down(Key::F2), Key::F2, cia1);
+        self.process_key(frontend.is_key_down(Key::F3), Key::F3, cia1);
+        self.process_key(frontend.is_key_down(Key::F4), Key::F4, cia1);
+        self.process_key(frontend.is_key_down(Key::F5), Key::F5, cia1);
+        self.process_key(frontend.is_key_down(Key::F6), Key::F6, cia1);
+        self.process_key(frontend.is_key_down(Key::F7), Key::F7, cia1);
+        self.process_key(frontend.is_key_down(Key::F8), Key::F8, cia1);
+
+        self.process_key(frontend.is_key_down(Key::Down),  Key::Down,  cia1);
+        self.process_key(frontend.is_key_down(Key::Up),    Key::Up,    cia1);
+        self.process_key(frontend.is_key_down(Key::Right), Key::Right, cia1);
+        self.process_key(frontend.is_key_down(Key::Left),  Key::Left,  cia1);
+        self.process_key(frontend.is_key_down(Key::Space),  Key::Space,  cia1);
+        self.process_key(frontend.is_key_down(Key::Comma),  Key::Comma,  cia1);
+        self.process_key(frontend.is_key_down(Key::Period), Key::Period, cia1);
+        self.process_key(frontend.is_key_down(Key::Slash),  Key::Slash,  cia1);
+        self.process_key(frontend.is_key_down(Key::Enter),  Key::Enter,  cia1);
+        self.process_key(frontend.is_key_down(Key::Backspace),  Key::Backspace,  cia1);
+        self.process_key(frontend.is_key_down(Key::Backquote),  Key::Backquote,  cia1);
+        self.process_key(frontend.is_key_down(Key::LeftShift),  Key::LeftShift,  cia1);
+        self.process_key(frontend.is_key_down(Key::RightShift), Key::RightShift, cia1);
+        self.process_key(frontend.is_key_down(Key::Escape),     Key::Escape,     cia1);
+        self.process_key(frontend.is_key_down(Key::Minus),  Key::Minus,  cia1);
+        self.process_key(frontend.is_key_down(Key::Equal),  Key::Equal,  cia1);
+        self.process_key(frontend.is_key_down(Key::Insert), Key::Insert, cia1);
+        self.process_key(frontend.is_key_down(Key::Home),   Key::Home,   cia1);
+        self.process_key(frontend.is_key_down(Key::LeftBracket),  Key::LeftBracket,  cia1);
+        self.process_key(frontend.is_key_down(Key::RightBracket), Key::RightBracket, cia1);
+        self.process_key(frontend.is_key_down(Key::Delete),     Key::Delete,     cia1);
+        self.process_key(frontend.is_key_down(Key::Semicolon),  Key::Semicolon,  cia1);
+        self.process_key(frontend.is_key_down(Key::Apostrophe), Key::Apostrophe, cia1);
+        self.process_key(frontend.is_key_down(Key::Backslash),  Key::Backslash,  cia1);
+        self.process_key(frontend.is_key_down(Key::Tab),        Key::Tab,        cia1);
+        self.process_key(frontend.is_key_down(Key::LeftCtrl),   Key::LeftCtrl,   cia1);
 
         // joystick processing
-        self.process_joystick(window.is_key_down(Key::NumPad1), Key::NumPad1, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad2), Key::NumPad2, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad3), Key::NumPad3, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad4), Key::NumPad4, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad5), Key::NumPad5, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad6), Key::NumPad6, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad7), Key::NumPad7, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad8), Key::NumPad8, cia1);
-        self.process_joystick(window.is_key_down(Key::NumPad9), Key::NumPad9, cia1);
-        self.process_joystick(window.is_key_down(Key::RightCtrl), Key::RightCtrl, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad1), Key::NumPad1, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad2), Key::NumPad2, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad3), Key::NumPad3, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad4), Key::NumPad4, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad5), Key::NumPad5, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad6), Key::NumPad6, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad7), Key::NumPad7, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad8), Key::NumPad8, cia1);
+        self.process_joystick(frontend.is_key_down(Key::NumPad9), Key::NumPad9, cia1);
+        self.process_joystick(frontend.is_key_down(Key::RightCtrl), Key::RightCtrl, cia1);
 
         // helper keys
         // toggle between joystick ports 1 and 2
-        if window.is_key_pressed(Key::NumLock, KeyRepeat::No) {
+        if frontend.is_key_pressed(Key::NumLock) {
             self.joy_port1 = !self.joy_port1;
 
             if self.joy_port1 {
@@ -140,9 +138,9 @@ impl IO {
     }
 
 
-    pub fn check_restore_key(&self, window: &Window) -> bool {
+    pub fn check_restore_key(&self, frontend: &dyn Frontend) -> bool {
         // End will serve as the Restore key
-        window.is_key_pressed(Key::End, KeyRepeat::No)
+        frontend.is_key_pressed(Key::End)
     }
 
 
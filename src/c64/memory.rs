@@ -1,4 +1,7 @@
 // memory banks
+use c64::cpu;
+use c64::crt;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
 use std::cell::RefCell;
 use std::rc::Rc;
 use utils;
@@ -11,6 +14,9 @@ pub enum MemType {
     Chargen,
     Io,
     Kernal,
+    Roml,
+    Romh,
+    Open,
 }
 
 
@@ -60,6 +66,19 @@ impl MemBank {
 
                 mem_bank.offset = 0xD000;
                 mem_bank.read_only = false;
+            },
+            // cartridge ROM - filled with the selected bank of the cartridge
+            MemType::Roml => {
+                mem_bank.data = vec![0xFF; 0x2000];
+                mem_bank.offset = 0x8000;
+            },
+            MemType::Romh => {
+                mem_bank.data = vec![0xFF; 0x2000];
+                mem_bank.offset = 0xA000;
+            },
+            // unmapped address space in Ultimax mode - nothing responds to reads or writes
+            MemType::Open => {
+                mem_bank.read_only = false;
             }
         }
         
@@ -83,6 +102,7 @@ impl MemBank {
                 }
                 
             },
+            MemType::Open => (),
             _ => panic!("Can't write to ROM!")
         }
     }
@@ -107,9 +127,25 @@ impl MemBank {
                     _ => self.data[(addr - self.offset) as usize]
                 }
             },
+            MemType::Open => 0xFF,
             _ => self.data[(addr - self.offset) as usize]
         }
-    }    
+    }
+
+
+    // read stored value without triggering any register side effects
+    pub fn peek(&self, addr: u16) -> u8 {
+        match self.bank_type {
+            MemType::Io => {
+                match addr {
+                    0xD040...0xD3FF => self.peek(0xD000 + (addr % 0x0040)),
+                    _ => self.data[(addr - self.offset) as usize]
+                }
+            },
+            MemType::Open => 0xFF,
+            _ => self.data[(addr - self.offset) as usize]
+        }
+    }
 }
 
 
@@ -120,6 +156,9 @@ pub struct Memory {
     chargen: MemBank,
     io:      MemBank,
     kernal:  MemBank,
+    roml:    MemBank,
+    romh:    MemBank,
+    open:    MemBank,
 
     // bank switching flags
     pub exrom:      bool,
@@ -128,6 +167,17 @@ pub struct Memory {
     pub chargen_on: bool,
     pub io_on:      bool,
     pub kernal_on:  bool,
+    pub roml_on:    bool,
+    pub romh_on:    bool,
+    pub ultimax:    bool, // EXROM high and GAME low: ROML, ROMH at $E000, I/O and 4K of RAM, the rest is unmapped
+
+    // datasette lines on the 6510 port
+    pub cassette_sense: bool, // a button is pressed (bit 4 reads 0)
+    pub cassette_motor: bool, // motor is running (bit 5 is low)
+    pub cassette_write: bool, // level of the write line (bit 3)
+
+    cartridge: Option<crt::Cartridge>,
+    mapped_cartridge: Option<(usize, bool, bool)>, // bank and EXROM/GAME lines last mapped into ROML/ROMH
 }
 
 impl Memory {
@@ -138,12 +188,23 @@ impl Memory {
             chargen: MemBank::new(MemType::Chargen), // 4k
             io:      MemBank::new(MemType::Io),      // 4k (VIC, SID, CIA, Color RAM)
             kernal:  MemBank::new(MemType::Kernal),  // 8k
+            roml:    MemBank::new(MemType::Roml),    // 8k (cartridge)
+            romh:    MemBank::new(MemType::Romh),    // 8k (cartridge)
+            open:    MemBank::new(MemType::Open),
             exrom:      true,
             game:       true,
             basic_on:   false,
             chargen_on: false,
             io_on:      false,
             kernal_on:  false,
+            roml_on:    false,
+            romh_on:    false,
+            ultimax:    false,
+            cassette_sense: false,
+            cassette_motor: false,
+            cassette_write: false,
+            cartridge: None,
+            mapped_cartridge: None,
         }))
     }
     
@@ -151,15 +212,26 @@ impl Memory {
     // returns memory bank for current latch setting and address
     pub fn get_bank(&mut self, addr: u16) -> (&mut MemBank) {
         match addr {
-            0x0000...0x9FFF => &mut self.ram,
-            0xA000...0xBFFF => if self.basic_on { &mut self.basic } else { &mut self.ram },
-            0xC000...0xCFFF => &mut self.ram,
+            0x0000...0x0FFF => &mut self.ram,
+            0x1000...0x7FFF => if self.ultimax { &mut self.open } else { &mut self.ram },
+            0x8000...0x9FFF => if self.roml_on { &mut self.roml } else { &mut self.ram },
+            0xA000...0xBFFF => {
+                if self.ultimax    { return &mut self.open; }
+                if self.romh_on    { return &mut self.romh; }
+                if self.basic_on   { return &mut self.basic; }
+                return &mut self.ram;
+            },
+            0xC000...0xCFFF => if self.ultimax { &mut self.open } else { &mut self.ram },
             0xD000...0xDFFF => {
                 if self.chargen_on { return &mut self.chargen }
                 if self.io_on      { return &mut self.io; }
                 return &mut self.ram;
             },
-            0xE000...0xFFFF => if self.kernal_on  { &mut self.kernal } else { &mut self.ram },
+            0xE000...0xFFFF => {
+                if self.ultimax    { return &mut self.romh; }
+                if self.kernal_on  { return &mut self.kernal; }
+                return &mut self.ram;
+            },
             _ => panic!("Address out of memory range")
         }
     }
@@ -181,12 +253,126 @@ impl Memory {
             MemType::Basic   => &mut self.basic,
             MemType::Chargen => &mut self.chargen,
             MemType::Kernal  => &mut self.kernal,
+            MemType::Roml    => &mut self.roml,
+            MemType::Romh    => &mut self.romh,
             _                => panic!("Unrecognized ROM Abank"),
         }
     }    
     
 
+    // plug a cartridge into the expansion port - its lines and ROM banks take effect right away
+    pub fn attach_cartridge(&mut self, cartridge: crt::Cartridge) {
+        self.cartridge = Some(cartridge);
+        self.mapped_cartridge = None;
+        self.update_cartridge();
+    }
+
+
+    pub fn has_cartridge(&self) -> bool {
+        self.cartridge.is_some()
+    }
+
+
+    pub fn cartridge_mut(&mut self) -> Option<&mut crt::Cartridge> {
+        self.cartridge.as_mut()
+    }
+
+
+    // I/O1 ($DE00-$DEFF) and I/O2 ($DF00-$DFFF) are decoded by the cartridge - returns None if nothing drives the bus
+    pub fn read_io_expansion(&mut self, addr: u16) -> Option<u8> {
+        let value = match self.cartridge {
+            Some(ref mut cartridge) => cartridge.read_io(addr),
+            None => Some(self.io.read(addr)),
+        };
+
+        self.update_cartridge();
+        value
+    }
+
+
+    pub fn write_io_expansion(&mut self, addr: u16, value: u8, on_write: &mut cpu::Callback) {
+        let changed = match self.cartridge {
+            Some(ref mut cartridge) => cartridge.write_io(addr, value, on_write),
+            None => {
+                self.io.write(addr, value);
+                false
+            },
+        };
+
+        if changed {
+            self.mapped_cartridge = None;
+        }
+
+        self.update_cartridge();
+    }
+
+
+    // press the freeze button of the cartridge - returns false if it's not a freezer
+    pub fn freeze_cartridge(&mut self) -> bool {
+        if !self.cartridge.as_mut().map_or(false, |cartridge| cartridge.freeze()) {
+            return false
+        }
+
+        self.mapped_cartridge = None;
+        self.update_cartridge();
+        true
+    }
+
+
+    // ROMs are not part of the state - only RAM, IO/Color RAM and the latch flags
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_bytes(&self.ram.data);
+        w.write_bytes(&self.io.data);
+        w.write_bool(self.exrom);
+        w.write_bool(self.game);
+        w.write_bool(self.basic_on);
+        w.write_bool(self.chargen_on);
+        w.write_bool(self.io_on);
+        w.write_bool(self.kernal_on);
+        w.write_bool(self.cassette_motor);
+        w.write_bool(self.cassette_write);
+
+        w.write_bool(self.cartridge.is_some());
+        if let Some(ref cartridge) = self.cartridge {
+            cartridge.save_state(w);
+        }
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        r.read_bytes(&mut self.ram.data)?;
+        r.read_bytes(&mut self.io.data)?;
+        self.exrom      = r.read_bool()?;
+        self.game       = r.read_bool()?;
+        self.basic_on   = r.read_bool()?;
+        self.chargen_on = r.read_bool()?;
+        self.io_on      = r.read_bool()?;
+        self.kernal_on  = r.read_bool()?;
+        self.cassette_motor = r.read_bool()?;
+        self.cassette_write = r.read_bool()?;
+
+        if r.read_bool()? != self.cartridge.is_some() {
+            return Err("Snapshot is for a different cartridge setup".to_string())
+        }
+
+        if let Some(ref mut cartridge) = self.cartridge {
+            cartridge.load_state(r)?;
+        }
+
+        // the cartridge banks and the flags derived from the latch are mapped again
+        self.mapped_cartridge = None;
+        self.update_cartridge();
+        self.update_memory_latch();
+        Ok(())
+    }
+
+
     pub fn reset(&mut self) {
+        if let Some(ref mut cartridge) = self.cartridge {
+            cartridge.reset();
+        }
+
+        self.update_cartridge();
         self.write_byte(0x0000, 0xFF);
         self.write_byte(0x0001, 0x07); // enable kernal, chargen and basic ROMs
     }
@@ -195,8 +381,17 @@ impl Memory {
     // Write a byte to memory - returns whether RAM was written (true) or RAM under ROM (false)
     pub fn write_byte(&mut self, addr: u16, value: u8) -> bool {
         // RAM under ROM written? Return false to let us know about it
+        // in Ultimax mode there's no RAM under the cartridge ROM, flash chips or RAM of the cartridge take the write
         if self.get_bank(addr).read_only {
-            self.ram.write(addr, value);
+            if !self.ultimax {
+                self.ram.write(addr, value);
+            }
+
+            if self.cartridge.as_mut().map_or(false, |cartridge| cartridge.write_rom(addr, value)) {
+                self.mapped_cartridge = None;
+                self.update_cartridge();
+            }
+
             return false;
         }
         else {
@@ -219,13 +414,24 @@ impl Memory {
         if addr == 0x0001 {
             let ddr = self.ram.read(0x0000);
             let pr  = self.ram.read(0x0001);
-            return (ddr & pr) | (!ddr & 0x17);
+            let sense = if self.cassette_sense { 0x00 } else { 0x10 };
+            return (ddr & pr) | (!ddr & (0x07 | sense));
         }
         
         self.get_bank(addr).read(addr)
     }
 
 
+    // Read a byte from memory without side effects
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        if addr == 0x0001 {
+            return self.read_byte(addr);
+        }
+
+        self.get_bank(addr).peek(addr)
+    }
+
+
     // Read a word from memory (stored in little endian)
     pub fn read_word_le(&mut self, addr: u16) -> u16 {
         let bank = self.get_bank(addr);
@@ -239,6 +445,35 @@ impl Memory {
 
     // *** private functions *** //
 
+    // follow the bank switching of the cartridge - the selected banks are mapped into ROML and ROMH
+    fn update_cartridge(&mut self) {
+        let state = match self.cartridge {
+            Some(ref cartridge) => (cartridge.bank(), cartridge.exrom(), cartridge.game()),
+            None => return,
+        };
+
+        if self.mapped_cartridge == Some(state) {
+            return;
+        }
+
+        self.mapped_cartridge = Some(state);
+        let (_, exrom, game) = state;
+        self.exrom = exrom;
+        self.game = game;
+
+        if let Some(ref cartridge) = self.cartridge {
+            for addr in 0..0x2000 {
+                self.roml.data[addr as usize] = cartridge.read_roml(addr);
+                self.romh.data[addr as usize] = cartridge.read_romh(addr);
+            }
+        }
+
+        // ROMH is seen at $E000 in Ultimax mode
+        self.romh.offset = if exrom && !game { 0xE000 } else { 0xA000 };
+        self.update_memory_latch();
+    }
+
+
     // update status of memory bank latches
     fn update_memory_latch(&mut self) {
         let ddr = self.ram.read(0x0000);
@@ -249,15 +484,115 @@ impl Memory {
         self.io_on      = ((latch & 0x04) != 0) && ((latch & 0x03) != 0); // %1xx except %100
         self.basic_on   = (latch & 0x03) == 3;
         self.kernal_on  = (latch & 0x02) != 0; 
+
+        // the motor is switched on by driving bit 5 low
+        self.cassette_motor = (latch & 0x20) == 0;
+        self.cassette_write = (latch & 0x08) != 0;
         
-        // binary logic is hard
-        if self.exrom && !self.game {
-            self.basic_on = false;
-            self.kernal_on = false;
+        // cartridge ROM: ROML needs LORAM and HIRAM, ROMH in 16K mode only HIRAM - Ultimax mode ignores the latch
+        self.ultimax = self.exrom && !self.game;
+        self.roml_on = self.ultimax || (!self.exrom && (latch & 0x03) == 3);
+        self.romh_on = self.ultimax || (!self.exrom && !self.game && (latch & 0x02) != 0);
+
+        if self.ultimax {
+            self.basic_on   = false;
+            self.kernal_on  = false;
+            self.chargen_on = false;
+            self.io_on      = true;
         }
-        if !self.exrom && !self.game {
+        if self.romh_on {
             self.basic_on = false;
         }
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+
+    // memory after reset with a cartridge of an 8K ROML chip filled with $11 and an 8K ROMH chip filled with $22 -
+    // EXROM and GAME are given as stored in the header (0: active)
+    fn memory_with_cartridge(name: &str, exrom: u8, game: u8) -> MemShared {
+        let mut data = b"C64 CARTRIDGE   ".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 0x40, 1, 0, 0, 0, exrom, game]);
+        data.resize(0x40, 0);
+        for &(load_addr, value) in [(0x80, 0x11), (0xA0, 0x22)].iter() {
+            data.extend_from_slice(b"CHIP");
+            data.extend_from_slice(&[0, 0, 0x20, 0x10, 0, 0, 0, 0, load_addr, 0, 0x20, 0]);
+            data.extend(vec![value; 0x2000]);
+        }
+
+        let path = env::temp_dir().join(format!("rust64_memory_{}.crt", name));
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let crt = crt::Crt::from_filename(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let memory = Memory::new_shared();
+        memory.borrow_mut().attach_cartridge(crt::Cartridge::new(crt, ""));
+        memory.borrow_mut().reset();
+        memory
+    }
+
+
+    #[test]
+    fn roml_only() {
+        let memory = memory_with_cartridge("8k", 0, 1);
+        let mut memory = memory.borrow_mut();
+        assert_eq!(memory.read_byte(0x8000), 0x11);
+        assert_eq!(memory.read_byte(0xA000), 0x94); // BASIC
+        assert_eq!(memory.read_byte(0xE000), 0x85); // KERNAL
+
+        // writes go to the RAM below, which shows up without LORAM
+        assert!(!memory.write_byte(0x8000, 0x42));
+        memory.write_byte(0x0001, 0x06);
+        assert_eq!(memory.read_byte(0x8000), 0x42);
+    }
+
+
+    #[test]
+    fn roml_and_romh() {
+        let memory = memory_with_cartridge("16k", 0, 0);
+        let mut memory = memory.borrow_mut();
+        assert_eq!(memory.read_byte(0x9FFF), 0x11);
+        assert_eq!(memory.read_byte(0xA000), 0x22);
+
+        // ROMH only needs HIRAM
+        memory.write_byte(0x0001, 0x06);
+        assert_eq!(memory.read_byte(0x8000), 0x00);
+        assert_eq!(memory.read_byte(0xA000), 0x22);
+
+        memory.write_byte(0x0001, 0x05);
+        assert_eq!(memory.read_byte(0xA000), 0x00);
+    }
+
+
+    #[test]
+    fn ultimax() {
+        let memory = memory_with_cartridge("ultimax", 1, 0);
+        let mut memory = memory.borrow_mut();
+        assert!(memory.ultimax);
+        assert_eq!(memory.read_byte(0x8000), 0x11);
+        assert_eq!(memory.read_byte(0xFFFF), 0x22);
+
+        // only the first 4K of RAM and I/O are there, the latch doesn't matter
+        memory.write_byte(0x0001, 0x00);
+        memory.write_byte(0x0800, 0x42);
+        assert_eq!(memory.read_byte(0x0800), 0x42);
+        memory.write_byte(0x1000, 0x42);
+        assert_eq!(memory.read_byte(0x1000), 0xFF);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+        assert_eq!(memory.read_byte(0xC000), 0xFF);
+        memory.write_byte(0xD020, 0x01);
+        assert_eq!(memory.read_byte(0xD020), 0xF1);
+
+        // there is no RAM below the cartridge ROM
+        memory.write_byte(0x8000, 0x42);
+        assert_eq!(memory.ram.read(0x8000), 0x00);
+    }
+}
@@ -1,35 +1,201 @@
 // main module for C64 updates
-extern crate minifb;
-
+pub mod c1541;
 pub mod cpu;
+pub mod d64;
+pub mod frontend;
+pub mod g64;
 pub mod memory;
+pub mod movie;
 pub mod opcodes;
+pub mod p00;
+pub mod sid;
+pub mod t64;
+pub mod tap;
 pub mod vic;
 pub mod crt;
 
 mod cia;
 mod clock;
+mod datasette;
+mod flash;
+mod gcr;
 mod io;
-mod sid;
+mod rewind;
 mod sid_tables;
+mod snapshot;
+mod vdrive;
+mod via;
 mod vic_tables;
+mod wd1770;
 
-use debugger;
-use minifb::*;
-use utils;
+use c64::frontend::{Frontend, Key};
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use num::FromPrimitive;
+use std::cell::Ref;
+use std::fs::File;
+use std::io::{Read, Write};
 
 
 pub const SCREEN_WIDTH:  usize = 384; // extend 20 pixels left and right for the borders
 pub const SCREEN_HEIGHT: usize = 272; // extend 36 pixels top and down for the borders
 
-// PAL clock frequency in Hz
-const CLOCK_FREQ: f64 = 1.5 * 985248.0;
+// snapshot file used by the F10 hotkey if none is configured
+const DEFAULT_SNAPSHOT_FILE: &'static str = "rust64.snap";
+
+// capture a rewind state every n frames
+const REWIND_INTERVAL: u32 = 5;
+
+// frames to run after rewinding, so the VIC (which skips every other frame) redraws the screen
+const REWIND_REDRAW_FRAMES: u32 = 2;
+
+// KERNAL entry points trapped by the virtual drive and tape (reached through the LOAD/SAVE vectors at $0330/$0332)
+const KERNAL_LOAD: u16 = 0xF4A5;
+const KERNAL_SAVE: u16 = 0xF5ED;
+
+// KERNAL exits used to return from a trap: successful LOAD/SAVE and the I/O error handlers
+const KERNAL_LOAD_OK:       u16 = 0xF5A9; // CLC, X/Y = end address from $AE/$AF, RTS
+const KERNAL_SAVE_OK:       u16 = 0xF68D; // CLC, RTS
+const KERNAL_ERR_NOT_FOUND: u16 = 0xF704; // ?FILE NOT FOUND
+const KERNAL_ERR_NO_NAME:   u16 = 0xF710; // ?MISSING FILE NAME
+
+// KERNAL keyboard buffer used to type commands: the buffer itself, number of keys in it and its maximum size
+const KEYBOARD_BUFFER:      u16 = 0x0277;
+const KEYBOARD_BUFFER_LEN:  u16 = 0x00C6;
+const KEYBOARD_BUFFER_SIZE: u16 = 0x0289;
+
+// start of BASIC programs and the token of the SYS command, for finding the entry point of machine code programs
+const BASIC_START: u16 = 0x0801;
+const TOKEN_SYS: u8 = 0x9E;
+
+// BASIC zero page pointers: start of the program, variables, arrays, end of arrays, strings and top of BASIC memory
+const TXTTAB: u16 = 0x2B;
+const VARTAB: u16 = 0x2D;
+const ARYTAB: u16 = 0x2F;
+const STREND: u16 = 0x31;
+const FRETOP: u16 = 0x33;
+const MEMSIZ: u16 = 0x37;
+
+// machine model - selects the VIC-II revision along with the matching clock and mains frequency
+enum_from_primitive! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Model {
+        Pal,     // 6569:     63 cycles x 312 lines, 985248 Hz, 50 Hz
+        Ntsc,    // 6567R8:   65 cycles x 263 lines, 1022727 Hz, 60 Hz
+        NtscOld, // 6567R56A: 64 cycles x 262 lines, 1022727 Hz, 60 Hz
+    }
+}
+
+impl Default for Model {
+    fn default() -> Model { Model::Pal }
+}
+
+impl Model {
+    // system clock frequency in Hz
+    pub fn clock_freq(&self) -> u32 {
+        match *self {
+            Model::Pal => 985248,
+            Model::Ntsc | Model::NtscOld => 1022727,
+        }
+    }
+
+
+    pub fn cycles_per_line(&self) -> u8 {
+        match *self {
+            Model::Pal     => 63,
+            Model::Ntsc    => 65,
+            Model::NtscOld => 64,
+        }
+    }
+
+
+    pub fn num_rasterlines(&self) -> u16 {
+        match *self {
+            Model::Pal     => 312,
+            Model::Ntsc    => 263,
+            Model::NtscOld => 262,
+        }
+    }
+
+
+    // raster lines shown in the window - NTSC has fewer lines than the window, its picture is centered in it
+    pub fn first_disp_line(&self) -> u16 {
+        match *self {
+            Model::Pal => 0x10,
+            Model::Ntsc | Model::NtscOld => 0x1C,
+        }
+    }
+
+
+    pub fn last_disp_line(&self) -> u16 {
+        match *self {
+            Model::Pal => 0x11F,
+            Model::Ntsc | Model::NtscOld => self.num_rasterlines() - 1,
+        }
+    }
+
+
+    pub fn cycles_per_frame(&self) -> u32 {
+        self.cycles_per_line() as u32 * self.num_rasterlines() as u32
+    }
+
+
+    // mains frequency in Hz (drives the CIA TOD clocks)
+    pub fn tod_freq(&self) -> u32 {
+        match *self {
+            Model::Pal => 50,
+            Model::Ntsc | Model::NtscOld => 60,
+        }
+    }
+}
+
+
+// machine configuration
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub model: Model,
+    pub prg_file: Option<String>, // *.prg to load once BASIC is ready
+    pub crt_file: Option<String>, // *.crt to attach on power-on
+    pub t64_file: Option<String>, // *.t64 to load the first file from once BASIC is ready, also served to tape LOAD
+    pub tap_file: Option<String>, // *.tap to insert into the datasette
+    pub tap_record_file: Option<String>, // new *.tap inserted as a blank tape to SAVE to
+    pub snapshot_file: Option<String>, // snapshot to save to/restore from with F10/Shift+F10
+    pub drive8: Option<String>,   // *.d64 image or host directory served as drive 8
+    pub true_drive: bool,         // emulate a real drive on the IEC bus instead of trapping LOAD/SAVE
+    pub drive_model: c1541::DriveModel, // 1541, 1571 or 1581 for the true drive
+    pub speed: u32,               // emulation speed in percent of real time
+    pub warp: bool,               // run as fast as possible (audio is muted)
+    pub rewind_seconds: u32,      // how far back the machine can be rewound (0: rewind disabled)
+    pub seed: u32,                // seed for the SID random generator (noise waveform, OSC3/ENV3)
+    pub autostart: bool,          // type RUN, SYS or LOAD for the loaded program, tape or disk once BASIC is ready
+    pub autostart_delay: u32,     // frames to wait before typing the autostart command
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            model: Model::default(),
+            prg_file: None,
+            crt_file: None,
+            t64_file: None,
+            tap_file: None,
+            tap_record_file: None,
+            snapshot_file: None,
+            drive8: None,
+            true_drive: false,
+            drive_model: c1541::DriveModel::default(),
+            speed: 100,
+            warp: false,
+            rewind_seconds: 10,
+            seed: 0,
+            autostart: false,
+            autostart_delay: 0,
+        }
+    }
+}
 
 
 pub struct C64 {
-    pub main_window: minifb::Window,
-    pub file_to_load: String,
-    pub crt_to_load: String,
+    config: Config,
     memory: memory::MemShared,
     io:     io::IO,
     clock:  clock::Clock,
@@ -38,41 +204,100 @@ pub struct C64 {
     cia2: cia::CIAShared,
     vic:  vic::VICShared,
     sid:  sid::SIDShared,
+    vdrive: vdrive::VirtualDrive,
+    drive:  Option<c1541::Drive>, // true drive emulation, replaces the virtual drive
+    tape_archive: Option<t64::T64>, // served to LOAD from device 1
+    datasette: datasette::Datasette,
+    typed_keys: Vec<u8>, // PETSCII waiting to be put into the keyboard buffer
+    autostart: Option<(u32, Vec<u8>)>, // frames left until the autostart command is typed
 
-    debugger: Option<debugger::Debugger>,
-    powered_on: bool,
     boot_complete: bool,
-    cycle_count: u32,
+    frame_finished: bool,
+    cycle_count: u64, // movie events are stamped with it - the VIC and CPU only need its low 32 bits
+    tod_cycles: u32, // mains frequency accumulator for the CIA TOD clocks
+    resample_frac: u32, // fractional sample position when resampling audio to the emulation speed
+    rewind: rewind::RewindBuffer,
+    rewind_frame_cnt: u32,
+    recording: Option<movie::Movie>,
+    last_input: Option<movie::InputState>, // last input written to the recorded movie
+    playback: Option<movie::Movie>,
+    playback_idx: usize,
 }
 
 impl C64 {
-    pub fn new(window_scale: Scale, debugger_on: bool, prg_to_load: &str, crt_to_load: &str) -> C64 {
+    pub fn new(config: Config) -> C64 {
         let memory = memory::Memory::new_shared();
-        let vic    = vic::VIC::new_shared();
+        let vic    = vic::VIC::new_shared(config.model);
         let cia1   = cia::CIA::new_shared(true);
         let cia2   = cia::CIA::new_shared(false);
         let cpu    = cpu::CPU::new_shared();
-        let sid    = sid::SID::new_shared();
+        let sid    = sid::SID::new_shared(config.model.clock_freq());
+        let clock  = clock::Clock::new(config.model.clock_freq() as f64);
+        let rewind_states = config.rewind_seconds * config.model.clock_freq() / (config.model.cycles_per_frame() * REWIND_INTERVAL);
 
         let mut c64 = C64 {
-            main_window: Window::new("Rust64", SCREEN_WIDTH, SCREEN_HEIGHT, WindowOptions { scale: window_scale, ..Default::default() }).unwrap(),
-            file_to_load: String::from(prg_to_load),
-            crt_to_load: String::from(crt_to_load),
+            config: config,
             memory: memory.clone(), // shared system memory (RAM, ROM, IO registers)
             io:     io::IO::new(),
-            clock:  clock::Clock::new(CLOCK_FREQ),
+            clock:  clock,
             cpu:  cpu.clone(),
             cia1: cia1.clone(),
             cia2: cia2.clone(),
             vic:  vic.clone(),
             sid:  sid.clone(),
-            debugger: if debugger_on { Some(debugger::Debugger::new()) } else { None },
-            powered_on: false,
+            vdrive: vdrive::VirtualDrive::new(),
+            drive:  None,
+            tape_archive: None,
+            datasette: datasette::Datasette::new(),
+            typed_keys: Vec::<u8>::new(),
+            autostart: None,
             boot_complete: false,
+            frame_finished: false,
             cycle_count: 0,
+            tod_cycles: 0,
+            resample_frac: 0,
+            rewind: rewind::RewindBuffer::new(rewind_states as usize),
+            rewind_frame_cnt: 0,
+            recording: None,
+            last_input: None,
+            playback: None,
+            playback_idx: 0,
         };
 
-        c64.main_window.set_position(75, 20);
+        let (speed, warp) = (c64.config.speed, c64.config.warp);
+        c64.clock.set_speed(speed);
+        c64.clock.set_warp(warp);
+
+        if c64.config.true_drive {
+            let drive_model = c64.config.drive_model;
+            match c1541::Drive::new(drive_model, drive_model.rom_file(), c64.config.model.clock_freq()) {
+                Ok(drive) => c64.drive = Some(drive),
+                Err(e) => println!("Can't start {} emulation, using the virtual drive: {}", drive_model.name(), e),
+            }
+        }
+
+        // the cartridge is plugged in before power-on, so its lines are seen by the reset routine
+        if let Some(path) = c64.config.crt_file.clone() {
+            if let Err(e) = c64.attach_cartridge(&path) {
+                println!("Can't attach {}: {}", path, e);
+            }
+        }
+
+        if let Some(path) = c64.config.drive8.clone() {
+            if let Err(e) = c64.attach_disk(&path) {
+                println!("Can't attach {}: {}", path, e);
+            }
+        }
+
+        if let Some(path) = c64.config.t64_file.clone().or(c64.config.tap_file.clone()) {
+            if let Err(e) = c64.attach_tape(&path) {
+                println!("Can't attach {}: {}", path, e);
+            }
+        }
+
+        if let Some(path) = c64.config.tap_record_file.clone() {
+            c64.create_tape(&path);
+        }
 
         // cyclic dependencies are not possible in Rust (yet?), so we have
         // to resort to setting references manually
@@ -80,6 +305,7 @@ impl C64 {
         c64.cia2.borrow_mut().set_references(memory.clone(), cpu.clone(), vic.clone());
         c64.vic.borrow_mut().set_references(memory.clone(), cpu.clone());
         c64.sid.borrow_mut().set_references(memory.clone());
+        c64.sid.borrow_mut().set_seed(c64.config.seed);
         c64.cpu.borrow_mut().set_references(memory.clone(), vic.clone(), cia1.clone(), cia2.clone(), sid.clone());
 
         drop(memory);
@@ -99,104 +325,1081 @@ impl C64 {
         self.cia1.borrow_mut().reset();
         self.cia2.borrow_mut().reset();
         self.sid.borrow_mut().reset();
+        self.typed_keys.clear();
+
+        if let Some(ref mut drive) = self.drive {
+            drive.reset();
+        }
     }
 
 
-    pub fn run(&mut self) {
-        // attempt to load a program supplied with command line
-        if !self.powered_on {
-            // $FCE2 is the power-on reset routine, which searches for and starts
-            // a cartridge amongst other things. The cartridge must be loaded here
-            self.powered_on = self.cpu.borrow_mut().pc == 0xFCE2;
-            if self.powered_on {
-                let crt_file = &self.crt_to_load.to_owned()[..];
-                if crt_file.len() > 0 {
-                    let crt = crt::Crt::from_filename(crt_file).unwrap();
-                    println!("{:?}", crt);
-                    crt.load_into_memory(self.memory.borrow_mut());
-                }
+    // run a single frame as fast as possible, exchange frames, audio and input with the frontend
+    // on VBlank, then sleep until the frame is due in real time
+    pub fn run(&mut self, frontend: &mut dyn Frontend) {
+        let start_cycle = self.cycle_count;
+
+        loop {
+            let vblank = self.step_cycle();
+
+            // let the frontend trace the cycle (VIC raster debugger)
+            frontend.on_cycle(&mut self.vic);
+
+            if vblank {
+                break;
             }
         }
 
+        // the frontend update may rewind the machine, so count the cycles first
+        let frame_cycles = self.cycle_count.wrapping_sub(start_cycle);
+        self.update_frontend(frontend);
+        self.clock.sync(frame_cycles as u32);
+    }
+
+
+    // advance the machine by exactly one cycle, regardless of real time - returns true on VBlank
+    pub fn step_cycle(&mut self) -> bool {
+        if self.playback.is_some() {
+            self.play_movie_input();
+        }
+
+        // samples of a finished frame stay available until the next frame starts
+        if self.frame_finished {
+            self.sid.borrow_mut().clear_samples();
+            self.frame_finished = false;
+        }
+
+        // attempt to load a program supplied with command line
         if !self.boot_complete {
             // $A480 is the BASIC warm start sequence - safe to assume we can load a cmdline program now
             self.boot_complete = self.cpu.borrow_mut().pc == 0xA480;
 
             if self.boot_complete {
-                let prg_file = &self.file_to_load.to_owned()[..];
+                let mut start_address = None;
+
+                if let Some(prg_file) = self.config.prg_file.clone() {
+                    match self.load_prg(&prg_file) {
+                        Ok(address) => start_address = Some(address),
+                        Err(e) => println!("Can't load {}: {}", prg_file, e),
+                    }
+                }
+
+                if let Some(t64_file) = self.config.t64_file.clone() {
+                    match self.load_t64(&t64_file, 0) {
+                        Ok(address) => start_address = Some(address),
+                        Err(e) => println!("Can't load {}: {}", t64_file, e),
+                    }
+                }
 
-                if prg_file.len() > 0 {
-                    self.boot_complete = true; self.load_prg(prg_file);
+                if self.config.autostart {
+                    let command = self.autostart_command(start_address);
+                    self.autostart = command.map(|command| (self.config.autostart_delay, command));
                 }
             }
         }
 
-        // main C64 update - use the clock to time all the operations
-        if self.clock.tick() {
-            let mut should_trigger_vblank = false;
+        self.check_kernal_traps();
+
+        let mut should_trigger_vblank = false;
+
+        if self.vic.borrow_mut().update(self.cycle_count as u32, &mut should_trigger_vblank) {
+            self.sid.borrow_mut().update(self.config.model.cycles_per_line() as u32);
+        }
+
+        self.cia1.borrow_mut().process_irq();
+        self.cia2.borrow_mut().process_irq();
+        self.cia1.borrow_mut().update();
+        self.cia2.borrow_mut().update();
+
+        // the datasette pulses CIA1 FLAG or records the write line while the motor runs, PLAY is sensed on the 6510 port
+        let (motor_on, write_line) = {
+            let memory = self.memory.borrow();
+            (memory.cassette_motor, memory.cassette_write)
+        };
+        if self.datasette.update(motor_on, write_line) {
+            self.cia1.borrow_mut().trigger_flag();
+        }
+        self.memory.borrow_mut().cassette_sense = self.datasette.is_playing();
+
+        if let Some(ref mut drive) = self.drive {
+            let lines = drive.update(self.cia2.borrow().iec_outputs());
+            self.cia2.borrow_mut().set_iec_inputs(lines);
+        }
+
+        self.cpu.borrow_mut().update(self.cycle_count as u32);
 
-            if self.vic.borrow_mut().update(self.cycle_count, &mut should_trigger_vblank) {
-                self.sid.borrow_mut().update();
+        // TOD clocks are driven by the mains frequency, not by the video timing
+        self.tod_cycles += self.config.model.tod_freq();
+        if self.tod_cycles >= self.config.model.clock_freq() {
+            self.tod_cycles -= self.config.model.clock_freq();
+            self.cia1.borrow_mut().count_tod();
+            self.cia2.borrow_mut().count_tod();
+        }
+
+        if should_trigger_vblank {
+            self.frame_finished = true;
+            self.update_typed_keys();
+
+            if let Some(cartridge) = self.memory.borrow_mut().cartridge_mut() {
+                cartridge.update();
             }
+        }
 
-            self.cia1.borrow_mut().process_irq();
-            self.cia2.borrow_mut().process_irq();
-            self.cia1.borrow_mut().update();
-            self.cia2.borrow_mut().update();
+        self.cycle_count += 1;
+        should_trigger_vblank
+    }
 
-            self.cpu.borrow_mut().update(self.cycle_count);
 
-            // update the debugger window if it exists
-            match self.debugger {
-                Some(ref mut dbg) => {
-                    dbg.update_vic_window(&mut self.vic);
-                    if should_trigger_vblank {
-                        dbg.render(&mut self.cpu, &mut self.memory);
-                    }
-                },
-                None => (),
+    // advance the machine until the CPU has completed one full instruction (or interrupt sequence)
+    // returns true if VBlank occured in the meantime
+    pub fn step_instruction(&mut self) -> bool {
+        let mut vblank = false;
+        let mut op_started = false;
+
+        loop {
+            vblank |= self.step_cycle();
+
+            match self.cpu.borrow_mut().state {
+                cpu::CPUState::FetchOp => if op_started { break; },
+                _ => op_started = true,
             }
+        }
+
+        vblank
+    }
+
+
+    // advance the machine until the VIC triggers the next VBlank
+    pub fn run_frame(&mut self) {
+        while !self.step_cycle() {}
+    }
+
+
+    // load a *.prg file or a program in a PC64 container (*.p00) into memory - returns the start address
+    pub fn load_prg(&mut self, filename: &str) -> Result<u16, String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut prg_data = Vec::<u8>::new();
+        file.read_to_end(&mut prg_data).map_err(|e| e.to_string())?;
+
+        if p00::has_signature(&prg_data) {
+            prg_data = p00::P00::from_bytes(&prg_data, d64::FileType::Prg)?.data;
+        }
+
+        self.inject_prg(filename, &prg_data)
+    }
+
 
-            // redraw the screen and process input on VBlank
-            if should_trigger_vblank {
-                let _ = self.main_window.update_with_buffer(&self.vic.borrow_mut().window_buffer);
-                self.io.update(&self.main_window, &mut self.cia1);
-                self.cia1.borrow_mut().count_tod();
-                self.cia2.borrow_mut().count_tod();
+    // load a file from a *.t64 archive into memory (entries are counted from 0) - returns the start address
+    pub fn load_t64(&mut self, filename: &str, entry_index: usize) -> Result<u16, String> {
+        let archive = t64::T64::from_filename(filename)?;
+        let entry = archive.entries().get(entry_index).ok_or("No such entry in archive".to_string())?;
+        let prg_data = archive.prg_data(entry)?;
 
-                if self.io.check_restore_key(&self.main_window) {
-                    self.cpu.borrow_mut().set_nmi(true);
+        self.inject_prg(&format!("{} {}", filename, entry), &prg_data)
+    }
+
+
+    // serve LOAD from device 1 (tape) with the files of a *.t64 archive or insert a *.tap image into the datasette
+    pub fn attach_tape(&mut self, path: &str) -> Result<(), String> {
+        if path.to_lowercase().ends_with(".tap") {
+            self.datasette.attach(path)?;
+            self.tape_archive = None;
+        }
+        else {
+            self.tape_archive = Some(t64::T64::from_filename(path)?);
+            self.datasette.detach();
+        }
+
+        Ok(())
+    }
+
+
+    // insert a blank tape into the datasette - SAVE to tape creates a *.tap file
+    pub fn create_tape(&mut self, path: &str) {
+        self.datasette.create(path);
+        self.tape_archive = None;
+    }
+
+
+    pub fn detach_tape(&mut self) {
+        self.tape_archive = None;
+        self.datasette.detach();
+    }
+
+
+    // datasette buttons - the KERNAL waits for PLAY after "PRESS PLAY ON TAPE" (or RECORD & PLAY for SAVE)
+    // the recording is written to the tape's file whenever the motor stops
+    pub fn tape_play(&mut self) {
+        self.datasette.play();
+    }
+
+
+    pub fn tape_record(&mut self) {
+        self.datasette.record();
+    }
+
+
+    pub fn tape_stop(&mut self) {
+        self.datasette.stop();
+    }
+
+
+    pub fn tape_rewind(&mut self) {
+        self.datasette.rewind();
+    }
+
+
+    pub fn is_tape_playing(&self) -> bool {
+        self.datasette.is_playing()
+    }
+
+
+    // plug in a *.crt cartridge image - it is started by the next reset
+    pub fn attach_cartridge(&mut self, path: &str) -> Result<(), String> {
+        let crt = crt::Crt::from_filename(path)?;
+        println!("{:?}", crt);
+        self.memory.borrow_mut().attach_cartridge(crt::Cartridge::new(crt, path));
+        Ok(())
+    }
+
+
+    // press the freeze button - a freezer cartridge switches to Ultimax mode and pulls NMI to enter its menu,
+    // returns false if there's none
+    pub fn freeze(&mut self) -> bool {
+        if !self.memory.borrow_mut().freeze_cartridge() {
+            return false
+        }
+
+        self.cpu.borrow_mut().set_nmi(true);
+        true
+    }
+
+
+    // insert a disk image into drive 8 - a *.g64 image requires the true drive, which takes the images its model can
+    // read (1571: *.d71 as well, 1581: *.d81 only), the virtual drive takes any image and host directories
+    pub fn attach_disk(&mut self, path: &str) -> Result<(), String> {
+        match self.drive {
+            Some(ref mut drive) => drive.attach(path),
+            None => self.vdrive.attach(path),
+        }
+    }
+
+
+    pub fn detach_disk(&mut self) {
+        match self.drive {
+            Some(ref mut drive) => drive.detach(),
+            None => self.vdrive.detach(),
+        }
+    }
+
+
+    // capture the complete machine state - ROMs, the frame buffer and host input are not included
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.write_bytes(snapshot::SIGNATURE);
+        w.write_u8(snapshot::VERSION);
+        w.write_u8(self.config.model as u8);
+
+        w.write_bool(self.boot_complete);
+        w.write_bool(self.frame_finished);
+        w.write_u64(self.cycle_count);
+        w.write_u32(self.tod_cycles);
+
+        self.memory.borrow().save_state(&mut w);
+        self.cpu.borrow().save_state(&mut w);
+        self.cia1.borrow().save_state(&mut w);
+        self.cia2.borrow().save_state(&mut w);
+        self.vic.borrow().save_state(&mut w);
+        self.sid.borrow().save_state(&mut w);
+
+        w.write_bool(self.drive.is_some());
+        if let Some(ref drive) = self.drive {
+            drive.save_state(&mut w);
+        }
+
+        self.datasette.save_state(&mut w);
+        w.into_data()
+    }
+
+
+    // restore a state captured with save_state - the machine model has to match
+    // the machine is left untouched if the state can't be restored
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+
+        let result = self.restore_state(data);
+        if result.is_err() {
+            self.restore_state(&backup).unwrap();
+        }
+
+        result
+    }
+
+
+    // step back to the most recent state in the rewind buffer and redraw the screen
+    // returns false if there is nothing left to rewind to
+    pub fn rewind(&mut self) -> bool {
+        match self.rewind.pop() {
+            Some(state) => {
+                if let Err(e) = self.load_state(&state) {
+                    println!("Can't rewind: {}", e);
+                    return false;
                 }
+
+                for _ in 0..REWIND_REDRAW_FRAMES {
+                    self.run_frame();
+                }
+
+                self.rewind_frame_cnt = 0;
+                true
+            },
+            None => false,
+        }
+    }
+
+
+    // start recording all input fed into CIA1 by the frontend - the movie starts at the current state
+    pub fn start_recording(&mut self) {
+        self.recording  = Some(movie::Movie::new(self.save_state()));
+        self.last_input = Some(self.input_state(false));
+    }
+
+
+    pub fn stop_recording(&mut self) -> Option<movie::Movie> {
+        self.last_input = None;
+        self.recording.take()
+    }
+
+
+    // restore the movie's start state and replay its input - frontend input is ignored until it ends
+    pub fn start_playback(&mut self, movie: movie::Movie) -> Result<(), String> {
+        self.load_state(&movie.start_state)?;
+        self.playback = Some(movie);
+        self.playback_idx = 0;
+        Ok(())
+    }
+
+
+    pub fn is_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+
+    pub fn save_snapshot(&self, filename: &str) -> Result<(), String> {
+        let mut file = File::create(filename).map_err(|e| e.to_string())?;
+        file.write_all(&self.save_state()).map_err(|e| e.to_string())
+    }
+
+
+    pub fn load_snapshot(&mut self, filename: &str) -> Result<(), String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        self.load_state(&data)
+    }
+
+
+    // read memory as currently seen by the CPU - I/O registers return the last written value
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.memory.borrow_mut().peek(addr)
+    }
+
+
+    // write to memory (RAM under ROM if ROM is banked in), bypassing I/O registers
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.memory.borrow_mut().write_byte(addr, value);
+    }
+
+
+    // type PETSCII text ("\r" for RETURN) through the KERNAL keyboard buffer - it's fed in chunks the buffer can hold
+    // whenever the previous ones have been read
+    pub fn type_keys(&mut self, keys: &[u8]) {
+        self.typed_keys.extend_from_slice(keys);
+    }
+
+
+    pub fn registers(&self) -> cpu::Registers {
+        self.cpu.borrow().registers()
+    }
+
+
+    // the last frame rendered by the VIC (SCREEN_WIDTH x SCREEN_HEIGHT, 0RGB)
+    pub fn frame_buffer<'a>(&'a self) -> Ref<'a, [u32]> {
+        Ref::map(self.vic.borrow(), |vic| &vic.window_buffer[..])
+    }
+
+
+    // audio samples (sid::SAMPLE_FREQ Hz, mono) generated during the current frame
+    pub fn audio_samples<'a>(&'a self) -> Ref<'a, [i16]> {
+        Ref::map(self.sid.borrow(), |sid| sid.samples())
+    }
+
+
+    // emulation speed in percent of real time (ignored in warp mode)
+    pub fn set_speed(&mut self, speed: u32) {
+        self.clock.set_speed(speed);
+    }
+
+
+    pub fn speed(&self) -> u32 {
+        self.clock.speed()
+    }
+
+
+    // run as fast as the host allows - audio is muted while warping
+    pub fn set_warp(&mut self, warp: bool) {
+        self.clock.set_warp(warp);
+    }
+
+
+    pub fn warp(&self) -> bool {
+        self.clock.warp()
+    }
+
+
+    // number of cycles emulated since the machine was created
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+
+    // *** private functions *** //
+
+    // redraw the screen, output audio and process input on VBlank
+    fn update_frontend(&mut self, frontend: &mut dyn Frontend) {
+        frontend.on_frame(&mut self.cpu, &mut self.memory);
+
+        // step back while the rewind key is held, otherwise capture a state every few frames
+        // (not while a movie is running - it would get out of sync)
+        let rewinding = !self.movie_active() && frontend.is_key_down(Key::PageUp) && self.rewind();
+
+        if !rewinding {
+            self.rewind_frame_cnt += 1;
+            if self.rewind_frame_cnt >= REWIND_INTERVAL {
+                self.rewind_frame_cnt = 0;
+                let state = self.save_state();
+                self.rewind.push(state);
             }
+        }
+
+        frontend.draw_frame(&self.vic.borrow_mut().window_buffer);
+
+        if !rewinding {
+            self.queue_audio(frontend);
+        }
+
+        // during movie playback the input comes from the movie instead
+        if self.playback.is_none() {
+            self.io.update(frontend, &mut self.cia1);
 
-            // process special keys: console ASM output and reset switch
-            if self.main_window.is_key_pressed(Key::F11, KeyRepeat::No) {
-                let di = self.cpu.borrow_mut().debug_instr;
-                self.cpu.borrow_mut().debug_instr = !di;
+            let restore = self.io.check_restore_key(frontend);
+            if restore {
+                self.cpu.borrow_mut().set_nmi(true);
             }
 
-            if self.main_window.is_key_pressed(Key::F12, KeyRepeat::No) {
+            if self.recording.is_some() {
+                self.record_input(restore);
+            }
+        }
+
+        // process special keys: warp mode, snapshots, datasette, console ASM output and reset switch
+        // (the rewind key is handled above) - keys which act on the machine but aren't part of a movie
+        // are blocked while one is running, replaying it would get out of sync
+        let movie_active = self.movie_active();
+        if movie_active && [Key::F9, Key::PageDown, Key::F12].iter().any(|&key| frontend.is_key_pressed(key)) {
+            println!("Not possible while a movie is running");
+        }
+
+        if frontend.is_key_pressed(Key::F9) && !movie_active {
+            let warp = self.clock.warp();
+            self.clock.set_warp(!warp);
+        }
+
+        // F10 saves a snapshot, Shift+F10 restores it
+        if frontend.is_key_pressed(Key::F10) {
+            let filename = self.config.snapshot_file.clone().unwrap_or(DEFAULT_SNAPSHOT_FILE.to_string());
+            let shift = frontend.is_key_down(Key::LeftShift) || frontend.is_key_down(Key::RightShift);
+            let result = if !shift { self.save_snapshot(&filename) }
+                         else if movie_active { Err("not possible while a movie is running".to_string()) }
+                         else { self.load_snapshot(&filename) };
+
+            match result {
+                Ok(_)  => println!("Snapshot {} {}", if shift { "restored from" } else { "saved to" }, filename),
+                Err(e) => println!("Snapshot {} failed: {}", filename, e),
+            }
+        }
+
+        // PgDn presses PLAY or STOP on the datasette, Alt+PgDn RECORD & PLAY and Shift+PgDn rewinds the tape
+        if frontend.is_key_pressed(Key::PageDown) && self.datasette.is_attached() && !movie_active {
+            let shift = frontend.is_key_down(Key::LeftShift) || frontend.is_key_down(Key::RightShift);
+            let alt = frontend.is_key_down(Key::LeftAlt) || frontend.is_key_down(Key::RightAlt);
+            if shift {
+                self.datasette.rewind();
+                println!("Tape rewound");
+            }
+            else if alt && !self.datasette.is_playing() {
+                self.datasette.record();
+                println!("Tape recording");
+            }
+            else if self.datasette.is_playing() {
+                self.datasette.stop();
+                println!("Tape stopped");
+            }
+            else {
+                self.datasette.play();
+                println!("Tape playing");
+            }
+        }
+
+        if frontend.is_key_pressed(Key::F11) {
+            let di = self.cpu.borrow_mut().debug_instr;
+            self.cpu.borrow_mut().debug_instr = !di;
+        }
+
+        // F12 resets, Shift+F12 presses the freeze button of a freezer cartridge
+        if frontend.is_key_pressed(Key::F12) && !movie_active {
+            let shift = frontend.is_key_down(Key::LeftShift) || frontend.is_key_down(Key::RightShift);
+            if !shift {
                 self.reset();
             }
+            else if !self.freeze() {
+                println!("No freezer cartridge attached");
+            }
+        }
+    }
+
+
+    // output the samples of the last frame at real time rate - in warp mode audio is muted,
+    // at other speeds samples are dropped or repeated, so pitch follows the emulation speed
+    fn queue_audio(&mut self, frontend: &mut dyn Frontend) {
+        if self.clock.warp() {
+            return;
+        }
+
+        let speed = self.clock.speed();
+        let sid = self.sid.borrow();
+        let samples = sid.samples();
+
+        if speed == 100 {
+            frontend.queue_audio(samples);
+            return;
+        }
 
-            self.cycle_count += 1;
+        let mut resampled = Vec::<i16>::with_capacity(samples.len() * 100 / speed as usize + 1);
+
+        while (self.resample_frac as usize) < samples.len() * 100 {
+            resampled.push(samples[self.resample_frac as usize / 100]);
+            self.resample_frac += speed;
         }
 
-        // update SDL2 audio buffers
-        self.sid.borrow_mut().update_audio();
+        self.resample_frac -= (samples.len() * 100) as u32;
+        frontend.queue_audio(&resampled);
     }
 
 
-    // *** private functions *** //
+    // read all components from a snapshot in the order they were written by save_state
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = SnapshotReader::new(data);
+
+        let mut signature = [0u8; 16];
+        r.read_bytes(&mut signature)?;
+        if &signature != snapshot::SIGNATURE {
+            return Err("Invalid snapshot signature".to_string())
+        }
+
+        if r.read_u8()? != snapshot::VERSION {
+            return Err("Unsupported snapshot version".to_string())
+        }
+
+        let model = Model::from_u8(r.read_u8()?).ok_or("Invalid machine model".to_string())?;
+        if model != self.config.model {
+            return Err(format!("Snapshot is for a different machine model ({:?})", model))
+        }
+
+        self.boot_complete  = r.read_bool()?;
+        self.frame_finished = r.read_bool()?;
+        self.cycle_count = r.read_u64()?;
+        self.tod_cycles  = r.read_u32()?;
+
+        self.memory.borrow_mut().load_state(&mut r)?;
+        self.cpu.borrow_mut().load_state(&mut r)?;
+        self.cia1.borrow_mut().load_state(&mut r)?;
+        self.cia2.borrow_mut().load_state(&mut r)?;
+        self.vic.borrow_mut().load_state(&mut r)?;
+        self.sid.borrow_mut().load_state(&mut r)?;
+
+        if r.read_bool()? != self.drive.is_some() {
+            return Err("Snapshot is for a different drive setup".to_string())
+        }
+
+        if let Some(ref mut drive) = self.drive {
+            drive.load_state(&mut r)?;
+        }
+
+        self.datasette.load_state(&mut r)?;
+
+        if !r.is_finished() {
+            return Err("Trailing data in snapshot".to_string())
+        }
+
+        Ok(())
+    }
+
+
+    // command typed to start what was loaded or attached on the command line - a tape is started with PLAY as well
+    fn autostart_command(&mut self, start_address: Option<u16>) -> Option<Vec<u8>> {
+        match start_address {
+            Some(BASIC_START) => {
+                match self.sys_stub_address() {
+                    Some(address) => Some(format!("SYS{}\r", address).into_bytes()),
+                    None => Some(b"RUN\r".to_vec()),
+                }
+            },
+            Some(address) => Some(format!("SYS{}\r", address).into_bytes()),
+            None if self.datasette.is_attached() => {
+                self.datasette.play();
+                Some(b"LOAD\rRUN\r".to_vec())
+            },
+            None if self.config.drive8.is_some() => Some(b"LOAD\"*\",8,1\rRUN\r".to_vec()),
+            None => None,
+        }
+    }
+
+
+    // entry point of a machine code program started by a BASIC line like "10 SYS2061"
+    fn sys_stub_address(&self) -> Option<u16> {
+        let mut addr = BASIC_START + 4; // behind the link to the next line and the line number
+        while self.peek(addr) == b' ' {
+            addr += 1;
+        }
+
+        if self.peek(addr) != TOKEN_SYS {
+            return None
+        }
+
+        addr += 1;
+        while self.peek(addr) == b' ' || self.peek(addr) == b'(' {
+            addr += 1;
+        }
+
+        let mut entry: u32 = 0;
+        let mut num_digits = 0;
+        while self.peek(addr) >= b'0' && self.peek(addr) <= b'9' && num_digits < 5 {
+            entry = entry * 10 + (self.peek(addr) - b'0') as u32;
+            addr += 1;
+            num_digits += 1;
+        }
+
+        if num_digits > 0 && entry <= 0xFFFF { Some(entry as u16) } else { None }
+    }
+
+
+    // once per frame: count down to the autostart command and move typed keys into the keyboard buffer
+    // once the KERNAL has emptied it
+    fn update_typed_keys(&mut self) {
+        if let Some((frames, command)) = self.autostart.take() {
+            if frames == 0 {
+                self.type_keys(&command);
+            }
+            else {
+                self.autostart = Some((frames - 1, command));
+            }
+        }
+
+        if self.typed_keys.is_empty() || self.peek(KEYBOARD_BUFFER_LEN) != 0 {
+            return;
+        }
+
+        let max_len = (self.peek(KEYBOARD_BUFFER_SIZE) as usize).max(1).min(10).min(self.typed_keys.len());
+        let keys: Vec<u8> = self.typed_keys.drain(..max_len).collect();
+        for (i, &key) in keys.iter().enumerate() {
+            self.poke(KEYBOARD_BUFFER + i as u16, key);
+        }
+
+        self.poke(KEYBOARD_BUFFER_LEN, keys.len() as u8);
+    }
+
+
+    // copy a program preceded by its load address into memory - returns the start address
+    fn inject_prg(&mut self, name: &str, prg_data: &[u8]) -> Result<u16, String> {
+        if prg_data.len() < 2 {
+            return Err("File too short".to_string())
+        }
 
-    // load a *.prg file
-    fn load_prg(&mut self, filename: &str) {
-        let prg_data = utils::open_file(filename, 0);
         let start_address: u16 = ((prg_data[1] as u16) << 8) | (prg_data[0] as u16);
-        println!("Loading {} to start location at ${:04x} ({})", filename, start_address, start_address);
+        println!("Loading {} to start location at ${:04x} ({})", name, start_address, start_address);
 
         for i in 2..(prg_data.len()) {
-            self.memory.borrow_mut().write_byte(start_address + (i as u16) - 2, prg_data[i]);
+            self.memory.borrow_mut().write_byte(start_address.wrapping_add((i as u16) - 2), prg_data[i]);
+        }
+
+        // programs loaded to the start of BASIC get the pointers LOAD sets up - machine code elsewhere is left alone
+        let basic_start = (self.peek(TXTTAB) as u16) | ((self.peek(TXTTAB + 1) as u16) << 8);
+        if start_address == basic_start {
+            let end_address = start_address.wrapping_add((prg_data.len() - 2) as u16);
+            self.relink_basic(basic_start, end_address);
+        }
+
+        Ok(start_address)
+    }
+
+
+    // what BASIC does after a LOAD in direct mode: the variables start behind the program, the line links are
+    // recalculated and the variables are cleared
+    fn relink_basic(&mut self, basic_start: u16, end_address: u16) {
+        let mut line = basic_start;
+
+        // each line ends with a 0 byte, the program with a link of $0000 - links are only followed within the program
+        while self.peek(line.wrapping_add(1)) != 0 && line.wrapping_add(4) < end_address {
+            let mut next = line.wrapping_add(4);
+            while self.peek(next) != 0 && next < end_address {
+                next = next.wrapping_add(1);
+            }
+
+            next = next.wrapping_add(1);
+            self.poke(line, next as u8);
+            self.poke(line.wrapping_add(1), (next >> 8) as u8);
+            line = next;
+        }
+
+        let memory_top = (self.peek(MEMSIZ) as u16) | ((self.peek(MEMSIZ + 1) as u16) << 8);
+        for &(pointer, value) in [(VARTAB, end_address), (ARYTAB, end_address), (STREND, end_address), (FRETOP, memory_top)].iter() {
+            self.poke(pointer, value as u8);
+            self.poke(pointer + 1, (value >> 8) as u8);
+        }
+    }
+
+
+    // handle KERNAL LOAD/SAVE on device 1 (T64 archive) and 8 (virtual drive) when the CPU is about to execute the routine
+    fn check_kernal_traps(&mut self) {
+        let (pc, a) = {
+            let cpu = self.cpu.borrow();
+            match cpu.state {
+                cpu::CPUState::FetchOp => (cpu.pc, cpu.a),
+                _ => return,
+            }
+        };
+
+        if (pc != KERNAL_LOAD && pc != KERNAL_SAVE) || !self.memory.borrow().kernal_on {
+            return;
         }
+
+        // tape files can only be loaded
+        let device = self.peek(0xBA);
+        let served = match device {
+            1 => pc == KERNAL_LOAD && self.tape_archive.is_some(),
+            8 => self.drive.is_none() && self.vdrive.is_attached(),
+            _ => false,
+        };
+
+        if !served {
+            return;
+        }
+
+        // file name, secondary address, start and end addresses are set up by SETNAM, SETLFS and LOAD/SAVE
+        let name_addr = (self.peek(0xBB) as u16) | ((self.peek(0xBC) as u16) << 8);
+        let name: Vec<u8> = (0..(self.peek(0xB7) as u16)).map(|i| self.peek(name_addr.wrapping_add(i))).collect();
+
+        // without a name, tape LOAD takes the first file
+        let next_pc = if name.is_empty() && device == 8 {
+            KERNAL_ERR_NO_NAME
+        }
+        else if pc == KERNAL_LOAD {
+            let data = if device == 1 { self.tape_file(&name) } else { self.vdrive.load(&name) };
+            self.trap_load(device, data, a != 0)
+        }
+        else {
+            self.trap_save(&name)
+        };
+
+        self.cpu.borrow_mut().pc = next_pc;
+    }
+
+
+    // contents of the first file of the tape archive matching the name, in PRG format
+    fn tape_file(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self.tape_archive {
+            Some(ref archive) => {
+                match archive.find_entry(name) {
+                    Some(entry) => Ok(Some(archive.prg_data(entry)?)),
+                    None => Ok(None),
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+
+    // LOAD (or VERIFY) a file read from a trapped device - returns the KERNAL address to continue at
+    fn trap_load(&mut self, device: u8, file: Result<Option<Vec<u8>>, String>, verify: bool) -> u16 {
+        self.poke(0x93, verify as u8);
+
+        let data = match file {
+            Ok(Some(data)) => if data.len() >= 2 { data } else { return KERNAL_ERR_NOT_FOUND },
+            Ok(None) => return KERNAL_ERR_NOT_FOUND,
+            Err(e) => {
+                println!("Device {}: {}", device, e);
+                return KERNAL_ERR_NOT_FOUND;
+            }
+        };
+
+        // secondary address 0 loads to the address passed to LOAD instead of the file's own
+        let start_address = if self.peek(0xB9) == 0 {
+            (self.peek(0xC3) as u16) | ((self.peek(0xC4) as u16) << 8)
+        }
+        else {
+            (data[0] as u16) | ((data[1] as u16) << 8)
+        };
+
+        let mut status = 0x40; // EOI
+        let mut addr = start_address;
+
+        for &value in data[2..].iter() {
+            if !verify {
+                self.poke(addr, value);
+            }
+            else if self.peek(addr) != value {
+                status |= 0x10;
+            }
+
+            addr = addr.wrapping_add(1);
+        }
+
+        self.poke(0x90, status);
+        self.poke(0xAE, addr as u8);
+        self.poke(0xAF, (addr >> 8) as u8);
+        KERNAL_LOAD_OK
+    }
+
+
+    // SAVE the memory from $C1/$C2 up to $AE/$AF to the virtual drive
+    fn trap_save(&mut self, name: &[u8]) -> u16 {
+        let start_address = (self.peek(0xC1) as u16) | ((self.peek(0xC2) as u16) << 8);
+        let end_address = (self.peek(0xAE) as u16) | ((self.peek(0xAF) as u16) << 8);
+
+        let mut data = vec![start_address as u8, (start_address >> 8) as u8];
+        let mut addr = start_address;
+        while addr != end_address {
+            data.push(self.peek(addr));
+            addr = addr.wrapping_add(1);
+        }
+
+        // like on a real drive, errors are only reported by the drive - not by the KERNAL
+        if let Err(e) = self.vdrive.save(name, &data) {
+            println!("Drive 8: {}", e);
+        }
+
+        self.poke(0x90, 0);
+        KERNAL_SAVE_OK
+    }
+
+
+    fn movie_active(&self) -> bool {
+        self.recording.is_some() || self.playback.is_some()
+    }
+
+
+    // the input currently seen by CIA1
+    fn input_state(&self, restore: bool) -> movie::InputState {
+        let cia1 = self.cia1.borrow();
+
+        movie::InputState {
+            key_matrix: cia1.key_matrix,
+            rev_matrix: cia1.rev_matrix,
+            joystick_1: cia1.joystick_1,
+            joystick_2: cia1.joystick_2,
+            restore: restore,
+        }
+    }
+
+
+    // add an event to the recorded movie if the input has changed since the last one
+    fn record_input(&mut self, restore: bool) {
+        let input = self.input_state(restore);
+
+        if self.last_input != Some(input) {
+            if let Some(ref mut movie) = self.recording {
+                movie.events.push(movie::MovieEvent {
+                    cycle: self.cycle_count,
+                    input: input,
+                });
+            }
+
+            self.last_input = Some(input);
+        }
+    }
+
+
+    // apply all movie events stamped with the current cycle, stop once the movie has ended
+    fn play_movie_input(&mut self) {
+        let mut inputs = Vec::<movie::InputState>::new();
+        let mut finished = false;
+
+        if let Some(ref movie) = self.playback {
+            while self.playback_idx < movie.events.len() && movie.events[self.playback_idx].cycle == self.cycle_count {
+                inputs.push(movie.events[self.playback_idx].input);
+                self.playback_idx += 1;
+            }
+
+            finished = self.playback_idx >= movie.events.len();
+        }
+
+        for input in inputs {
+            {
+                let mut cia1 = self.cia1.borrow_mut();
+                cia1.key_matrix = input.key_matrix;
+                cia1.rev_matrix = input.rev_matrix;
+                cia1.joystick_1 = input.joystick_1;
+                cia1.joystick_2 = input.joystick_2;
+            }
+
+            if input.restore {
+                self.cpu.borrow_mut().set_nmi(true);
+            }
+        }
+
+        if finished {
+            println!("Movie playback finished");
+            self.playback = None;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use c64::frontend::Headless;
+
+    // types the keys one after another, each is held for 2 frames and released for 2 frames
+    struct ScriptedFrontend {
+        frame: usize,
+        keys: Vec<Key>,
+    }
+
+    impl Frontend for ScriptedFrontend {
+        fn is_open(&self) -> bool { true }
+        fn draw_frame(&mut self, _buffer: &[u32]) { self.frame += 1; }
+        fn queue_audio(&mut self, _samples: &[i16]) {}
+        fn is_key_down(&self, key: Key) -> bool { self.frame % 4 < 2 && self.keys.get(self.frame / 4) == Some(&key) }
+        fn is_key_pressed(&self, _key: Key) -> bool { false }
+    }
+
+
+    // a machine which has run the first frames of the KERNAL reset routine
+    fn machine(model: Model) -> C64 {
+        let mut config = Config::default();
+        config.model = model;
+
+        let mut c64 = C64::new(config);
+        c64.reset();
+        for _ in 0..10 {
+            c64.run_frame();
+        }
+
+        c64
+    }
+
+
+    #[test]
+    fn snapshot_restores_the_machine() {
+        let mut c64 = machine(Model::Pal);
+        let state = c64.save_state();
+        for _ in 0..5 {
+            c64.run_frame();
+        }
+
+        // another machine restored from the snapshot continues exactly the same way
+        let mut restored = machine(Model::Pal);
+        restored.load_state(&state).unwrap();
+        assert!(restored.save_state() == state);
+        for _ in 0..5 {
+            restored.run_frame();
+        }
+
+        assert!(restored.save_state() == c64.save_state());
+        assert_eq!(restored.cycle_count(), c64.cycle_count());
+        assert!(restored.frame_buffer()[..] == c64.frame_buffer()[..]);
+    }
+
+
+    #[test]
+    fn invalid_snapshots() {
+        let mut c64 = machine(Model::Pal);
+        let mut state = c64.save_state();
+
+        assert!(c64.load_state(&state[..1000]).is_err());
+        assert_eq!(machine(Model::Ntsc).load_state(&state), Err("Snapshot is for a different machine model (Pal)".to_string()));
+
+        state[16] = snapshot::VERSION + 1;
+        assert_eq!(c64.load_state(&state), Err("Unsupported snapshot version".to_string()));
+    }
+
+
+    #[test]
+    fn movie_playback_repeats_the_recording() {
+        let mut c64 = machine(Model::Pal);
+        while !c64.boot_complete {
+            c64.run_frame();
+        }
+
+        c64.set_warp(true);
+        c64.start_recording();
+
+        let mut frontend = ScriptedFrontend {
+            frame: 0,
+            keys: vec![Key::A, Key::B, Key::Enter],
+        };
+        for _ in 0..16 {
+            c64.run(&mut frontend);
+        }
+
+        // each key is pressed and released
+        let movie = c64.stop_recording().unwrap();
+        assert_eq!(movie.events.len(), 6);
+
+        // the movie starts with its own state, the frontend is ignored until it ends
+        let mut replay = C64::new(Config::default());
+        replay.set_warp(true);
+        replay.start_playback(movie::Movie::from_bytes(&movie.to_bytes()).unwrap()).unwrap();
+        while replay.is_playing() {
+            replay.run(&mut Headless);
+        }
+        while replay.cycle_count() < c64.cycle_count() {
+            replay.step_cycle();
+        }
+
+        // "AB" has been typed and BASIC has answered with ?SYNTAX ERROR
+        let screen = |c64: &C64| (0x0400..0x07E8).map(|addr| c64.peek(addr)).collect::<Vec<u8>>();
+        assert!(screen(&c64).windows(3).any(|chars| chars == [1, 2, 32]));
+        assert!(screen(&replay) == screen(&c64));
+        assert!(replay.save_state() == c64.save_state());
+    }
+
+
+    #[test]
+    fn basic_program_is_relinked() {
+        let mut c64 = C64::new(Config::default());
+        let word = |c64: &C64, addr: u16| (c64.peek(addr) as u16) | ((c64.peek(addr + 1) as u16) << 8);
+        c64.poke(TXTTAB, 0x01);
+        c64.poke(TXTTAB + 1, 0x08);
+        c64.poke(MEMSIZ, 0x00);
+        c64.poke(MEMSIZ + 1, 0xA0);
+
+        // 10 PRINT : 20 END with wrong line links
+        let prg = [0x01, 0x08, 0x34, 0x12, 10, 0, 0x99, 0, 0x34, 0x12, 20, 0, 0x80, 0, 0, 0];
+        assert_eq!(c64.inject_prg("TEST", &prg).unwrap(), 0x0801);
+        assert_eq!(word(&c64, 0x0801), 0x0807);
+        assert_eq!(word(&c64, 0x0807), 0x080D);
+        assert_eq!(word(&c64, 0x080D), 0x0000);
+
+        for &pointer in [VARTAB, ARYTAB, STREND].iter() {
+            assert_eq!(word(&c64, pointer), 0x080F);
+        }
+        assert_eq!(word(&c64, FRETOP), 0xA000);
+
+        // machine code loaded elsewhere doesn't touch the BASIC pointers
+        c64.inject_prg("CODE", &[0x00, 0xC0, 0x60]).unwrap();
+        assert_eq!(word(&c64, VARTAB), 0x080F);
+        assert_eq!(c64.peek(0xC000), 0x60);
     }
 }
@@ -0,0 +1,161 @@
+// input movies - keyboard/joystick changes stamped with the cycle they were applied in,
+// replayed on top of the machine state captured when the recording started
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use std::fs::File;
+use std::io::{Read, Write};
+
+const SIGNATURE: &'static [u8; 16] = b"RUST64 MOVIE\0\0\0\0";
+const VERSION: u8 = 2;
+
+
+// everything the host feeds into CIA1 (and the NMI line for RESTORE)
+#[derive(Clone, Copy, PartialEq)]
+pub struct InputState {
+    pub key_matrix: [u8; 8],
+    pub rev_matrix: [u8; 8],
+    pub joystick_1: u8,
+    pub joystick_2: u8,
+    pub restore: bool,
+}
+
+
+pub struct MovieEvent {
+    pub cycle: u64, // C64 cycle count at which the input is applied
+    pub input: InputState,
+}
+
+
+pub struct Movie {
+    pub start_state: Vec<u8>, // C64::save_state at the start of the recording
+    pub events: Vec<MovieEvent>,
+}
+
+impl Movie {
+    pub fn new(start_state: Vec<u8>) -> Movie {
+        Movie {
+            start_state: start_state,
+            events: Vec::<MovieEvent>::new(),
+        }
+    }
+
+
+    pub fn from_filename(filename: &str) -> Result<Movie, String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        Movie::from_bytes(&data)
+    }
+
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, String> {
+        let mut r = SnapshotReader::new(data);
+        let mut signature = [0u8; 16];
+        r.read_bytes(&mut signature)?;
+        if &signature != SIGNATURE {
+            return Err("Invalid movie signature".to_string())
+        }
+
+        if r.read_u8()? != VERSION {
+            return Err("Unsupported movie version".to_string())
+        }
+
+        // check the stored size before allocating, it may be garbage
+        let state_size = r.read_u32()? as usize;
+        if state_size > r.remaining() {
+            return Err("Invalid movie state size".to_string())
+        }
+
+        let mut movie = Movie::new(vec![0; state_size]);
+        r.read_bytes(&mut movie.start_state)?;
+
+        let num_events = r.read_u32()?;
+        for _ in 0..num_events {
+            let cycle = r.read_u64()?;
+            let mut input = InputState {
+                key_matrix: [0xFF; 8],
+                rev_matrix: [0xFF; 8],
+                joystick_1: 0xFF,
+                joystick_2: 0xFF,
+                restore: false,
+            };
+
+            r.read_bytes(&mut input.key_matrix)?;
+            r.read_bytes(&mut input.rev_matrix)?;
+            input.joystick_1 = r.read_u8()?;
+            input.joystick_2 = r.read_u8()?;
+            input.restore = r.read_bool()?;
+
+            movie.events.push(MovieEvent {
+                cycle: cycle,
+                input: input,
+            });
+        }
+
+        Ok(movie)
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.write_bytes(SIGNATURE);
+        w.write_u8(VERSION);
+        w.write_u32(self.start_state.len() as u32);
+        w.write_bytes(&self.start_state);
+        w.write_u32(self.events.len() as u32);
+
+        for event in self.events.iter() {
+            w.write_u64(event.cycle);
+            w.write_bytes(&event.input.key_matrix);
+            w.write_bytes(&event.input.rev_matrix);
+            w.write_u8(event.input.joystick_1);
+            w.write_u8(event.input.joystick_2);
+            w.write_bool(event.input.restore);
+        }
+
+        w.into_data()
+    }
+
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let mut file = File::create(filename).map_err(|e| e.to_string())?;
+        file.write_all(&self.to_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut movie = Movie::new(vec![1, 2, 3]);
+        movie.events.push(MovieEvent {
+            cycle: 1000,
+            input: InputState {
+                key_matrix: [0xFE; 8],
+                rev_matrix: [0x7F; 8],
+                joystick_1: 0xEF,
+                joystick_2: 0xFF,
+                restore: true,
+            },
+        });
+
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded.start_state, vec![1, 2, 3]);
+        assert_eq!(loaded.events.len(), 1);
+        assert_eq!(loaded.events[0].cycle, 1000);
+        assert!(loaded.events[0].input == movie.events[0].input);
+    }
+
+
+    #[test]
+    fn state_size_beyond_end_of_file() {
+        let mut data = Movie::new(vec![0; 100]).to_bytes();
+        data[17..21].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(Movie::from_bytes(&data).err(), Some("Invalid movie state size".to_string()));
+
+        data.truncate(50);
+        assert!(Movie::from_bytes(&data).is_err());
+    }
+}
@@ -12,6 +12,8 @@
 // rel = $0000                // relative to PC/IP
 
 use c64::cpu;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use num::FromPrimitive;
 use std::fmt;
 
 pub enum AddrMode {
@@ -30,48 +32,51 @@ pub enum AddrMode {
     IndirectIndexedY(bool)
 }
 
-pub enum Op {
-    // Load/store
-    LDA, LDX, LDY,
-    STA, STX, STY,
-    // Register transfers
-    TAX, TAY, TXA,
-    TYA,
-    // Stack operations
-    TSX, TXS, PHA,
-    PHP, PLA, PLP,
-    // Logical
-    AND, EOR, ORA,
-    BIT,
-    // Arithmetic
-    ADC, SBC, CMP,
-    CPX, CPY,
-    // Inc/Dec
-    INC, INX, INY,
-    DEC, DEX, DEY,
-    // Shifts
-    ASL, LSR, ROL,
-    ROR,
-    // Jump calls
-    JMP, JSR, RTS,
-    // Branches
-    BCC, BCS, BEQ,
-    BMI, BNE, BPL,
-    BVC, BVS,
-    // Status flag changes
-    CLC, CLD, CLI,
-    CLV, SEC, SED,
-    SEI,
-    // System functions
-    BRK, NOP, RTI,
-    // forbidden/undocumented
-    HLT, SLO, ANC,
-    RLA, SRE, RRA,
-    ALR, SAX, XAA,
-    AHX, TAS, SHY,
-    SHX, ARR, LAX,
-    LAS, DCP, AXS,
-    ISC
+enum_from_primitive! {
+    #[derive(Clone, Copy)]
+    pub enum Op {
+        // Load/store
+        LDA, LDX, LDY,
+        STA, STX, STY,
+        // Register transfers
+        TAX, TAY, TXA,
+        TYA,
+        // Stack operations
+        TSX, TXS, PHA,
+        PHP, PLA, PLP,
+        // Logical
+        AND, EOR, ORA,
+        BIT,
+        // Arithmetic
+        ADC, SBC, CMP,
+        CPX, CPY,
+        // Inc/Dec
+        INC, INX, INY,
+        DEC, DEX, DEY,
+        // Shifts
+        ASL, LSR, ROL,
+        ROR,
+        // Jump calls
+        JMP, JSR, RTS,
+        // Branches
+        BCC, BCS, BEQ,
+        BMI, BNE, BPL,
+        BVC, BVS,
+        // Status flag changes
+        CLC, CLD, CLI,
+        CLV, SEC, SED,
+        SEI,
+        // System functions
+        BRK, NOP, RTI,
+        // forbidden/undocumented
+        HLT, SLO, ANC,
+        RLA, SRE, RRA,
+        ALR, SAX, XAA,
+        AHX, TAS, SHY,
+        SHX, ARR, LAX,
+        LAS, DCP, AXS,
+        ISC
+    }
 }
 
 pub struct Instruction {
@@ -104,6 +109,72 @@ impl Instruction {
     }
 
 
+    // the instruction may be saved mid-execution, so all of the progress is stored as well
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        let (mode, page_cross) = match self.addr_mode {
+            AddrMode::Implied     => (0, false),
+            AddrMode::Accumulator => (1, false),
+            AddrMode::Immediate   => (2, false),
+            AddrMode::Absolute    => (3, false),
+            AddrMode::AbsoluteIndexedX(pc) => (4, pc),
+            AddrMode::AbsoluteIndexedY(pc) => (5, pc),
+            AddrMode::Zeropage         => (6, false),
+            AddrMode::ZeropageIndexedX => (7, false),
+            AddrMode::ZeropageIndexedY => (8, false),
+            AddrMode::Relative         => (9, false),
+            AddrMode::Indirect         => (10, false),
+            AddrMode::IndexedIndirectX => (11, false),
+            AddrMode::IndirectIndexedY(pc) => (12, pc),
+        };
+
+        w.write_u8(mode);
+        w.write_bool(page_cross);
+        w.write_u8(self.opcode as u8);
+        w.write_u16(self.operand_addr);
+        w.write_u16(self.index_addr);
+        w.write_u8(self.cycles_to_fetch);
+        w.write_u8(self.cycles_to_run);
+        w.write_u8(self.cycles_to_rmw);
+        w.write_bool(self.is_rmw);
+        w.write_u8(self.rmw_buffer);
+        w.write_bool(self.zp_crossed);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let mode = r.read_u8()?;
+        let page_cross = r.read_bool()?;
+
+        self.addr_mode = match mode {
+            0  => AddrMode::Implied,
+            1  => AddrMode::Accumulator,
+            2  => AddrMode::Immediate,
+            3  => AddrMode::Absolute,
+            4  => AddrMode::AbsoluteIndexedX(page_cross),
+            5  => AddrMode::AbsoluteIndexedY(page_cross),
+            6  => AddrMode::Zeropage,
+            7  => AddrMode::ZeropageIndexedX,
+            8  => AddrMode::ZeropageIndexedY,
+            9  => AddrMode::Relative,
+            10 => AddrMode::Indirect,
+            11 => AddrMode::IndexedIndirectX,
+            12 => AddrMode::IndirectIndexedY(page_cross),
+            _  => return Err("Invalid addressing mode".to_string()),
+        };
+
+        self.opcode = Op::from_u8(r.read_u8()?).ok_or("Invalid opcode".to_string())?;
+        self.operand_addr    = r.read_u16()?;
+        self.index_addr      = r.read_u16()?;
+        self.cycles_to_fetch = r.read_u8()?;
+        self.cycles_to_run   = r.read_u8()?;
+        self.cycles_to_rmw   = r.read_u8()?;
+        self.is_rmw     = r.read_bool()?;
+        self.rmw_buffer = r.read_u8()?;
+        self.zp_crossed = r.read_bool()?;
+        Ok(())
+    }
+
+
     // takes into account all factors (rmw, fetch cycles, etc) to calculate the number of execution cycles
     pub fn calculate_cycles(&mut self, total_cycles: u8, is_rmw: bool) {
         match self.addr_mode {
@@ -759,7 +830,7 @@ pub fn run(cpu: &mut cpu::CPU) -> bool {
                     }
                 },
                 2 => {
-                    cpu.first_nmi_cycle += 1; // delay NMI
+                    cpu.first_nmi_cycle = cpu.first_nmi_cycle.wrapping_add(1); // delay NMI
                 },
                 1  => {
                     //println!("Received BRK instruction at ${:04X}", cpu.pc-1);
@@ -0,0 +1,113 @@
+// PC64 containers (*.p00, *.s00, *.u00, *.r00) - a single C64 file behind a header with its original PETSCII name,
+// the file type is given by the first letter of the extension
+use c64::d64;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const SIGNATURE: &'static [u8; 8] = b"C64File\0";
+pub const HEADER_SIZE: usize = 0x1A;
+
+
+pub struct P00 {
+    pub file_type: d64::FileType,
+    pub name: [u8; 16],  // PETSCII, padded with $A0
+    pub record_len: u8,  // REL files only
+    pub data: Vec<u8>,   // contents of the file - PRG files include the load address
+}
+
+impl P00 {
+    pub fn from_filename(filename: &str) -> Result<P00, String> {
+        let file_type = file_type(Path::new(filename)).ok_or("Not a PC64 container".to_string())?;
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        P00::from_bytes(&data, file_type)
+    }
+
+
+    pub fn from_bytes(data: &[u8], file_type: d64::FileType) -> Result<P00, String> {
+        if !has_signature(data) {
+            return Err("Invalid P00 signature".to_string())
+        }
+
+        Ok(P00 {
+            file_type: file_type,
+            name: header_name(data),
+            record_len: data[0x19],
+            data: data[HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+
+// the type of the contained file if the extension is one of the PC64 ones ("p00" to "p99" etc.)
+pub fn file_type(path: &Path) -> Option<d64::FileType> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase().into_bytes();
+    if ext.len() != 3 || !ext[1..].iter().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+
+    match ext[0] {
+        b'd' => Some(d64::FileType::Del),
+        b's' => Some(d64::FileType::Seq),
+        b'p' => Some(d64::FileType::Prg),
+        b'u' => Some(d64::FileType::Usr),
+        b'r' => Some(d64::FileType::Rel),
+        _ => None,
+    }
+}
+
+
+pub fn has_signature(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && &data[0..8] == SIGNATURE
+}
+
+
+// the original name stored in a container header - the name is padded with 0 bytes
+pub fn header_name(data: &[u8]) -> [u8; 16] {
+    let mut name = [0xA0; 16];
+    for (i, &c) in data[8..24].iter().take_while(|&&c| c != 0).enumerate() {
+        name[i] = c;
+    }
+
+    name
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(name: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut data = SIGNATURE.to_vec();
+        let mut header_name = [0; 17];
+        header_name[..name.len()].copy_from_slice(name);
+        data.extend_from_slice(&header_name);
+        data.push(0);
+        data.extend_from_slice(contents);
+        data
+    }
+
+
+    #[test]
+    fn unpack() {
+        let data = container(b"GAME", &[0x01, 0x08, 0xAA]);
+        assert_eq!(data.len(), HEADER_SIZE + 3);
+
+        let p00 = P00::from_bytes(&data, d64::FileType::Prg).unwrap();
+        assert_eq!(&p00.name[..5], b"GAME\xA0");
+        assert_eq!(p00.data, vec![0x01, 0x08, 0xAA]);
+        assert!(P00::from_bytes(&data[1..], d64::FileType::Prg).is_err());
+    }
+
+
+    #[test]
+    fn file_types() {
+        assert_eq!(file_type(Path::new("game.p00")), Some(d64::FileType::Prg));
+        assert_eq!(file_type(Path::new("DATA.S12")), Some(d64::FileType::Seq));
+        assert_eq!(file_type(Path::new("rel.r99")), Some(d64::FileType::Rel));
+        assert_eq!(file_type(Path::new("game.prg")), None);
+        assert_eq!(file_type(Path::new("game.p0")), None);
+    }
+}
@@ -0,0 +1,166 @@
+// rewind buffer - a ring of machine states captured with C64::save_state
+// only the newest state is kept in full, each older state is stored as the difference
+// to the next newer one (XOR, zero runs compressed), so consecutive states take little memory
+use std::collections::VecDeque;
+
+
+pub struct RewindBuffer {
+    latest: Option<Vec<u8>>, // newest captured state
+    deltas: VecDeque<Vec<u8>>, // older states, oldest first
+    capacity: usize,         // maximum number of states held
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            latest: None,
+            deltas: VecDeque::<Vec<u8>>::new(),
+            capacity: capacity,
+        }
+    }
+
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == state.len() {
+                self.deltas.push_back(encode_delta(&latest, &state));
+            }
+            else {
+                self.deltas.clear();
+            }
+        }
+
+        self.latest = Some(state);
+
+        // the oldest state is never referenced by another one, so it can simply be dropped
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+
+    // remove and return the newest state - the one before it becomes the newest
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.latest.take();
+
+        if let Some(ref state) = state {
+            self.latest = self.deltas.pop_back().map(|delta| decode_delta(state, &delta));
+        }
+
+        state
+    }
+
+
+    pub fn len(&self) -> usize {
+        if self.latest.is_some() { self.deltas.len() + 1 } else { 0 }
+    }
+}
+
+
+// *** private functions *** //
+
+// XOR both states and compress the result: a control byte n < 0x80 is followed by n+1 literal bytes,
+// n >= 0x80 stands for (n & 0x7F)+1 zero bytes
+fn encode_delta(state: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::<u8>::new();
+    let mut i = 0;
+
+    while i < state.len() {
+        let start = i;
+
+        if state[i] == newer[i] {
+            while i < state.len() && (i - start) < 0x80 && state[i] == newer[i] {
+                i += 1;
+            }
+
+            delta.push(0x80 | (i - start - 1) as u8);
+        }
+        else {
+            while i < state.len() && (i - start) < 0x80 && state[i] != newer[i] {
+                i += 1;
+            }
+
+            delta.push((i - start - 1) as u8);
+            for j in start..i {
+                delta.push(state[j] ^ newer[j]);
+            }
+        }
+    }
+
+    delta
+}
+
+
+// restore the older state from the newer one and their difference
+fn decode_delta(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = newer.to_vec();
+    let mut pos = 0;
+    let mut i = 0;
+
+    while i < delta.len() {
+        let count = ((delta[i] & 0x7F) as usize) + 1;
+
+        if (delta[i] & 0x80) != 0 {
+            i += 1;
+        }
+        else {
+            for j in 0..count {
+                state[pos + j] ^= delta[i + 1 + j];
+            }
+
+            i += count + 1;
+        }
+
+        pos += count;
+    }
+
+    state
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(seed: u8) -> Vec<u8> {
+        (0..1000).map(|i| if i % 97 == 0 { seed.wrapping_mul(i as u8) } else { i as u8 }).collect()
+    }
+
+
+    #[test]
+    fn delta_restores_older_state() {
+        let older = state(3);
+        let newer = state(5);
+        let delta = encode_delta(&older, &newer);
+        assert!(delta.len() < older.len() / 4);
+        assert_eq!(decode_delta(&newer, &delta), older);
+    }
+
+
+    #[test]
+    fn delta_of_unrelated_states() {
+        let older: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let newer: Vec<u8> = (0..300).map(|i| !(i as u8)).collect();
+        assert_eq!(decode_delta(&newer, &encode_delta(&older, &newer)), older);
+        assert_eq!(decode_delta(&newer, &encode_delta(&newer, &newer)), newer);
+    }
+
+
+    #[test]
+    fn states_are_popped_newest_first() {
+        let mut buffer = RewindBuffer::new(3);
+        for seed in 1..6 {
+            buffer.push(state(seed));
+        }
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop(), Some(state(5)));
+        assert_eq!(buffer.pop(), Some(state(4)));
+        assert_eq!(buffer.pop(), Some(state(3)));
+        assert_eq!(buffer.pop(), None);
+    }
+}
@@ -1,53 +1,57 @@
 // SID chip
-extern crate rand;
-#[cfg(not(target_os = "redox"))]
-extern crate sdl2;
-
-#[cfg(not(target_os = "redox"))]
-use self::sdl2::audio::{ AudioCallback, AudioSpecDesired };
 use c64::memory;
 use c64::sid_tables::*;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use num::FromPrimitive;
 use std::cell::RefCell;
 use std::f32;
 use std::rc::Rc;
 
 pub type SIDShared = Rc<RefCell<SID>>;
 
-const SAMPLE_FREQ: u32 = 44100;  // output frequency
-const SID_FREQ:    u32 = 985248; // SID frequency in Hz
-pub const SID_CYCLES:  u32 = SID_FREQ / SAMPLE_FREQ;  // SID clocks/sample frame
-const NUM_SAMPLES: usize = 624; // size of buffer for sampled voice
-
-
-enum WaveForm {
-    None,
-    Triangle,
-    Saw,
-    TriSaw,
-    Pulse,
-    TriPulse,
-    SawPulse,
-    TriSawPulse,
-    Noise
+pub const SAMPLE_FREQ: u32 = 44100;  // output frequency
+
+// xorshift128 start state, the seed is mixed into the first word
+const RNG_INIT: [u32; 4] = [0x193A6754, 0xA8A7D469, 0x97830E05, 0x113BA7BB];
+
+
+enum_from_primitive! {
+    #[derive(Clone, Copy)]
+    enum WaveForm {
+        None,
+        Triangle,
+        Saw,
+        TriSaw,
+        Pulse,
+        TriPulse,
+        SawPulse,
+        TriSawPulse,
+        Noise
+    }
 }
 
-enum VoiceState {
-    Idle,
-    Attack,
-    Decay,
-    Release
+enum_from_primitive! {
+    #[derive(Clone, Copy)]
+    enum VoiceState {
+        Idle,
+        Attack,
+        Decay,
+        Release
+    }
 }
 
-#[derive(PartialEq)]
-enum FilterType {
-    None,
-    Lowpass,
-    Bandpass,
-    LowBandpass,
-    Highpass,
-    Notch,
-    HighBandpass,
-    All
+enum_from_primitive! {
+    #[derive(Clone, Copy, PartialEq)]
+    enum FilterType {
+        None,
+        Lowpass,
+        Bandpass,
+        LowBandpass,
+        Highpass,
+        Notch,
+        HighBandpass,
+        All
+    }
 }
 
 
@@ -76,7 +80,7 @@ struct SIDVoice {
 }
 
 impl SIDVoice {
-    fn new() -> SIDVoice {
+    fn new(eg_rate: u32) -> SIDVoice {
         SIDVoice {
             wave: WaveForm::None,
             state: VoiceState::Idle,
@@ -86,9 +90,9 @@ impl SIDVoice {
             wf_add: 0,
             freq: 0,
             pw_val: 0,
-            attack_add: EG_TABLE[0],
-            decay_sub: EG_TABLE[0],
-            release_sub: EG_TABLE[0],
+            attack_add: eg_rate,
+            decay_sub: eg_rate,
+            release_sub: eg_rate,
             sustain_level: 0,
             level: 0,
             noise: 0,
@@ -102,16 +106,62 @@ impl SIDVoice {
     }
 
 
-    fn reset(&mut self) {
+    // modulator/modulatee are fixed connections and not part of the state
+    fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.wave as u8);
+        w.write_u8(self.state as u8);
+        w.write_u32(self.wf_cnt);
+        w.write_u32(self.wf_add);
+        w.write_u16(self.freq);
+        w.write_u16(self.pw_val);
+        w.write_u32(self.attack_add);
+        w.write_u32(self.decay_sub);
+        w.write_u32(self.release_sub);
+        w.write_u32(self.sustain_level);
+        w.write_u32(self.level);
+        w.write_u32(self.noise);
+        w.write_bool(self.gate);
+        w.write_bool(self.ring);
+        w.write_bool(self.test);
+        w.write_bool(self.filter);
+        w.write_bool(self.sync);
+        w.write_bool(self.mute);
+    }
+
+
+    fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.wave  = WaveForm::from_u8(r.read_u8()?).ok_or("Invalid SID waveform".to_string())?;
+        self.state = VoiceState::from_u8(r.read_u8()?).ok_or("Invalid SID voice state".to_string())?;
+        self.wf_cnt = r.read_u32()?;
+        self.wf_add = r.read_u32()?;
+        self.freq   = r.read_u16()?;
+        self.pw_val = r.read_u16()?;
+        self.attack_add    = r.read_u32()?;
+        self.decay_sub     = r.read_u32()?;
+        self.release_sub   = r.read_u32()?;
+        self.sustain_level = r.read_u32()?;
+        self.level  = r.read_u32()?;
+        self.noise  = r.read_u32()?;
+        self.gate   = r.read_bool()?;
+        self.ring   = r.read_bool()?;
+        self.test   = r.read_bool()?;
+        self.filter = r.read_bool()?;
+        self.sync   = r.read_bool()?;
+        self.mute   = r.read_bool()?;
+        Ok(())
+    }
+
+
+    fn reset(&mut self, eg_rate: u32) {
         self.wave  = WaveForm::None;
         self.state = VoiceState::Idle;
         self.wf_cnt = 0;
         self.wf_add = 0;
         self.freq   = 0;
         self.pw_val = 0;
-        self.attack_add  = EG_TABLE[0];
-        self.decay_sub   = EG_TABLE[0];
-        self.release_sub = EG_TABLE[0];
+        self.attack_add  = eg_rate;
+        self.decay_sub   = eg_rate;
+        self.release_sub = eg_rate;
         self.sustain_level = 0;
         self.level = 0;
         self.noise = 0;
@@ -125,31 +175,23 @@ impl SIDVoice {
 }
 
 
-// the SID chip with associated SDL2 audio device
+// the SID chip - samples are generated as the emulation runs and collected by the frontend
 pub struct SID {
     mem_ref: Option<memory::MemShared>,
-    #[cfg(not(target_os = "redox"))]
-    audio_device: sdl2::audio::AudioDevice<SIDAudioDevice>,
+    audio_device: SIDAudioDevice,
+    sid_freq: u32,         // SID frequency in Hz (system clock)
+    sample_frac: u32,      // fractional part of the next sample (in SID cycles * SAMPLE_FREQ)
+    samples: Vec<i16>,     // samples generated since the last frame
 }
 
-#[cfg(not(target_os = "redox"))]
 impl SID {
-    pub fn new_shared() -> SIDShared {
-        let sdl_context = sdl2::init().unwrap();
-        let audio_subsystem = sdl_context.audio().unwrap();
-
-        let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
-            channels: Some(1),  // mono
-            samples: Some(512), // default sample size
-        };
-
+    pub fn new_shared(sid_freq: u32) -> SIDShared {
         Rc::new(RefCell::new(SID {
             mem_ref: None,
-            audio_device: audio_subsystem.open_playback(None, &desired_spec, |spec| {
-                println!("{:?}", spec);
-                SIDAudioDevice::new()
-                }).unwrap()
+            audio_device: SIDAudioDevice::new(sid_freq),
+            sid_freq: sid_freq,
+            sample_frac: 0,
+            samples: Vec::<i16>::new(),
         }))
     }
 
@@ -159,15 +201,43 @@ impl SID {
     }
 
 
+    // seed the random generator used for noise and the OSC3/ENV3 registers - applied on reset
+    pub fn set_seed(&mut self, seed: u32) {
+        self.audio_device.seed = seed;
+        self.audio_device.reset_rng();
+    }
+
+
     pub fn reset(&mut self) {
-        let mut lock = self.audio_device.lock();
-        (*lock).reset();
+        self.audio_device.reset();
+        self.sample_frac = 0;
+        self.samples.clear();
     }
 
 
-    pub fn update(&mut self) {
-        let mut lock = self.audio_device.lock();
-        (*lock).update();
+    // samples already generated for the current frame are not part of the state
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u32(self.sample_frac);
+        self.audio_device.save_state(w);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.sample_frac = r.read_u32()?;
+        self.samples.clear();
+        self.audio_device.load_state(r)
+    }
+
+
+    // generate all samples that are due after the given number of SID cycles
+    pub fn update(&mut self, cycles: u32) {
+        self.sample_frac += cycles * SAMPLE_FREQ;
+        let num_samples = (self.sample_frac / self.sid_freq) as usize;
+        self.sample_frac %= self.sid_freq;
+
+        let start = self.samples.len();
+        self.samples.resize(start + num_samples, 0);
+        self.audio_device.generate_samples(&mut self.samples[start..]);
     }
 
 
@@ -176,13 +246,11 @@ impl SID {
 
         match addr {
             0xD419...0xD41A => {
-                let mut lock = self.audio_device.lock();
-                rval = (*lock).read_register(addr);
+                rval = self.audio_device.read_register(addr);
                 as_ref!(self.mem_ref).get_ram_bank(memory::MemType::Io).write(addr, rval);
             },
             0xD41B...0xD41C => {
-                let mut lock = self.audio_device.lock();
-                rval = (*lock).read_register(addr);
+                rval = self.audio_device.read_register(addr);
                 as_ref!(self.mem_ref).get_ram_bank(memory::MemType::Io).write(addr, rval);
             },
             0xD420...0xD7FF =>  { rval = self.read_register(0xD400 + (addr % 0x0020)); },
@@ -196,54 +264,31 @@ impl SID {
 
 
     pub fn write_register(&mut self, addr: u16, value: u8) {
-        let mut lock = self.audio_device.lock();
-        (*lock).write_register(addr, value);
+        self.audio_device.write_register(addr, value);
         as_ref!(self.mem_ref).get_ram_bank(memory::MemType::Io).write(addr, value);
     }
 
 
-    pub fn update_audio(&mut self) {
-        self.audio_device.resume();
+    // samples generated since the last call to clear_samples()
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
     }
-}
 
 
-#[cfg(target_os = "redox")]
-impl SID {
-    pub fn new_shared() -> SIDShared {
-        Rc::new(RefCell::new(SID {
-            mem_ref: None,
-        }))
-    }
-
-
-    pub fn set_references(&mut self, memref: memory::MemShared) {
-        self.mem_ref = Some(memref);
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
     }
-
-
-    pub fn reset(&mut self) {}
-
-
-    pub fn update(&mut self) {}
-
-
-    pub fn read_register(&mut self, addr: u16) -> u8 {
-        0
-    }
-
-
-    pub fn write_register(&mut self, addr: u16, value: u8) {}
-
-
-    pub fn update_audio(&mut self) {}
 }
 
 
-// SDL2 audio device along with necessary SID parameters
+// SID sound generator along with necessary SID parameters
 // this is where the actual SID calculations are being performed
 struct SIDAudioDevice {
     last_sid_byte: u8,  // last byte read by the SID
+    sid_cycles: u32,    // SID clocks/sample frame
+    eg_table: [u32; 16], // envelope generator rates for the SID clock
+    seed: u32,          // random generator seed
+    rng_state: [u32; 4], // xorshift128 state
     volume: u8,
     filter_type: FilterType,
     filter_freq: u8,
@@ -261,15 +306,24 @@ struct SIDAudioDevice {
     yn2: f32,
 
     voices: Vec<SIDVoice>,
-    sample_buffer: [u8; NUM_SAMPLES],
-    sample_idx: usize
 }
 
 impl SIDAudioDevice {
-    pub fn new() -> SIDAudioDevice {
+    pub fn new(sid_freq: u32) -> SIDAudioDevice {
+        let sid_cycles = sid_freq / SAMPLE_FREQ;
+        let mut eg_table = [0; 16];
+
+        for i in 0..eg_table.len() {
+            eg_table[i] = (sid_cycles << 16) / EG_DIVISORS[i];
+        }
+
         let mut sid_audio_device = SIDAudioDevice {
             last_sid_byte: 0,
-            voices: vec![SIDVoice::new(), SIDVoice::new(), SIDVoice::new()],
+            sid_cycles: sid_cycles,
+            eg_table: eg_table,
+            seed: 0,
+            rng_state: RNG_INIT,
+            voices: vec![SIDVoice::new(eg_table[0]), SIDVoice::new(eg_table[0]), SIDVoice::new(eg_table[0])],
             volume: 0,
             filter_type: FilterType::None,
             filter_freq: 0,
@@ -283,8 +337,6 @@ impl SIDAudioDevice {
             xn2: 0.0,
             yn1: 0.0,
             yn2: 0.0,
-            sample_buffer: [0; NUM_SAMPLES],
-            sample_idx: 0
         };
 
         // calculate triangle table values
@@ -311,7 +363,7 @@ impl SIDAudioDevice {
         self.last_sid_byte = 0;
 
         for i in 0..self.voices.len() {
-            self.voices[i].reset();
+            self.voices[i].reset(self.eg_table[0]);
         }
 
         self.volume = 0;
@@ -327,19 +379,73 @@ impl SIDAudioDevice {
         self.xn2 = 0.0;
         self.yn1 = 0.0;
         self.yn2 = 0.0;
-        self.sample_idx = 0;
         self.calculate_filter();
+        self.reset_rng();
+    }
+
 
-        for i in 0..NUM_SAMPLES {
-            self.sample_buffer[i] = 0;
+    pub fn reset_rng(&mut self) {
+        self.rng_state = RNG_INIT;
+        self.rng_state[0] ^= self.seed;
+
+        // the state must never be all zeros
+        if self.rng_state[0] == 0 {
+            self.rng_state[0] = RNG_INIT[0];
         }
     }
 
 
-    pub fn update(&mut self) {
-        let idx = self.sample_idx;
-        self.sample_buffer[idx] = self.volume;
-        self.sample_idx = (self.sample_idx + 1) % NUM_SAMPLES;
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.last_sid_byte);
+        w.write_u8(self.volume);
+        w.write_u8(self.filter_type as u8);
+        w.write_u8(self.filter_freq);
+        w.write_u8(self.filter_resonance);
+        w.write_f32(self.iir_att);
+        w.write_f32(self.d1);
+        w.write_f32(self.d2);
+        w.write_f32(self.g1);
+        w.write_f32(self.g2);
+        w.write_f32(self.xn1);
+        w.write_f32(self.xn2);
+        w.write_f32(self.yn1);
+        w.write_f32(self.yn2);
+
+        for v in self.rng_state.iter() {
+            w.write_u32(*v);
+        }
+
+        for voice in self.voices.iter() {
+            voice.save_state(w);
+        }
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.last_sid_byte = r.read_u8()?;
+        self.volume = r.read_u8()?;
+        self.filter_type = FilterType::from_u8(r.read_u8()?).ok_or("Invalid SID filter type".to_string())?;
+        self.filter_freq = r.read_u8()?;
+        self.filter_resonance = r.read_u8()?;
+        self.iir_att = r.read_f32()?;
+        self.d1  = r.read_f32()?;
+        self.d2  = r.read_f32()?;
+        self.g1  = r.read_f32()?;
+        self.g2  = r.read_f32()?;
+        self.xn1 = r.read_f32()?;
+        self.xn2 = r.read_f32()?;
+        self.yn1 = r.read_f32()?;
+        self.yn2 = r.read_f32()?;
+
+        for v in self.rng_state.iter_mut() {
+            *v = r.read_u32()?;
+        }
+
+        for voice in self.voices.iter_mut() {
+            voice.load_state(r)?;
+        }
+
+        Ok(())
     }
 
 
@@ -354,7 +460,7 @@ impl SIDAudioDevice {
             },
             0xD41B...0xD41C => {
                 self.last_sid_byte = 0;
-                let rval = rand::random::<u8>();
+                let rval = self.random() as u8;
                 rval
             },
             0xD420...0xD7FF => self.read_register(0xD400 + (addr % 0x0020)),
@@ -372,11 +478,11 @@ impl SIDAudioDevice {
         match addr {
             0xD400 => {
                 self.voices[0].freq = (self.voices[0].freq & 0xFF00) | value as u16;
-                self.voices[0].wf_add = SID_CYCLES * self.voices[0].freq as u32;
+                self.voices[0].wf_add = self.sid_cycles * self.voices[0].freq as u32;
             },
             0xD401 => {
                 self.voices[0].freq = (self.voices[0].freq & 0x00FF) | ((value as u16) << 8);
-                self.voices[0].wf_add = SID_CYCLES * self.voices[0].freq as u32;
+                self.voices[0].wf_add = self.sid_cycles * self.voices[0].freq as u32;
             },
             0xD402 => {
                 self.voices[0].pw_val = (self.voices[0].pw_val & 0x0F00) | value as u16;
@@ -388,20 +494,20 @@ impl SIDAudioDevice {
                 self.set_control_register(0, value);
             },
             0xD405 => {
-                self.voices[0].attack_add = EG_TABLE[ (value >> 4) as usize ];
-                self.voices[0].decay_sub  = EG_TABLE[ (value & 0x0F) as usize ];
+                self.voices[0].attack_add = self.eg_table[ (value >> 4) as usize ];
+                self.voices[0].decay_sub  = self.eg_table[ (value & 0x0F) as usize ];
             },
             0xD406 => {
                 self.voices[0].sustain_level = 0x111111 * (value >> 4) as u32;
-                self.voices[0].release_sub   = EG_TABLE[ (value & 0x0F) as usize ];
+                self.voices[0].release_sub   = self.eg_table[ (value & 0x0F) as usize ];
             },
             0xD407 => {
                 self.voices[1].freq = (self.voices[1].freq & 0xFF00) | value as u16;
-                self.voices[1].wf_add = SID_CYCLES * self.voices[1].freq as u32;
+                self.voices[1].wf_add = self.sid_cycles * self.voices[1].freq as u32;
             },
             0xD408 => {
                 self.voices[1].freq = (self.voices[1].freq & 0x00FF) | ((value as u16) << 8);
-                self.voices[1].wf_add = SID_CYCLES * self.voices[1].freq as u32;
+                self.voices[1].wf_add = self.sid_cycles * self.voices[1].freq as u32;
             },
             0xD409 => {
                 self.voices[1].pw_val = (self.voices[1].pw_val & 0x0F00) | value as u16;
@@ -413,20 +519,20 @@ impl SIDAudioDevice {
                 self.set_control_register(1, value);
             },
             0xD40C => {
-                self.voices[1].attack_add = EG_TABLE[ (value >> 4) as usize ];
-                self.voices[1].decay_sub  = EG_TABLE[ (value & 0x0F) as usize ];
+                self.voices[1].attack_add = self.eg_table[ (value >> 4) as usize ];
+                self.voices[1].decay_sub  = self.eg_table[ (value & 0x0F) as usize ];
             },
             0xD40D => {
                 self.voices[1].sustain_level = 0x111111 * (value >> 4) as u32;
-                self.voices[1].release_sub   = EG_TABLE[ (value & 0x0F) as usize ];
+                self.voices[1].release_sub   = self.eg_table[ (value & 0x0F) as usize ];
             },
             0xD40E => {
                 self.voices[2].freq = (self.voices[2].freq & 0xFF00) | value as u16;
-                self.voices[2].wf_add = SID_CYCLES * self.voices[2].freq as u32;
+                self.voices[2].wf_add = self.sid_cycles * self.voices[2].freq as u32;
             },
             0xD40F => {
                 self.voices[2].freq = (self.voices[2].freq & 0x00FF) | ((value as u16) << 8);
-                self.voices[2].wf_add = SID_CYCLES * self.voices[2].freq as u32;
+                self.voices[2].wf_add = self.sid_cycles * self.voices[2].freq as u32;
             },
             0xD410 => {
                 self.voices[2].pw_val = (self.voices[2].pw_val & 0x0F00) | value as u16;
@@ -438,12 +544,12 @@ impl SIDAudioDevice {
                 self.set_control_register(2, value);
             },
             0xD413 => {
-                self.voices[2].attack_add = EG_TABLE[ (value >> 4) as usize ];
-                self.voices[2].decay_sub  = EG_TABLE[ (value & 0x0F) as usize ];
+                self.voices[2].attack_add = self.eg_table[ (value >> 4) as usize ];
+                self.voices[2].decay_sub  = self.eg_table[ (value & 0x0F) as usize ];
             },
             0xD414 => {
                 self.voices[2].sustain_level = 0x111111 * (value >> 4) as u32;
-                self.voices[2].release_sub   = EG_TABLE[ (value & 0x0F) as usize ];
+                self.voices[2].release_sub   = self.eg_table[ (value & 0x0F) as usize ];
             },
             0xD416 => {
                 if self.filter_freq != value {
@@ -622,26 +728,31 @@ impl SIDAudioDevice {
             }
         }
     }
-}
 
-// SDL2 audio callback implementation - this is where the samples are being converted to output sound
-#[cfg(not(target_os = "redox"))]
-impl AudioCallback for SIDAudioDevice {
-    type Channel = i16;
 
-    fn callback(&mut self, out: &mut [i16]) {
+    // xorshift128 - the same sequence for the same seed, so noise is reproducible
+    fn random(&mut self) -> u32 {
+        let t = self.rng_state[0] ^ (self.rng_state[0] << 11);
+        self.rng_state[0] = self.rng_state[1];
+        self.rng_state[1] = self.rng_state[2];
+        self.rng_state[2] = self.rng_state[3];
+        self.rng_state[3] = self.rng_state[3] ^ (self.rng_state[3] >> 19) ^ (t ^ (t >> 8));
+        self.rng_state[3]
+    }
+}
+
+// sample generation - this is where the SID state is being converted to output sound
+impl SIDAudioDevice {
+    fn generate_samples(&mut self, out: &mut [i16]) {
         let iir_att = self.iir_att;
         let d1 = self.d1;
         let d2 = self.d2;
         let g1 = self.g1;
         let g2 = self.g2;
 
-        let mut sample_count = (self.sample_idx + NUM_SAMPLES/2) << 16;
+        let master_volume: u8 = self.volume;
 
         for x in out.iter_mut() {
-            let master_volume: u8 = self.sample_buffer[(sample_count >> 16) % NUM_SAMPLES];
-
-            sample_count += ((50 * NUM_SAMPLES/2) << 16) / SAMPLE_FREQ as usize;
             let mut total_output: i32 = (SAMPLE_TABLE[master_volume as usize] as i32) << 8;
             let mut total_output_filter: i32 = 0;
 
@@ -738,7 +849,7 @@ impl AudioCallback for SIDAudioDevice {
                     },
                     WaveForm::Noise => {
                         if self.voices[i].wf_cnt > 0x100000 {
-                            let rnd_noise = rand::random::<u16>() << 8;
+                            let rnd_noise = (self.random() as u16) << 8;
                             self.voices[i].noise = rnd_noise as u32;
                             output = rnd_noise;
                             self.voices[i].wf_cnt &= 0xFFFFF;
@@ -0,0 +1,122 @@
+// machine snapshots - the complete emulation state as a flat little endian byte stream
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read};
+
+pub const SIGNATURE: &'static [u8; 16] = b"RUST64 SNAPSHOT\0";
+pub const VERSION: u8 = 11;
+
+
+// collects the state of all components - each component writes its fields in a fixed order
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter {
+        SnapshotWriter {
+            data: Vec::<u8>::new(),
+        }
+    }
+
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.write_u16::<LittleEndian>(value).unwrap();
+    }
+
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.write_u32::<LittleEndian>(value).unwrap();
+    }
+
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.write_u64::<LittleEndian>(value).unwrap();
+    }
+
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.data.write_f32::<LittleEndian>(value).unwrap();
+    }
+
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+
+// reads the state back in the same order it was written by SnapshotWriter
+pub struct SnapshotReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> SnapshotReader<'a> {
+        SnapshotReader {
+            cursor: Cursor::new(data),
+        }
+    }
+
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        self.cursor.read_u8().map_err(|e| e.to_string())
+    }
+
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        self.cursor.read_u16::<LittleEndian>().map_err(|e| e.to_string())
+    }
+
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        self.cursor.read_u32::<LittleEndian>().map_err(|e| e.to_string())
+    }
+
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        self.cursor.read_u64::<LittleEndian>().map_err(|e| e.to_string())
+    }
+
+
+    pub fn read_f32(&mut self) -> Result<f32, String> {
+        self.cursor.read_f32::<LittleEndian>().map_err(|e| e.to_string())
+    }
+
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+
+    // fill the whole buffer - the size is known by the component
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), String> {
+        self.cursor.read_exact(bytes).map_err(|e| e.to_string())
+    }
+
+
+    // has all of the data been consumed?
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == 0
+    }
+
+
+    // number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.cursor.get_ref().len().saturating_sub(self.cursor.position() as usize)
+    }
+}
@@ -0,0 +1,191 @@
+// T64 tape archives - a header, a fixed size directory and the contents of the files without their load addresses
+use byteorder::{ByteOrder, LittleEndian};
+use c64::d64;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+
+const HEADER_SIZE: usize = 0x40;
+const ENTRY_SIZE:  usize = 0x20;
+
+
+// a single file of the archive
+#[derive(Clone, Debug)]
+pub struct T64Entry {
+    pub file_type: u8,      // C64 file type as found in a D64 directory ($82: PRG)
+    pub start_address: u16,
+    pub end_address: u16,   // first address after the file (often wrong in images made by old tools)
+    pub offset: usize,      // position of the contents in the archive
+    pub name: [u8; 16],     // PETSCII, padded with spaces
+}
+
+impl T64Entry {
+    // the file name without padding
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().rposition(|&c| c != 0x20 && c != 0xA0).map_or(0, |pos| pos + 1);
+        &self.name[..len]
+    }
+}
+
+impl fmt::Display for T64Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\" ${:04x}-${:04x}", d64::petscii_to_ascii(self.name()), self.start_address, self.end_address)
+    }
+}
+
+
+pub struct T64 {
+    data: Vec<u8>,
+    name: [u8; 24],
+    entries: Vec<T64Entry>,
+}
+
+impl T64 {
+    pub fn from_filename(filename: &str) -> Result<T64, String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        T64::from_bytes(data)
+    }
+
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<T64, String> {
+        // "C64 tape image file", "C64S tape file" and other variants are in use
+        if data.len() < HEADER_SIZE || &data[0..3] != b"C64" {
+            return Err("Invalid T64 signature".to_string())
+        }
+
+        let max_entries  = LittleEndian::read_u16(&data[0x22..0x24]) as usize;
+        let used_entries = LittleEndian::read_u16(&data[0x24..0x26]) as usize;
+        let mut name = [0x20; 24];
+        name.copy_from_slice(&data[0x28..0x40]);
+
+        let mut entries = Vec::<T64Entry>::new();
+
+        // some tools store 0 as the number of used entries - all slots are checked instead
+        for i in 0..max_entries.max(used_entries).max(1) {
+            let pos = HEADER_SIZE + i * ENTRY_SIZE;
+            if pos + ENTRY_SIZE > data.len() {
+                break;
+            }
+
+            let entry = &data[pos..(pos + ENTRY_SIZE)];
+
+            // 0: free slot, 1: normal tape file, everything else (memory snapshots etc.) is not supported
+            if entry[0] != 1 {
+                continue;
+            }
+
+            let mut entry_name = [0x20; 16];
+            entry_name.copy_from_slice(&entry[16..32]);
+
+            entries.push(T64Entry {
+                file_type:     entry[1],
+                start_address: LittleEndian::read_u16(&entry[2..4]),
+                end_address:   LittleEndian::read_u16(&entry[4..6]),
+                offset:        LittleEndian::read_u32(&entry[8..12]) as usize,
+                name:          entry_name,
+            });
+        }
+
+        Ok(T64 {
+            data: data,
+            name: name,
+            entries: entries,
+        })
+    }
+
+
+    // the tape name, padded with spaces
+    pub fn name(&self) -> &[u8; 24] {
+        &self.name
+    }
+
+
+    pub fn entries(&self) -> &[T64Entry] {
+        &self.entries
+    }
+
+
+    // first entry whose name starts with the given name, as matched by the KERNAL tape LOAD - an empty name matches any file
+    pub fn find_entry(&self, name: &[u8]) -> Option<&T64Entry> {
+        self.entries.iter().find(|entry| entry.name().starts_with(name))
+    }
+
+
+    // the contents of an entry in PRG format (preceded by the load address)
+    // the end address is not trusted, the file ends at the next file or at the end of the archive at the latest
+    pub fn prg_data(&self, entry: &T64Entry) -> Result<Vec<u8>, String> {
+        if entry.offset >= self.data.len() {
+            return Err("Entry offset beyond end of archive".to_string())
+        }
+
+        let next_offset = self.entries.iter().map(|e| e.offset).filter(|&offset| offset > entry.offset).min();
+        let max_len = next_offset.unwrap_or(self.data.len()).min(self.data.len()) - entry.offset;
+        let len = (entry.end_address.wrapping_sub(entry.start_address) as usize).min(max_len);
+
+        let mut prg = vec![entry.start_address as u8, (entry.start_address >> 8) as u8];
+        prg.extend_from_slice(&self.data[entry.offset..(entry.offset + len)]);
+        Ok(prg)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an archive with the given (start address, contents) files - the end addresses can be overridden
+    fn archive(files: &[(u16, &[u8])], end_addresses: Option<&[u16]>) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE + files.len() * ENTRY_SIZE];
+        data[0..19].copy_from_slice(b"C64 tape image file");
+        LittleEndian::write_u16(&mut data[0x22..0x24], files.len() as u16);
+        LittleEndian::write_u16(&mut data[0x24..0x26], files.len() as u16);
+        data[0x28..0x40].copy_from_slice(&[0x20; 24]);
+
+        for (i, &(start, contents)) in files.iter().enumerate() {
+            let offset = data.len();
+            let end = end_addresses.map_or(start + contents.len() as u16, |ends| ends[i]);
+            let entry = &mut data[(HEADER_SIZE + i * ENTRY_SIZE)..(HEADER_SIZE + (i + 1) * ENTRY_SIZE)];
+            entry[0] = 1;
+            entry[1] = 0x82;
+            LittleEndian::write_u16(&mut entry[2..4], start);
+            LittleEndian::write_u16(&mut entry[4..6], end);
+            LittleEndian::write_u32(&mut entry[8..12], offset as u32);
+            entry[16..32].copy_from_slice(&[0x20; 16]);
+            entry[16..22].copy_from_slice(format!("FILE{:02}", i).as_bytes());
+            data.extend_from_slice(contents);
+        }
+
+        data
+    }
+
+
+    #[test]
+    fn extract_files() {
+        let t64 = T64::from_bytes(archive(&[(0x0801, &[1, 2, 3]), (0xC000, &[4, 5])], None)).unwrap();
+        assert_eq!(t64.entries().len(), 2);
+
+        let entry = t64.find_entry(b"FILE01").unwrap();
+        assert_eq!(entry.name(), b"FILE01");
+        assert_eq!(t64.prg_data(entry).unwrap(), vec![0x00, 0xC0, 4, 5]);
+
+        let first = t64.find_entry(b"").unwrap();
+        assert_eq!(t64.prg_data(first).unwrap(), vec![0x01, 0x08, 1, 2, 3]);
+    }
+
+
+    #[test]
+    fn wrong_end_address() {
+        // the end address is beyond the next file and the end of the archive
+        let t64 = T64::from_bytes(archive(&[(0x0801, &[1, 2, 3]), (0xC000, &[4, 5])], Some(&[0xFFFF, 0xFFFF]))).unwrap();
+        assert_eq!(t64.prg_data(&t64.entries()[0]).unwrap(), vec![0x01, 0x08, 1, 2, 3]);
+        assert_eq!(t64.prg_data(&t64.entries()[1]).unwrap(), vec![0x00, 0xC0, 4, 5]);
+    }
+
+
+    #[test]
+    fn invalid_signature() {
+        assert!(T64::from_bytes(vec![0; HEADER_SIZE]).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// TAP tape images - the raw signal of a cassette as the lengths of its pulses (one full square wave each),
+// measured in C64 cycles
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::File;
+use std::io::{Read, Write};
+
+const SIGNATURE: &'static [u8; 12] = b"C64-TAPE-RAW";
+const HEADER_SIZE: usize = 0x14;
+
+// a 0 byte in a version 0 image stands for a pulse longer than 255 * 8 cycles of unknown length
+const OVERFLOW_CYCLES: u32 = 256 * 8;
+
+
+pub struct Tap {
+    pub version: u8, // 0: overflows have no length, 1: overflows are followed by their exact length
+    pub pulses: Vec<u32>,
+}
+
+impl Tap {
+    // a blank tape to record on
+    pub fn new() -> Tap {
+        Tap {
+            version: 1,
+            pulses: Vec::<u32>::new(),
+        }
+    }
+
+
+    pub fn from_filename(filename: &str) -> Result<Tap, String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        Tap::from_bytes(&data)
+    }
+
+
+    pub fn from_bytes(data: &[u8]) -> Result<Tap, String> {
+        if data.len() < HEADER_SIZE || &data[0..12] != SIGNATURE {
+            return Err("Invalid TAP signature".to_string())
+        }
+
+        // version 2 (half waves) is only used by the C16
+        let version = data[0x0C];
+        if version > 1 {
+            return Err(format!("Unsupported TAP version {}", version))
+        }
+
+        // the stored size is wrong in some images, the data always ends with the file
+        let size = LittleEndian::read_u32(&data[0x10..0x14]) as usize;
+        let end = (HEADER_SIZE + size).min(data.len());
+
+        let mut pulses = Vec::<u32>::new();
+        let mut i = HEADER_SIZE;
+
+        while i < end {
+            if data[i] != 0 {
+                pulses.push(data[i] as u32 * 8);
+                i += 1;
+            }
+            else if version == 0 {
+                pulses.push(OVERFLOW_CYCLES);
+                i += 1;
+            }
+            else {
+                if i + 4 > end {
+                    break;
+                }
+
+                pulses.push(LittleEndian::read_u24(&data[(i + 1)..(i + 4)]));
+                i += 4;
+            }
+        }
+
+        Ok(Tap {
+            version: version,
+            pulses: pulses,
+        })
+    }
+
+
+    // the image in TAP format - pulses are rounded to multiples of 8 cycles unless they are stored as overflows
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::<u8>::with_capacity(self.pulses.len());
+
+        for &cycles in self.pulses.iter() {
+            let value = cycles.saturating_add(4) / 8;
+
+            if value >= 1 && value <= 0xFF {
+                data.push(value as u8);
+            }
+            else if self.version == 0 {
+                data.push(0);
+            }
+            else {
+                let mut long = [0; 3];
+                LittleEndian::write_u24(&mut long, cycles.min(0xFFFFFF));
+                data.push(0);
+                data.extend_from_slice(&long);
+            }
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        header[0..12].copy_from_slice(SIGNATURE);
+        header[0x0C] = self.version;
+        LittleEndian::write_u32(&mut header[0x10..0x14], data.len() as u32);
+
+        let mut bytes = header.to_vec();
+        bytes.extend(data);
+        bytes
+    }
+
+
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        let mut file = File::create(filename).map_err(|e| e.to_string())?;
+        file.write_all(&self.to_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(version: u8, data: &[u8]) -> Vec<u8> {
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend_from_slice(&[version, 0, 0, 0, 0, 0, 0, 0]);
+        LittleEndian::write_u32(&mut bytes[0x10..0x14], data.len() as u32);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+
+    #[test]
+    fn version_1_overflows() {
+        let tap = Tap::from_bytes(&image(1, &[0x2E, 0x00, 0x20, 0x03, 0x00, 0x01])).unwrap();
+        assert_eq!(tap.version, 1);
+        assert_eq!(tap.pulses, vec![368, 800, 8]);
+    }
+
+
+    #[test]
+    fn version_0_overflows() {
+        let tap = Tap::from_bytes(&image(0, &[0x2E, 0x00, 0x01])).unwrap();
+        assert_eq!(tap.version, 0);
+        assert_eq!(tap.pulses, vec![368, OVERFLOW_CYCLES, 8]);
+    }
+
+
+    #[test]
+    fn data_ends_with_the_file() {
+        let mut data = image(1, &[0x2E, 0x30, 0x00, 0x20]);
+        LittleEndian::write_u32(&mut data[0x10..0x14], 1000);
+        assert_eq!(Tap::from_bytes(&data).unwrap().pulses, vec![368, 384]);
+    }
+
+
+    #[test]
+    fn invalid_images() {
+        assert!(Tap::from_bytes(b"C64-TAPE-RAW").is_err());
+        assert!(Tap::from_bytes(&image(2, &[0x2E])).is_err());
+    }
+
+
+    #[test]
+    fn version_1_round_trip() {
+        let tap = Tap {
+            version: 1,
+            pulses: vec![8, 368, 2040, 2048, 100000, 0xFFFFFF],
+        };
+
+        let loaded = Tap::from_bytes(&tap.to_bytes()).unwrap();
+        assert_eq!(loaded.version, 1);
+        assert_eq!(loaded.pulses, tap.pulses);
+    }
+
+
+    #[test]
+    fn version_0_round_trip() {
+        let tap = Tap {
+            version: 0,
+            pulses: vec![8, 368, 2040, 100000],
+        };
+
+        let loaded = Tap::from_bytes(&tap.to_bytes()).unwrap();
+        assert_eq!(loaded.version, 0);
+        assert_eq!(loaded.pulses, vec![8, 368, 2040, OVERFLOW_CYCLES]);
+    }
+
+
+    #[test]
+    fn pulses_are_rounded() {
+        let tap = Tap {
+            version: 1,
+            pulses: vec![3, 365, 372],
+        };
+
+        assert_eq!(Tap::from_bytes(&tap.to_bytes()).unwrap().pulses, vec![3, 368, 376]);
+    }
+}
@@ -0,0 +1,441 @@
+// virtual drive 8 - serves the KERNAL LOAD/SAVE traps from a D64 image or a host directory
+use c64::d64;
+use c64::p00;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// load address of directory listings, as sent by the 1541
+const DIR_LOAD_ADDRESS: u16 = 0x0401;
+
+// host file names longer than a C64 file name are shortened to this length and numbered if that isn't unique
+const SHORT_NAME_LEN: usize = 14;
+
+
+// a file of a host directory as seen from the C64
+struct HostFile {
+    path: PathBuf,
+    entry: d64::DirEntry,
+    offset: usize, // size of the PC64 container header in front of the contents
+}
+
+
+enum Media {
+    Image(d64::D64, String), // image and the file it's written back to
+    HostDir(PathBuf),
+}
+
+
+pub struct VirtualDrive {
+    media: Option<Media>,
+}
+
+impl VirtualDrive {
+    pub fn new() -> VirtualDrive {
+        VirtualDrive {
+            media: None,
+        }
+    }
+
+
+    // insert a *.d64, *.d71 or *.d81 image or use a host directory as the disk
+    pub fn attach(&mut self, path: &str) -> Result<(), String> {
+        let media = if Path::new(path).is_dir() {
+            Media::HostDir(PathBuf::from(path))
+        }
+        else if path.to_lowercase().ends_with(".g64") {
+            return Err("G64 images require the 1541 emulation".to_string())
+        }
+        else {
+            Media::Image(d64::D64::from_filename(path)?, path.to_string())
+        };
+
+        self.media = Some(media);
+        Ok(())
+    }
+
+
+    pub fn detach(&mut self) {
+        self.media = None;
+    }
+
+
+    pub fn is_attached(&self) -> bool {
+        self.media.is_some()
+    }
+
+
+    // contents of the file including the load address - "$" returns the directory listing
+    // returns None if the file doesn't exist
+    pub fn load(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if name.first() == Some(&b'$') {
+            let pattern = match name.iter().position(|&c| c == b':') {
+                Some(pos) => &name[(pos + 1)..],
+                None => b"*",
+            };
+
+            return Ok(Some(self.directory_listing(pattern)?))
+        }
+
+        let (_, pattern) = split_name(name);
+
+        match self.media {
+            Some(Media::Image(ref image, _)) => {
+                match image.find_file(pattern)? {
+                    Some(entry) => Ok(Some(image.read_file(&entry)?)),
+                    None => Ok(None),
+                }
+            },
+            Some(Media::HostDir(ref dir)) => {
+                let files = host_files(dir)?;
+                match files.into_iter().find(|file| file.entry.file_type != d64::FileType::Del && file.entry.matches(pattern)) {
+                    Some(host_file) => {
+                        let mut file = File::open(host_file.path).map_err(|e| e.to_string())?;
+                        let mut data = Vec::<u8>::new();
+                        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+                        Ok(Some(data.split_off(host_file.offset.min(data.len()))))
+                    },
+                    None => Ok(None),
+                }
+            },
+            None => Err("No disk attached".to_string()),
+        }
+    }
+
+
+    // store a PRG file - an existing file is only replaced if the name starts with "@:" or "@0:"
+    pub fn save(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        let (replace, name) = split_name(name);
+
+        if name.is_empty() || name.iter().any(|&c| c == b'*' || c == b'?') {
+            return Err("Invalid file name".to_string())
+        }
+
+        match self.media {
+            Some(Media::Image(ref mut image, ref filename)) => {
+                if image.find_file(name)?.is_some() {
+                    if !replace {
+                        return Err("File exists".to_string())
+                    }
+
+                    image.delete_file(name)?;
+                }
+
+                image.write_file(name, d64::FileType::Prg, data)?;
+                image.save(filename)
+            },
+            Some(Media::HostDir(ref dir)) => {
+                let file_name = d64::petscii_to_ascii(name);
+                if file_name.contains('/') || file_name.contains('\\') {
+                    return Err("Invalid file name".to_string())
+                }
+
+                let path = dir.join(format!("{}.prg", file_name));
+                if path.exists() && !replace {
+                    return Err("File exists".to_string())
+                }
+
+                let mut file = File::create(path).map_err(|e| e.to_string())?;
+                file.write_all(data).map_err(|e| e.to_string())
+            },
+            None => Err("No disk attached".to_string()),
+        }
+    }
+
+
+    // *** private functions *** //
+
+    // the directory as a BASIC program, formatted like the 1541 does
+    fn directory_listing(&self, pattern: &[u8]) -> Result<Vec<u8>, String> {
+        let (disk_name, disk_id, entries, free) = match self.media {
+            Some(Media::Image(ref image, _)) => (image.disk_name(), image.disk_id(), image.directory()?, image.free_sectors()),
+            Some(Media::HostDir(ref dir)) => {
+                let entries = host_files(dir)?.into_iter().map(|file| file.entry).collect();
+                let mut disk_name = [0xA0; 16];
+                let dir_name = d64::ascii_to_petscii(&dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default());
+                let len = dir_name.len().min(16);
+                disk_name[..len].copy_from_slice(&dir_name[..len]);
+                (disk_name, *b"00 2A", entries, 0)
+            },
+            None => return Err("No disk attached".to_string()),
+        };
+
+        let mut lines = Vec::<(u16, Vec<u8>)>::new();
+
+        let mut header = vec![0x12, b'"'];
+        header.extend_from_slice(&disk_name);
+        header.push(b'"');
+        header.push(b' ');
+        header.extend_from_slice(&disk_id);
+        lines.push((0, header));
+
+        for entry in entries.iter().filter(|entry| entry.file_type != d64::FileType::Del && entry.matches(pattern)) {
+            let indent = if entry.size < 10 { 3 } else if entry.size < 100 { 2 } else { 1 };
+            let mut line = vec![b' '; indent];
+            line.push(b'"');
+            line.extend_from_slice(entry.name());
+            line.push(b'"');
+            line.extend(vec![b' '; 16 - entry.name().len()]);
+            line.push(if entry.closed { b' ' } else { b'*' });
+            line.extend_from_slice(entry.file_type.to_string().as_bytes());
+            line.push(if entry.locked { b'<' } else { b' ' });
+            lines.push((entry.size, line));
+        }
+
+        lines.push((free, b"BLOCKS FREE.".to_vec()));
+
+        let mut listing = vec![DIR_LOAD_ADDRESS as u8, (DIR_LOAD_ADDRESS >> 8) as u8];
+        let mut addr = DIR_LOAD_ADDRESS;
+
+        for (number, mut text) in lines.into_iter() {
+            // shifted spaces would be expanded to BASIC keywords by LIST
+            for c in text.iter_mut() {
+                if *c == 0xA0 { *c = b' '; }
+            }
+
+            addr += text.len() as u16 + 5;
+            listing.extend_from_slice(&[addr as u8, (addr >> 8) as u8, number as u8, (number >> 8) as u8]);
+            listing.extend_from_slice(&text);
+            listing.push(0);
+        }
+
+        listing.extend_from_slice(&[0, 0]);
+        Ok(listing)
+    }
+}
+
+
+// *** private functions *** //
+
+// strip the drive number from a file name ("0:NAME", ":NAME") and check for the replace prefix ("@0:NAME")
+fn split_name(name: &[u8]) -> (bool, &[u8]) {
+    let replace = name.first() == Some(&b'@');
+
+    match name.iter().position(|&c| c == b':') {
+        Some(pos) if pos <= 2 => (replace, &name[(pos + 1)..]),
+        _ => (false, name),
+    }
+}
+
+
+// regular files in a host directory with their PETSCII names - "name.prg" is shown as "NAME",
+// files with other extensions keep them, PC64 containers (*.p00 etc.) are shown with the name in their header
+fn host_files(dir: &Path) -> Result<Vec<HostFile>, String> {
+    let mut paths = Vec::<PathBuf>::new();
+    for dir_entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+        paths.push(dir_entry.map_err(|e| e.to_string())?.path());
+    }
+
+    // sorted, so long names are always numbered the same way
+    paths.sort();
+
+    let mut files = Vec::<HostFile>::new();
+
+    for path in paths.into_iter() {
+        let metadata = fs::metadata(&path).map_err(|e| e.to_string())?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let (petscii_name, file_type, offset) = match path.extension().map(|ext| ext.to_string_lossy().to_lowercase()) {
+            Some(ref ext) if ext == "prg" => (d64::ascii_to_petscii(&file_name[..(file_name.len() - 4)]), d64::FileType::Prg, 0),
+            Some(ref ext) if ext == "seq" => (d64::ascii_to_petscii(&file_name[..(file_name.len() - 4)]), d64::FileType::Seq, 0),
+            Some(ref ext) if ext == "usr" => (d64::ascii_to_petscii(&file_name[..(file_name.len() - 4)]), d64::FileType::Usr, 0),
+            _ => match (p00::file_type(&path), container_name(&path)) {
+                (Some(file_type), Some(name)) => (name, file_type, p00::HEADER_SIZE),
+                _ => (d64::ascii_to_petscii(&file_name), d64::FileType::Prg, 0),
+            },
+        };
+
+        let name = unique_name(&petscii_name, &files);
+        let size = (metadata.len().saturating_sub(offset as u64) + 253) / 254;
+
+        files.push(HostFile {
+            path: path,
+            entry: d64::DirEntry {
+                file_type:   file_type,
+                closed:      true,
+                locked:      false,
+                name:        name,
+                track:       0,
+                sector:      0,
+                side_track:  0,
+                side_sector: 0,
+                record_len:  0,
+                size:        size.min(0xFFFF) as u16,
+            },
+            offset: offset,
+        });
+    }
+
+    Ok(files)
+}
+
+
+// the original name from the header of a PC64 container - None if the file isn't one
+fn container_name(path: &Path) -> Option<Vec<u8>> {
+    let mut header = [0; p00::HEADER_SIZE];
+    let mut file = File::open(path).ok()?;
+    file.read_exact(&mut header).ok()?;
+
+    if !p00::has_signature(&header) {
+        return None
+    }
+
+    let name = p00::header_name(&header);
+    Some(name[..name.iter().position(|&c| c == 0xA0).unwrap_or(16)].to_vec())
+}
+
+
+// a 16 character name padded with $A0 - names which are too long or already taken by another file are shortened
+// and numbered ("A VERY LONG NA01")
+fn unique_name(petscii_name: &[u8], files: &[HostFile]) -> [u8; 16] {
+    let is_taken = |name: &[u8; 16]| files.iter().any(|file| &file.entry.name == name);
+
+    let mut name = [0xA0; 16];
+    if petscii_name.len() <= 16 {
+        name[..petscii_name.len()].copy_from_slice(petscii_name);
+        if !is_taken(&name) {
+            return name
+        }
+    }
+
+    let len = petscii_name.len().min(SHORT_NAME_LEN);
+    for number in 1..100 {
+        name = [0xA0; 16];
+        name[..len].copy_from_slice(&petscii_name[..len]);
+        name[len..(len + 2)].copy_from_slice(format!("{:02}", number).as_bytes());
+        if !is_taken(&name) {
+            break;
+        }
+    }
+
+    name
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // an empty directory for the test to work in
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rust64_vdrive_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+
+    #[test]
+    fn save_and_load_on_an_image() {
+        let dir = temp_dir("image");
+        let path = dir.join("test.d64");
+        d64::D64::new(b"TEST", b"01").save(path.to_str().unwrap()).unwrap();
+
+        let mut drive = VirtualDrive::new();
+        drive.attach(path.to_str().unwrap()).unwrap();
+        drive.save(b"GAME", &[0x01, 0x08, 1, 2, 3]).unwrap();
+        assert_eq!(drive.save(b"0:GAME", &[0x01, 0x08]), Err("File exists".to_string()));
+        drive.save(b"@0:GAME", &[0x01, 0x08, 4]).unwrap();
+        assert!(drive.save(b"G*", &[0x01, 0x08]).is_err());
+
+        assert_eq!(drive.load(b"G*").unwrap(), Some(vec![0x01, 0x08, 4]));
+        assert_eq!(drive.load(b"MISSING").unwrap(), None);
+
+        // the image file is written back with every save with every save
+        let image = d64::D64::from_filename(path.to_str().unwrap()).unwrap();
+        assert_eq!(image.directory().unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn directory_listing() {
+        let dir = temp_dir("listing");
+        let path = dir.join("test.d64");
+        let mut image = d64::D64::new(b"TEST", b"01");
+        image.write_file(b"GAME", d64::FileType::Prg, &[0; 1000]).unwrap();
+        image.write_file(b"DATA", d64::FileType::Seq, &[0; 10]).unwrap();
+        image.save(path.to_str().unwrap()).unwrap();
+
+        let mut drive = VirtualDrive::new();
+        drive.attach(path.to_str().unwrap()).unwrap();
+        let listing = drive.load(b"$").unwrap().unwrap();
+
+        // a BASIC program at $0401 whose lines are linked correctly
+        assert_eq!(&listing[0..2], &[0x01, 0x04]);
+        let mut lines = Vec::<(u16, Vec<u8>)>::new();
+        let mut offset = 2;
+        while listing[offset] != 0 || listing[offset + 1] != 0 {
+            let next = (listing[offset] as usize | (listing[offset + 1] as usize) << 8) - 0x0401 + 2;
+            let number = listing[offset + 2] as u16 | (listing[offset + 3] as u16) << 8;
+            lines.push((number, listing[(offset + 4)..(next - 1)].to_vec()));
+            offset = next;
+        }
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(&lines[0].1[..8], b"\x12\"TEST  ");
+        assert_eq!(lines[1], (4, b"   \"GAME\"             PRG ".to_vec()));
+        assert_eq!(lines[2], (1, b"   \"DATA\"             SEQ ".to_vec()));
+        assert_eq!(lines[3], (659, b"BLOCKS FREE.".to_vec()));
+
+        assert_eq!(drive.load(b"$:G*").unwrap().unwrap().len(), listing.len() - 31);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn host_directory() {
+        let dir = temp_dir("host");
+        File::create(dir.join("game.prg")).unwrap().write_all(&[0x01, 0x08, 1, 2, 3]).unwrap();
+
+        let mut drive = VirtualDrive::new();
+        drive.attach(dir.to_str().unwrap()).unwrap();
+        assert_eq!(drive.load(b"GAME").unwrap(), Some(vec![0x01, 0x08, 1, 2, 3]));
+
+        // unshifted PETSCII letters are lower case on the host
+        drive.save(b"NEW", &[0x01, 0x08, 4]).unwrap();
+        assert_eq!(fs::read(dir.join("new.prg")).unwrap(), vec![0x01, 0x08, 4]);
+        assert_eq!(drive.save(b"GAME", &[0x01, 0x08]), Err("File exists".to_string()));
+        assert_eq!(drive.save(b"A/B", &[0x01, 0x08]), Err("Invalid file name".to_string()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn file_names() {
+        assert_eq!(split_name(b"GAME"), (false, &b"GAME"[..]));
+        assert_eq!(split_name(b"0:GAME"), (false, &b"GAME"[..]));
+        assert_eq!(split_name(b":GAME"), (false, &b"GAME"[..]));
+        assert_eq!(split_name(b"@0:GAME"), (true, &b"GAME"[..]));
+        assert_eq!(split_name(b"GAME:1"), (false, &b"GAME:1"[..]));
+    }
+
+
+    #[test]
+    fn containers_and_long_names() {
+        let dir = temp_dir("names");
+        File::create(dir.join("a very long file name.prg")).unwrap().write_all(&[0x01, 0x08, 1]).unwrap();
+        File::create(dir.join("a very long file name 2.prg")).unwrap().write_all(&[0x01, 0x08, 2]).unwrap();
+
+        let mut container = b"C64File\0".to_vec();
+        container.extend_from_slice(b"GAME\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        container.extend_from_slice(&[0x01, 0x08, 3]);
+        File::create(dir.join("game.p00")).unwrap().write_all(&container).unwrap();
+
+        let mut drive = VirtualDrive::new();
+        drive.attach(dir.to_str().unwrap()).unwrap();
+
+        let names: Vec<Vec<u8>> = host_files(&dir).unwrap().iter().map(|file| file.entry.name().to_vec()).collect();
+        assert_eq!(names, vec![b"A VERY LONG FI01".to_vec(), b"A VERY LONG FI02".to_vec(), b"GAME".to_vec()]);
+
+        assert_eq!(drive.load(b"A VERY LONG FI02").unwrap(), Some(vec![0x01, 0x08, 1]));
+        assert_eq!(drive.load(b"GAME").unwrap(), Some(vec![0x01, 0x08, 3]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
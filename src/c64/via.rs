@@ -0,0 +1,268 @@
+// 6522 VIA chip - two of them handle the IEC bus and the disk controller in the 1541
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+
+// interrupt flags (IFR/IER) - bit 2 is the shift register, which the 1541 doesn't use
+const IRQ_CA2: u8 = 1 << 0;
+const IRQ_CA1: u8 = 1 << 1;
+const IRQ_CB2: u8 = 1 << 3;
+const IRQ_CB1: u8 = 1 << 4;
+const IRQ_T2:  u8 = 1 << 5;
+const IRQ_T1:  u8 = 1 << 6;
+
+
+pub struct VIA {
+    pra:  u8,
+    prb:  u8,
+    ddra: u8,
+    ddrb: u8,
+    pub pa_in: u8, // levels of the port pins as driven by the outside world
+    pub pb_in: u8,
+    ca1: bool,     // current level of the CA1 input
+
+    t1_counter: u16,
+    t1_latch:   u16,
+    t1_armed:   bool, // one-shot mode: interrupt not yet triggered since the timer was loaded
+    t2_counter: u16,
+    t2_latch_lo: u8,
+    t2_armed:   bool,
+    sr:  u8,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+}
+
+impl VIA {
+    pub fn new() -> VIA {
+        VIA {
+            pra:  0,
+            prb:  0,
+            ddra: 0,
+            ddrb: 0,
+            pa_in: 0xFF,
+            pb_in: 0xFF,
+            ca1: false,
+            t1_counter: 0xFFFF,
+            t1_latch:   0xFFFF,
+            t1_armed:   false,
+            t2_counter: 0xFFFF,
+            t2_latch_lo: 0xFF,
+            t2_armed:   false,
+            sr:  0,
+            acr: 0,
+            pcr: 0,
+            ifr: 0,
+            ier: 0,
+        }
+    }
+
+
+    // the timers and latches are not affected by a reset
+    pub fn reset(&mut self) {
+        self.pra  = 0;
+        self.prb  = 0;
+        self.ddra = 0;
+        self.ddrb = 0;
+        self.sr   = 0;
+        self.acr  = 0;
+        self.pcr  = 0;
+        self.ifr  = 0;
+        self.ier  = 0;
+    }
+
+
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.pra);
+        w.write_u8(self.prb);
+        w.write_u8(self.ddra);
+        w.write_u8(self.ddrb);
+        w.write_u8(self.pa_in);
+        w.write_u8(self.pb_in);
+        w.write_bool(self.ca1);
+        w.write_u16(self.t1_counter);
+        w.write_u16(self.t1_latch);
+        w.write_bool(self.t1_armed);
+        w.write_u16(self.t2_counter);
+        w.write_u8(self.t2_latch_lo);
+        w.write_bool(self.t2_armed);
+        w.write_u8(self.sr);
+        w.write_u8(self.acr);
+        w.write_u8(self.pcr);
+        w.write_u8(self.ifr);
+        w.write_u8(self.ier);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.pra   = r.read_u8()?;
+        self.prb   = r.read_u8()?;
+        self.ddra  = r.read_u8()?;
+        self.ddrb  = r.read_u8()?;
+        self.pa_in = r.read_u8()?;
+        self.pb_in = r.read_u8()?;
+        self.ca1   = r.read_bool()?;
+        self.t1_counter  = r.read_u16()?;
+        self.t1_latch    = r.read_u16()?;
+        self.t1_armed    = r.read_bool()?;
+        self.t2_counter  = r.read_u16()?;
+        self.t2_latch_lo = r.read_u8()?;
+        self.t2_armed    = r.read_bool()?;
+        self.sr  = r.read_u8()?;
+        self.acr = r.read_u8()?;
+        self.pcr = r.read_u8()?;
+        self.ifr = r.read_u8()?;
+        self.ier = r.read_u8()?;
+        Ok(())
+    }
+
+
+    // count down both timers by one cycle
+    pub fn update(&mut self) {
+        // timer 1 underflows after latch + 1.5 cycles - rounded to latch + 2 in free-running mode
+        if self.t1_counter == 0 {
+            if self.t1_armed {
+                self.ifr |= IRQ_T1;
+            }
+
+            if (self.acr & 0x40) != 0 {
+                self.t1_counter = self.t1_latch;
+            }
+            else {
+                self.t1_armed = false;
+                self.t1_counter = 0xFFFF;
+            }
+        }
+        else {
+            self.t1_counter -= 1;
+        }
+
+        // timer 2 only counts phi2 (pulse counting on PB6 is not used by the 1541)
+        if (self.acr & 0x20) == 0 {
+            if self.t2_counter == 0 && self.t2_armed {
+                self.ifr |= IRQ_T2;
+                self.t2_armed = false;
+            }
+
+            self.t2_counter = self.t2_counter.wrapping_sub(1);
+        }
+    }
+
+
+    // is the IRQ output active?
+    pub fn irq(&self) -> bool {
+        (self.ifr & self.ier & 0x7F) != 0
+    }
+
+
+    pub fn read_register(&mut self, reg: u8) -> u8 {
+        match reg & 0x0F {
+            0x00 => {
+                self.ifr &= !(IRQ_CB1 | IRQ_CB2);
+                (self.prb & self.ddrb) | (self.pb_in & !self.ddrb)
+            },
+            0x01 => {
+                self.ifr &= !(IRQ_CA1 | IRQ_CA2);
+                (self.pra & self.ddra) | (self.pa_in & !self.ddra)
+            },
+            0x02 => self.ddrb,
+            0x03 => self.ddra,
+            0x04 => {
+                self.ifr &= !IRQ_T1;
+                self.t1_counter as u8
+            },
+            0x05 => (self.t1_counter >> 8) as u8,
+            0x06 => self.t1_latch as u8,
+            0x07 => (self.t1_latch >> 8) as u8,
+            0x08 => {
+                self.ifr &= !IRQ_T2;
+                self.t2_counter as u8
+            },
+            0x09 => (self.t2_counter >> 8) as u8,
+            0x0A => self.sr,
+            0x0B => self.acr,
+            0x0C => self.pcr,
+            0x0D => if self.irq() { self.ifr | 0x80 } else { self.ifr },
+            0x0E => self.ier | 0x80,
+            0x0F => (self.pra & self.ddra) | (self.pa_in & !self.ddra), // no handshake
+            _ => unreachable!(),
+        }
+    }
+
+
+    pub fn write_register(&mut self, reg: u8, value: u8) {
+        match reg & 0x0F {
+            0x00 => {
+                self.ifr &= !(IRQ_CB1 | IRQ_CB2);
+                self.prb = value;
+            },
+            0x01 => {
+                self.ifr &= !(IRQ_CA1 | IRQ_CA2);
+                self.pra = value;
+            },
+            0x02 => self.ddrb = value,
+            0x03 => self.ddra = value,
+            0x04 | 0x06 => self.t1_latch = (self.t1_latch & 0xFF00) | value as u16,
+            0x05 => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((value as u16) << 8);
+                self.t1_counter = self.t1_latch;
+                self.t1_armed = true;
+                self.ifr &= !IRQ_T1;
+            },
+            0x07 => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((value as u16) << 8);
+                self.ifr &= !IRQ_T1;
+            },
+            0x08 => self.t2_latch_lo = value,
+            0x09 => {
+                self.t2_counter = (self.t2_latch_lo as u16) | ((value as u16) << 8);
+                self.t2_armed = true;
+                self.ifr &= !IRQ_T2;
+            },
+            0x0A => self.sr = value,
+            0x0B => self.acr = value,
+            0x0C => self.pcr = value,
+            0x0D => self.ifr &= !value,
+            0x0E => {
+                if (value & 0x80) != 0 { self.ier |= value & 0x7F; }
+                else                   { self.ier &= !value; }
+            },
+            0x0F => self.pra = value,
+            _ => unreachable!(),
+        }
+    }
+
+
+    // port pins as seen from the outside - pins configured as inputs are pulled up
+    pub fn port_a_output(&self) -> u8 {
+        self.pra | !self.ddra
+    }
+
+
+    pub fn port_b_output(&self) -> u8 {
+        self.prb | !self.ddrb
+    }
+
+
+    // CA2 and CB2 levels when configured as manual outputs (PCR modes 110/111), high otherwise
+    pub fn ca2_output(&self) -> bool {
+        (self.pcr & 0x0E) != 0x0C
+    }
+
+
+    pub fn cb2_output(&self) -> bool {
+        (self.pcr & 0xE0) != 0xC0
+    }
+
+
+    // change the CA1 input level - the active edge is selected in PCR bit 0
+    pub fn set_ca1(&mut self, level: bool) {
+        if level != self.ca1 {
+            let positive_edge = (self.pcr & 0x01) != 0;
+            if level == positive_edge {
+                self.ifr |= IRQ_CA1;
+            }
+
+            self.ca1 = level;
+        }
+    }
+}
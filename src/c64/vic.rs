@@ -2,6 +2,7 @@
 use c64;
 use c64::memory;
 use c64::cpu;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
 use c64::vic_tables::*;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -10,9 +11,7 @@ use utils;
 pub type VICShared = Rc<RefCell<VIC>>;
 
 const SKIP_FRAMES:     u16 = 2;
-const NUM_RASTERLINES: u16 = 312;  // number of rasterlines for PAL (0x138)
-const FIRST_DISP_LINE: u16 = 0x10;
-const LAST_DISP_LINE:  u16 = 0x11f;
+const PAL_CYCLES_PER_LINE: u8 = 63; // the cycle handlers below follow the PAL (6569) timing
 const ROW25_YSTART: u16 = 0x33;
 const ROW25_YSTOP:  u16 = 0xFB;
 const ROW24_YSTART: u16 = 0x37;
@@ -29,6 +28,10 @@ pub struct VIC {
     pub raster_cnt: u16, // raster line counter (current raster line)
     pub raster_irq: u16, // raster interrupt line
     pub curr_cycle: u8,  // current cycle
+    pub cycles_per_line: u8,   // 63 (6569), 64 (6567R56A) or 65 (6567R8)
+    pub num_rasterlines: u16,  // 312 (6569), 262 (6567R56A) or 263 (6567R8)
+    pub first_disp_line: u16,  // raster lines drawn into the window buffer
+    pub last_disp_line: u16,
     pub border_on: bool, // upper/lower border on
     pub is_bad_line: bool,
     pub dbg_reg_changed: bool,  // has the VIC register changed? (use in visual debugger)
@@ -95,13 +98,17 @@ pub struct VIC {
 }
 
 impl VIC {
-    pub fn new_shared() -> VICShared {
+    pub fn new_shared(model: c64::Model) -> VICShared {
         Rc::new(RefCell::new(VIC {
             window_buffer: vec![0; c64::SCREEN_WIDTH * c64::SCREEN_HEIGHT],
             last_byte: 0,
-            raster_cnt: NUM_RASTERLINES - 1,
+            raster_cnt: model.num_rasterlines() - 1,
             raster_irq: 0,
             curr_cycle: 1,
+            cycles_per_line: model.cycles_per_line(),
+            num_rasterlines: model.num_rasterlines(),
+            first_disp_line: model.first_disp_line(),
+            last_disp_line: model.last_disp_line(),
             border_on:   false,
             is_bad_line: false,
             dbg_reg_changed: false,
@@ -167,6 +174,128 @@ impl VIC {
     }
     
 
+    // the frame buffer is not part of the state - it is redrawn with the next frame
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.last_byte);
+        w.write_u16(self.raster_cnt);
+        w.write_u16(self.raster_irq);
+        w.write_u8(self.curr_cycle);
+        w.write_bool(self.border_on);
+        w.write_bool(self.is_bad_line);
+        w.write_u8(self.irq_flag);
+        w.write_u8(self.irq_mask);
+        w.write_bytes(&self.matrix_line);
+        w.write_bytes(&self.color_line);
+        w.write_u32(self.screen_chunk_offset as u32);
+        w.write_u32(self.line_start_offset as u32);
+        w.write_u32(self.fg_mask_offset as u32);
+        w.write_u16(self.raster_x);
+        w.write_u16(self.dy_start);
+        w.write_u16(self.dy_stop);
+        w.write_u16(self.row_cnt);
+        w.write_u16(self.video_cnt);
+        w.write_u16(self.video_cnt_base);
+        w.write_u16(self.x_scroll);
+        w.write_u16(self.y_scroll);
+        w.write_u16(self.cia_vabase);
+        w.write_u16(self.display_mode);
+        w.write_bool(self.bad_lines_on);
+        w.write_bool(self.lp_triggered);
+        for v in self.mc.iter() { w.write_u16(*v); }
+        for v in self.mc_base.iter() { w.write_u16(*v); }
+        w.write_bool(self.display_state);
+        w.write_bool(self.ud_border_on);
+        w.write_bool(self.frame_skipped);
+        w.write_bool(self.draw_this_line);
+        w.write_u32(self.ml_idx as u32);
+        w.write_u16(self.skip_cnt);
+        for v in self.mx.iter() { w.write_u16(*v); }
+        w.write_bytes(&self.my);
+        w.write_bool(self.trigger_vblank);
+        for v in self.border_on_sample.iter() { w.write_bool(*v); }
+        w.write_bytes(&self.fg_mask_buffer);
+        w.write_bytes(&self.border_color_sample);
+        w.write_u16(self.matrix_base);
+        w.write_u16(self.char_base);
+        w.write_u16(self.bitmap_base);
+        w.write_u8(self.refresh_cnt);
+        w.write_u8(self.sprite_y_exp);
+        w.write_u8(self.sprite_dma_on);
+        w.write_u8(self.sprite_display_on);
+        w.write_u8(self.sprite_draw);
+        for v in self.sprite_ptr.iter() { w.write_u16(*v); }
+        w.write_u8(self.gfx_data);
+        w.write_u8(self.char_data);
+        w.write_u8(self.color_data);
+        w.write_u8(self.last_char_data);
+        w.write_bytes(&self.sprite_coll_buffer);
+        for data in self.sprite_data.iter() { w.write_bytes(data); }
+        for data in self.sprite_draw_data.iter() { w.write_bytes(data); }
+        w.write_u32(self.first_ba_cycle);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.last_byte = r.read_u8()?;
+        self.raster_cnt = r.read_u16()?;
+        self.raster_irq = r.read_u16()?;
+        self.curr_cycle = r.read_u8()?;
+        self.border_on = r.read_bool()?;
+        self.is_bad_line = r.read_bool()?;
+        self.irq_flag = r.read_u8()?;
+        self.irq_mask = r.read_u8()?;
+        r.read_bytes(&mut self.matrix_line)?;
+        r.read_bytes(&mut self.color_line)?;
+        self.screen_chunk_offset = r.read_u32()? as usize;
+        self.line_start_offset = r.read_u32()? as usize;
+        self.fg_mask_offset = r.read_u32()? as usize;
+        self.raster_x = r.read_u16()?;
+        self.dy_start = r.read_u16()?;
+        self.dy_stop = r.read_u16()?;
+        self.row_cnt = r.read_u16()?;
+        self.video_cnt = r.read_u16()?;
+        self.video_cnt_base = r.read_u16()?;
+        self.x_scroll = r.read_u16()?;
+        self.y_scroll = r.read_u16()?;
+        self.cia_vabase = r.read_u16()?;
+        self.display_mode = r.read_u16()?;
+        self.bad_lines_on = r.read_bool()?;
+        self.lp_triggered = r.read_bool()?;
+        for v in self.mc.iter_mut() { *v = r.read_u16()?; }
+        for v in self.mc_base.iter_mut() { *v = r.read_u16()?; }
+        self.display_state = r.read_bool()?;
+        self.ud_border_on = r.read_bool()?;
+        self.frame_skipped = r.read_bool()?;
+        self.draw_this_line = r.read_bool()?;
+        self.ml_idx = r.read_u32()? as usize;
+        self.skip_cnt = r.read_u16()?;
+        for v in self.mx.iter_mut() { *v = r.read_u16()?; }
+        r.read_bytes(&mut self.my)?;
+        self.trigger_vblank = r.read_bool()?;
+        for v in self.border_on_sample.iter_mut() { *v = r.read_bool()?; }
+        r.read_bytes(&mut self.fg_mask_buffer)?;
+        r.read_bytes(&mut self.border_color_sample)?;
+        self.matrix_base = r.read_u16()?;
+        self.char_base = r.read_u16()?;
+        self.bitmap_base = r.read_u16()?;
+        self.refresh_cnt = r.read_u8()?;
+        self.sprite_y_exp = r.read_u8()?;
+        self.sprite_dma_on = r.read_u8()?;
+        self.sprite_display_on = r.read_u8()?;
+        self.sprite_draw = r.read_u8()?;
+        for v in self.sprite_ptr.iter_mut() { *v = r.read_u16()?; }
+        self.gfx_data = r.read_u8()?;
+        self.char_data = r.read_u8()?;
+        self.color_data = r.read_u8()?;
+        self.last_char_data = r.read_u8()?;
+        r.read_bytes(&mut self.sprite_coll_buffer)?;
+        for data in self.sprite_data.iter_mut() { r.read_bytes(data)?; }
+        for data in self.sprite_draw_data.iter_mut() { r.read_bytes(data)?; }
+        self.first_ba_cycle = r.read_u32()?;
+        Ok(())
+    }
+
+
     pub fn read_register(&self, addr: u16) -> u8 {
         match addr {
             0xD000...0xD00F => {
@@ -368,7 +497,13 @@ impl VIC {
     pub fn read_byte(&mut self, addr: u16) -> u8 {
         let va = addr | self.cia_vabase;
 
-        if (va & 0x7000) == 0x1000 {
+        // in Ultimax mode the VIC sees the upper 4K of ROMH at $3000-$3FFF of each bank instead of the character ROM
+        let ultimax = as_ref!(self.mem_ref).ultimax;
+        if ultimax && (va & 0x3000) == 0x3000 {
+            let addr = 0xF000 + (va & 0x0FFF);
+            self.last_byte = as_mut!(self.mem_ref).get_rom_bank(memory::MemType::Romh).read(addr);
+        }
+        else if !ultimax && (va & 0x7000) == 0x1000 {
             let addr = 0xD000 + (va & 0x0FFF);
             self.last_byte = as_mut!(self.mem_ref).get_rom_bank(memory::MemType::Chargen).read(addr);
         }
@@ -387,11 +522,16 @@ impl VIC {
         let mut line_finished = false;
         self.dbg_reg_changed = false;
 
-        match self.curr_cycle {
+        match self.pal_cycle() {
+            // extra NTSC cycles: idle access, sprite 0 and 1 BA stays as set in cycles 55-57
+            0 => {
+                self.idle_access();
+                self.display_if_bad_line();
+            },
             // fetch sprite pointer 3, inc raster counter, trigger raster irq,
             // test for bad line, reset BA if sprites 3 and 4 are off, read data of sprite 3
             1 => {
-                if self.raster_cnt == (NUM_RASTERLINES - 1) {
+                if self.raster_cnt == (self.num_rasterlines - 1) {
                     self.trigger_vblank = true;
                 }
                 else {
@@ -413,8 +553,8 @@ impl VIC {
                                        ((self.raster_cnt & 7) == self.y_scroll) &&
                                         self.bad_lines_on;
 
-                    self.draw_this_line = (self.raster_cnt >= FIRST_DISP_LINE) &&
-                                          (self.raster_cnt <= LAST_DISP_LINE ) && !self.frame_skipped;
+                    self.draw_this_line = (self.raster_cnt >= self.first_disp_line) &&
+                                          (self.raster_cnt <= self.last_disp_line) && !self.frame_skipped;
                 }
 
                 self.border_on_sample[0] = self.border_on;
@@ -447,7 +587,9 @@ impl VIC {
                     // trigger VBlank
                     *should_trigger_vblank = true;
                     
-                    self.line_start_offset = 0;
+                    // frames with fewer lines than the window are centered, the rows around them stay black
+                    let num_disp_lines = (self.last_disp_line - self.first_disp_line + 1) as usize;
+                    self.line_start_offset = (c64::SCREEN_HEIGHT - num_disp_lines) / 2 * c64::SCREEN_WIDTH;
                     
                     if self.raster_irq == 0 {
                         match self.raster_irq() {
@@ -940,6 +1082,19 @@ impl VIC {
 
     // *** private functions *** //
 
+    // map the current cycle onto the PAL cycle it behaves like - the extra cycles
+    // of a 64/65 cycle NTSC line are inserted after cycle 57 and map to 0
+    fn pal_cycle(&self) -> u8 {
+        let extra = self.cycles_per_line - PAL_CYCLES_PER_LINE;
+
+        match self.curr_cycle {
+            c if c <= 57 => c,
+            c if c <= 57 + extra => 0,
+            c => c - extra,
+        }
+    }
+
+
     // check if register status has changed - used for visual debugger
     fn dbg_check_regs(&mut self, addr: u16, value: u8) {
         self.dbg_reg_changed = as_mut!(self.mem_ref).get_ram_bank(memory::MemType::Io).read(addr) != value;
@@ -948,7 +1103,7 @@ impl VIC {
 
     fn matrix_access(&mut self, c64_cycle_cnt: u32) {
         if as_ref!(self.cpu_ref).ba_low {
-            if (c64_cycle_cnt - self.first_ba_cycle) < 3 {
+            if c64_cycle_cnt.wrapping_sub(self.first_ba_cycle) < 3 {
                 self.color_line[self.ml_idx]  = 0xFF;
                 self.matrix_line[self.ml_idx] = 0xFF;
             }
@@ -1576,7 +1731,7 @@ impl VIC {
     fn sample_border(&mut self) {
         if self.draw_this_line {
             if self.border_on {
-                self.border_color_sample[(self.curr_cycle-13) as usize] = self.read_register(0xD020);
+                self.border_color_sample[(self.pal_cycle()-13) as usize] = self.read_register(0xD020);
             }
             
             self.screen_chunk_offset += 8;
@@ -0,0 +1,750 @@
+// WD1770/1772 floppy disk controller - reads and writes the 512 byte MFM sectors of the 1581 (the 1571 has one
+// for MFM disks, too, but it never sees such a disk). The disk is modelled on sector level: the IDs of the 10
+// sectors of a track pass the head at fixed points of each revolution and the data bytes are handed over through
+// the data register at the bit rate - a byte is never lost, the controller waits for the CPU instead
+use c64::d64;
+use c64::snapshot::{SnapshotReader, SnapshotWriter};
+use num::FromPrimitive;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const SECTORS_PER_TRACK: u8 = 10;
+const NUM_CYLINDERS: u8 = 80;
+const MAX_CYLINDER: u8 = 83;
+
+// times in microseconds - the controller is updated once per microsecond
+const REVOLUTION_TIME: u32 = 200000; // 300 rpm
+const SECTOR_TIME: u32 = REVOLUTION_TIME / SECTORS_PER_TRACK as u32;
+const INDEX_TIME: u32 = 2000;        // length of the index pulse
+const FIRST_ID_TIME: u32 = 3000;     // the first sector ID follows the index pulse after the gap
+const BYTE_TIME: u32 = 32;           // 250 kbit/s
+const SETTLE_TIME: u32 = 30000;      // head settling delay, requested with the E flag
+const STEP_TIMES: [u32; 4] = [6000, 12000, 2000, 3000]; // stepping rates of the WD1772
+
+// bytes taken by Write Track in one revolution
+const TRACK_SIZE: usize = (REVOLUTION_TIME / BYTE_TIME) as usize;
+
+// status register - bits 1 and 2 depend on the type of the last command
+const BUSY:      u8 = 0x01;
+const DRQ:       u8 = 0x02; // type II/III
+const INDEX:     u8 = 0x02; // type I
+const TRACK_0:   u8 = 0x04; // type I
+const NOT_FOUND: u8 = 0x10; // record not found, seek error after a type I command
+const SPIN_UP:   u8 = 0x20; // type I
+const MOTOR_ON:  u8 = 0x80;
+
+// command flags
+const NO_SPIN_UP: u8 = 0x08; // h: don't wait for the motor to spin up
+const SETTLE:     u8 = 0x04; // E: wait for the head to settle (type II/III)
+const VERIFY:     u8 = 0x04; // V: verify the track after seeking (type I)
+const UPDATE:     u8 = 0x10; // u: step commands update the track register
+const MULTIPLE:   u8 = 0x10; // m: read or write sectors until the end of the track
+
+
+enum_from_primitive! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Phase {
+        Idle,
+        SpinUp,     // waiting 6 revolutions for the motor before the command starts
+        Seek,       // stepping until the track register matches the data register - or track 0 for Restore
+        Step,       // a single step
+        Settle,     // type I command done after the last step, verify the track if requested
+        Verify,     // waiting for an ID of the track in the track register
+        Search,     // waiting for the ID of the sector in the sector register (any sector for Read Address)
+        Read,       // handing the sector (or ID) to the CPU
+        Write,      // taking the sector from the CPU
+        WriteTrack, // taking a whole track from the CPU
+    }
+}
+
+
+pub struct WD1770 {
+    status:  u8,
+    track:   u8,
+    sector:  u8,
+    data:    u8,
+    command: u8,
+    type_one: bool,   // the status register shows the type I bits
+    phase:   Phase,
+    delay:   u32,     // time until the current phase continues
+    cylinder: u8,     // head position
+    step_in: bool,    // direction of the last step - towards the inner tracks
+    step_pulse: bool, // a step pulse was sent since the drive last asked
+    rotation: u32,    // time since the last index pulse
+    index: bool,      // the index hole is at the sensor
+    index_count: u8,  // index pulses since the command started - or ended, for the motor timeout
+    motor:   bool,    // motor on output
+    buffer:  Vec<u8>, // bytes of the sector being read or written
+    pos:     usize,
+    pub dirty: bool,  // a sector was written since the image was last saved
+}
+
+impl WD1770 {
+    pub fn new() -> WD1770 {
+        WD1770 {
+            status:  0,
+            track:   0,
+            sector:  1,
+            data:    0,
+            command: 0,
+            type_one: true,
+            phase:   Phase::Idle,
+            delay:   0,
+            cylinder: 0,
+            step_in: false,
+            step_pulse: false,
+            rotation: 0,
+            index: false,
+            index_count: 0,
+            motor:   false,
+            buffer:  Vec::<u8>::new(),
+            pos:     0,
+            dirty:   false,
+        }
+    }
+
+
+    // the head stays where it is
+    pub fn reset(&mut self) {
+        self.status  = 0;
+        self.track   = 0;
+        self.sector  = 1;
+        self.data    = 0;
+        self.command = 0;
+        self.type_one = true;
+        self.phase   = Phase::Idle;
+        self.delay   = 0;
+        self.index_count = 0;
+        self.motor   = false;
+        self.buffer.clear();
+        self.pos     = 0;
+    }
+
+
+    pub fn save_state(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.status);
+        w.write_u8(self.track);
+        w.write_u8(self.sector);
+        w.write_u8(self.data);
+        w.write_u8(self.command);
+        w.write_bool(self.type_one);
+        w.write_u8(self.phase as u8);
+        w.write_u32(self.delay);
+        w.write_u8(self.cylinder);
+        w.write_bool(self.step_in);
+        w.write_bool(self.step_pulse);
+        w.write_u32(self.rotation);
+        w.write_bool(self.index);
+        w.write_u8(self.index_count);
+        w.write_bool(self.motor);
+        w.write_u32(self.buffer.len() as u32);
+        w.write_bytes(&self.buffer);
+        w.write_u32(self.pos as u32);
+    }
+
+
+    pub fn load_state(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.status  = r.read_u8()?;
+        self.track   = r.read_u8()?;
+        self.sector  = r.read_u8()?;
+        self.data    = r.read_u8()?;
+        self.command = r.read_u8()?;
+        self.type_one = r.read_bool()?;
+        self.phase   = Phase::from_u8(r.read_u8()?).ok_or("Invalid disk controller state".to_string())?;
+        self.delay   = r.read_u32()?;
+        self.cylinder = r.read_u8()?;
+        self.step_in  = r.read_bool()?;
+        self.step_pulse = r.read_bool()?;
+        self.rotation = r.read_u32()?;
+        self.index    = r.read_bool()?;
+        self.index_count = r.read_u8()?;
+        self.motor   = r.read_bool()?;
+
+        let len = r.read_u32()? as usize;
+        if len > TRACK_SIZE || self.cylinder > MAX_CYLINDER || self.rotation >= REVOLUTION_TIME {
+            return Err("Invalid disk controller state".to_string())
+        }
+
+        self.buffer = vec![0; len];
+        r.read_bytes(&mut self.buffer)?;
+        self.pos = r.read_u32()? as usize;
+        Ok(())
+    }
+
+
+    pub fn read_register(&mut self, reg: u8) -> u8 {
+        match reg & 0x03 {
+            0x00 => {
+                let motor = if self.motor { MOTOR_ON } else { 0 };
+                if self.type_one {
+                    let spin_up = if self.motor && self.phase != Phase::SpinUp { SPIN_UP } else { 0 };
+                    let index = if self.index { INDEX } else { 0 };
+                    let track_0 = if self.cylinder == 0 { TRACK_0 } else { 0 };
+                    (self.status & !(INDEX | TRACK_0)) | motor | spin_up | index | track_0
+                }
+                else {
+                    self.status | motor
+                }
+            },
+            0x01 => self.track,
+            0x02 => self.sector,
+            0x03 => {
+                if !self.type_one {
+                    self.status &= !DRQ;
+                }
+                self.data
+            },
+            _ => unreachable!(),
+        }
+    }
+
+
+    pub fn write_register(&mut self, reg: u8, value: u8) {
+        match reg & 0x03 {
+            0x00 => self.write_command(value),
+            0x01 => self.track = value,
+            0x02 => self.sector = value,
+            0x03 => {
+                if !self.type_one {
+                    self.status &= !DRQ;
+                }
+                self.data = value;
+            },
+            _ => unreachable!(),
+        }
+    }
+
+
+    // the drive asks whether the head was stepped - a step with a disk in the drive resets its disk change line
+    pub fn take_step_pulse(&mut self) -> bool {
+        let step_pulse = self.step_pulse;
+        self.step_pulse = false;
+        step_pulse
+    }
+
+
+    // advance by one microsecond - side 0 is the head holding the first half of each track of the image
+    // (sectors 0-19 of a D81 track), the sector IDs only pass the head while the disk turns
+    pub fn update(&mut self, disk: Option<&mut d64::D64>, side: u8, spinning: bool) {
+        let mut id: Option<u8> = None;
+
+        self.index = spinning && self.rotation < INDEX_TIME;
+        if spinning {
+            self.rotation = (self.rotation + 1) % REVOLUTION_TIME;
+            if self.rotation == 0 {
+                self.index_count = self.index_count.saturating_add(1);
+            }
+
+            if disk.is_some() && self.cylinder < NUM_CYLINDERS && self.rotation >= FIRST_ID_TIME &&
+               (self.rotation - FIRST_ID_TIME) % SECTOR_TIME == 0 {
+                id = Some(((self.rotation - FIRST_ID_TIME) / SECTOR_TIME) as u8 + 1);
+            }
+        }
+
+        // the motor is turned off after 9 revolutions without a command
+        if self.phase == Phase::Idle {
+            if self.motor && self.index_count >= 9 {
+                self.motor = false;
+            }
+            return;
+        }
+
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        match self.phase {
+            Phase::Idle => (),
+            Phase::SpinUp => {
+                if self.index_count >= 6 {
+                    self.execute();
+                }
+            },
+            Phase::Seek => {
+                // Restore starts with track 255 and ends when the head reaches track 0
+                let restore = (self.command & 0xF0) == 0x00;
+                if restore && self.cylinder == 0 {
+                    self.track = 0;
+                    self.phase = Phase::Settle;
+                }
+                else if restore && self.track == 0 {
+                    self.finish(NOT_FOUND);
+                }
+                else if self.track == self.data {
+                    self.phase = Phase::Settle;
+                }
+                else {
+                    self.step_in = self.data > self.track;
+                    self.track = if self.step_in { self.track.wrapping_add(1) } else { self.track.wrapping_sub(1) };
+                    self.step();
+                }
+            },
+            Phase::Step => {
+                if (self.command & UPDATE) != 0 {
+                    self.track = if self.step_in { self.track.wrapping_add(1) } else { self.track.wrapping_sub(1) };
+                }
+
+                self.step();
+                self.phase = Phase::Settle;
+            },
+            Phase::Settle => {
+                if (self.command & VERIFY) != 0 {
+                    self.phase = Phase::Verify;
+                    self.index_count = 0;
+                }
+                else {
+                    self.finish(0);
+                }
+            },
+            Phase::Verify => {
+                if id.is_some() && self.track == self.cylinder {
+                    self.finish(0);
+                }
+                else if self.index_count >= 5 {
+                    self.finish(NOT_FOUND);
+                }
+            },
+            Phase::Search => self.search(disk, side, id),
+            Phase::Read => {
+                // the next byte is handed over once the CPU has taken the last one
+                if (self.status & DRQ) != 0 {
+                    return;
+                }
+
+                if self.pos < self.buffer.len() {
+                    self.data = self.buffer[self.pos];
+                    self.pos += 1;
+                    self.status |= DRQ;
+                    self.delay = BYTE_TIME;
+                }
+                else if (self.command & 0xF0) == 0xC0 {
+                    // Read Address leaves the track of the ID in the sector register
+                    self.sector = self.buffer[0];
+                    self.finish(0);
+                }
+                else {
+                    self.next_sector();
+                }
+            },
+            Phase::Write => {
+                if (self.status & DRQ) != 0 {
+                    return;
+                }
+
+                self.buffer.push(self.data);
+                if self.buffer.len() < SECTOR_SIZE {
+                    self.status |= DRQ;
+                    self.delay = BYTE_TIME;
+                    return;
+                }
+
+                if let Some(image) = disk {
+                    let (track, sector) = location(self.cylinder, side, self.sector);
+                    for (i, data) in self.buffer.chunks(d64::SECTOR_SIZE).enumerate() {
+                        if image.write_sector(track, sector + i as u8, data).is_ok() {
+                            self.dirty = true;
+                        }
+                    }
+                }
+
+                self.next_sector();
+            },
+            Phase::WriteTrack => {
+                if (self.status & DRQ) != 0 {
+                    return;
+                }
+
+                self.buffer.push(self.data);
+                if self.buffer.len() < TRACK_SIZE {
+                    self.status |= DRQ;
+                    self.delay = BYTE_TIME;
+                    return;
+                }
+
+                if let Some(image) = disk {
+                    self.format_track(image, side);
+                }
+
+                self.finish(0);
+            },
+        }
+    }
+
+
+    // *** private functions *** //
+
+    // Force Interrupt ends any command, the other commands are ignored while the controller is busy
+    fn write_command(&mut self, value: u8) {
+        if (value & 0xF0) == 0xD0 {
+            if (self.status & BUSY) != 0 {
+                self.status &= !(BUSY | DRQ);
+            }
+            else {
+                self.type_one = true;
+                self.status = 0;
+            }
+
+            self.phase = Phase::Idle;
+            self.index_count = 0;
+            return;
+        }
+
+        if (self.status & BUSY) != 0 {
+            return;
+        }
+
+        self.command = value;
+        self.type_one = (value & 0x80) == 0;
+        self.status = BUSY;
+        self.delay = 0;
+        self.index_count = 0;
+
+        if (value & NO_SPIN_UP) == 0 && !self.motor {
+            self.motor = true;
+            self.phase = Phase::SpinUp;
+        }
+        else {
+            self.motor = true;
+            self.execute();
+        }
+    }
+
+
+    fn execute(&mut self) {
+        self.index_count = 0;
+
+        match self.command & 0xF0 {
+            0x00 => {
+                self.track = 0xFF;
+                self.data = 0;
+                self.phase = Phase::Seek;
+            },
+            0x10 => self.phase = Phase::Seek,
+            0x20 | 0x30 => self.phase = Phase::Step,
+            0x40 | 0x50 => {
+                self.step_in = true;
+                self.phase = Phase::Step;
+            },
+            0x60 | 0x70 => {
+                self.step_in = false;
+                self.phase = Phase::Step;
+            },
+            0x80 | 0x90 | 0xA0 | 0xB0 | 0xC0 => self.phase = Phase::Search,
+            0xF0 => {
+                self.buffer.clear();
+                self.status |= DRQ;
+                self.phase = Phase::WriteTrack;
+            },
+            // Read Track isn't used by the DOS and returns no data
+            _ => {
+                self.finish(0);
+                return;
+            },
+        }
+
+        if !self.type_one && (self.command & SETTLE) != 0 {
+            self.delay = SETTLE_TIME;
+        }
+    }
+
+
+    // wait for the addressed sector to pass the head - the search gives up after 5 revolutions
+    fn search(&mut self, disk: Option<&mut d64::D64>, side: u8, id: Option<u8>) {
+        match (id, disk) {
+            (Some(sector), _) if (self.command & 0xF0) == 0xC0 => {
+                let crc = crc16(&[0xA1, 0xA1, 0xA1, 0xFE, self.cylinder, side, sector, 2]);
+                self.buffer = vec![self.cylinder, side, sector, 2, (crc >> 8) as u8, crc as u8];
+                self.start_transfer(Phase::Read);
+                return;
+            },
+            (Some(sector), Some(image)) if sector == self.sector && self.track == self.cylinder => {
+                if (self.command & 0xE0) == 0xA0 {
+                    self.buffer.clear();
+                    self.start_transfer(Phase::Write);
+                    self.status |= DRQ;
+                    return;
+                }
+
+                let (track, first) = location(self.cylinder, side, sector);
+                let data = image.read_sector(track, first).and_then(|data| {
+                    image.read_sector(track, first + 1).map(|next| [data, next].concat())
+                });
+
+                if let Ok(data) = data {
+                    self.buffer = data;
+                    self.start_transfer(Phase::Read);
+                    return;
+                }
+            },
+            _ => (),
+        }
+
+        if self.index_count >= 5 {
+            self.finish(NOT_FOUND);
+        }
+    }
+
+
+    fn start_transfer(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.pos = 0;
+        self.delay = BYTE_TIME;
+    }
+
+
+    // a multi-sector command continues with the next sector
+    fn next_sector(&mut self) {
+        if (self.command & MULTIPLE) != 0 {
+            self.sector = self.sector.wrapping_add(1);
+            self.index_count = 0;
+            self.phase = Phase::Search;
+        }
+        else {
+            self.finish(0);
+        }
+    }
+
+
+    fn step(&mut self) {
+        if self.step_in {
+            if self.cylinder < MAX_CYLINDER {
+                self.cylinder += 1;
+            }
+        }
+        else if self.cylinder > 0 {
+            self.cylinder -= 1;
+        }
+
+        self.step_pulse = true;
+        self.delay = STEP_TIMES[(self.command & 0x03) as usize];
+    }
+
+
+    fn finish(&mut self, flags: u8) {
+        self.status = (self.status & !(BUSY | DRQ)) | flags;
+        self.phase = Phase::Idle;
+        self.index_count = 0;
+    }
+
+
+    // the track written by Write Track - $F5 writes an address mark, $F7 the CRC of the field; the data field
+    // following an ID field is stored in the sector named by the ID
+    fn format_track(&mut self, image: &mut d64::D64, side: u8) {
+        let mut sector: Option<u8> = None;
+        let mut i = 0;
+
+        while i + 4 <= self.buffer.len() {
+            if self.buffer[i..(i + 3)] != [0xF5, 0xF5, 0xF5] {
+                i += 1;
+                continue;
+            }
+
+            match self.buffer[i + 3] {
+                0xFE if i + 8 <= self.buffer.len() => {
+                    sector = Some(self.buffer[i + 6]);
+                    i += 8;
+                },
+                0xFB if i + 4 + SECTOR_SIZE <= self.buffer.len() => {
+                    if let Some(sector) = sector.take() {
+                        if sector >= 1 && sector <= SECTORS_PER_TRACK {
+                            let (track, first) = location(self.cylinder, side, sector);
+                            let data = &self.buffer[(i + 4)..(i + 4 + SECTOR_SIZE)];
+                            for (j, data) in data.chunks(d64::SECTOR_SIZE).enumerate() {
+                                if image.write_sector(track, first + j as u8, data).is_ok() {
+                                    self.dirty = true;
+                                }
+                            }
+                        }
+                    }
+                    i += 4 + SECTOR_SIZE;
+                },
+                _ => i += 4,
+            }
+        }
+    }
+}
+
+
+// track and first sector of the image holding a physical sector (numbered from 1) - each 512 byte sector holds
+// two logical sectors, the second side continues the track with sectors 20-39
+fn location(cylinder: u8, side: u8, sector: u8) -> (u8, u8) {
+    (cylinder + 1, 2 * (sector - 1) + 2 * SECTORS_PER_TRACK * side)
+}
+
+
+// CRC-CCITT of an MFM field, including its address mark
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if (crc & 0x8000) != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 }
+        })
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a D81 image - each sector starts with its track and sector number
+    fn image() -> d64::D64 {
+        let mut data = vec![0; 80 * 40 * d64::SECTOR_SIZE];
+        for (i, sector) in data.chunks_mut(d64::SECTOR_SIZE).enumerate() {
+            sector[0] = (i / 40) as u8 + 1;
+            sector[1] = (i % 40) as u8;
+        }
+
+        d64::D64::from_bytes(data).unwrap()
+    }
+
+
+    // run a command until the controller is done - the bytes it hands over are returned, the bytes it asks for
+    // are taken from input
+    fn run(fdc: &mut WD1770, image: &mut d64::D64, side: u8, command: u8, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::<u8>::new();
+        let mut input = input.iter();
+
+        fdc.write_register(0, command);
+        for _ in 0..(10 * REVOLUTION_TIME) {
+            let status = fdc.read_register(0);
+            if (status & BUSY) == 0 {
+                break;
+            }
+
+            if !fdc.type_one && (status & DRQ) != 0 {
+                if (command & 0xE0) == 0xA0 || (command & 0xF0) == 0xF0 {
+                    fdc.write_register(3, *input.next().unwrap_or(&0));
+                }
+                else {
+                    output.push(fdc.read_register(3));
+                }
+            }
+
+            fdc.update(Some(image), side, true);
+        }
+
+        output
+    }
+
+
+    #[test]
+    fn seek_and_restore() {
+        let mut fdc = WD1770::new();
+        let mut image = image();
+
+        fdc.write_register(3, 39);
+        run(&mut fdc, &mut image, 0, 0x1C, &[]);
+        assert_eq!((fdc.read_register(0) & (NOT_FOUND | TRACK_0 | BUSY), fdc.read_register(1)), (0, 39));
+        assert_eq!(fdc.cylinder, 39);
+
+        run(&mut fdc, &mut image, 0, 0x58, &[]);
+        assert_eq!((fdc.read_register(1), fdc.cylinder), (40, 40));
+        assert!(fdc.take_step_pulse());
+
+        // verify fails if the track register doesn't match the head position
+        fdc.write_register(1, 12);
+        run(&mut fdc, &mut image, 0, 0x6C, &[]);
+        assert_eq!(fdc.read_register(0) & NOT_FOUND, NOT_FOUND);
+
+        run(&mut fdc, &mut image, 0, 0x08, &[]);
+        assert_eq!((fdc.read_register(0) & (NOT_FOUND | TRACK_0), fdc.read_register(1)), (TRACK_0, 0));
+        assert_eq!(fdc.cylinder, 0);
+    }
+
+
+    #[test]
+    fn read_sectors() {
+        let mut fdc = WD1770::new();
+        let mut image = image();
+
+        fdc.write_register(3, 2);
+        run(&mut fdc, &mut image, 0, 0x18, &[]);
+
+        // each physical sector holds two sectors of the image, the second side the second half of the track
+        fdc.write_register(2, 4);
+        let data = run(&mut fdc, &mut image, 1, 0x88, &[]);
+        assert_eq!(data.len(), SECTOR_SIZE);
+        assert_eq!((data[0], data[1], data[256], data[257]), (3, 26, 3, 27));
+
+        // multi-sector reads continue up to the end of the track
+        fdc.write_register(2, 9);
+        let data = run(&mut fdc, &mut image, 0, 0x98, &[]);
+        assert_eq!(data.len(), 2 * SECTOR_SIZE);
+        assert_eq!((data[512], data[513]), (3, 18));
+        assert_eq!(fdc.read_register(0) & NOT_FOUND, NOT_FOUND);
+
+        let id = run(&mut fdc, &mut image, 1, 0xC8, &[]);
+        assert_eq!(&id[..4], &[2, 1, 1, 2]);
+        assert_eq!(crc16(&[0xA1, 0xA1, 0xA1, 0xFE, 2, 1, 1, 2]), ((id[4] as u16) << 8) | id[5] as u16);
+        assert_eq!(fdc.read_register(2), 2);
+    }
+
+
+    #[test]
+    fn missing_sectors() {
+        let mut fdc = WD1770::new();
+        let mut image = image();
+
+        fdc.write_register(2, 11);
+        run(&mut fdc, &mut image, 0, 0x88, &[]);
+        assert_eq!(fdc.read_register(0) & (NOT_FOUND | BUSY), NOT_FOUND);
+
+        // the track register must match the track of the ID
+        fdc.write_register(1, 5);
+        fdc.write_register(2, 1);
+        assert!(run(&mut fdc, &mut image, 0, 0x88, &[]).is_empty());
+        assert_eq!(fdc.read_register(0) & NOT_FOUND, NOT_FOUND);
+
+        // without a turning disk the search doesn't end
+        fdc.write_register(0, 0x88);
+        for _ in 0..(10 * REVOLUTION_TIME) {
+            fdc.update(None, 0, false);
+        }
+        assert_eq!(fdc.read_register(0) & BUSY, BUSY);
+        fdc.write_register(0, 0xD0);
+        assert_eq!(fdc.read_register(0) & BUSY, 0);
+    }
+
+
+    #[test]
+    fn write_sector() {
+        let mut fdc = WD1770::new();
+        let mut image = image();
+
+        fdc.write_register(1, 0);
+        fdc.write_register(2, 10);
+        run(&mut fdc, &mut image, 1, 0xA8, &[0x5A; SECTOR_SIZE]);
+        assert_eq!(fdc.read_register(0) & (NOT_FOUND | BUSY), 0);
+        assert!(fdc.dirty);
+
+        assert_eq!(image.read_sector(1, 38).unwrap(), &[0x5A; 256][..]);
+        assert_eq!(image.read_sector(1, 39).unwrap(), &[0x5A; 256][..]);
+        assert_eq!(image.read_sector(1, 18).unwrap()[..2], [1, 18]);
+    }
+
+
+    #[test]
+    fn format_track() {
+        let mut fdc = WD1770::new();
+        let mut image = image();
+
+        // ID and data fields of sectors 1 and 2 as the DOS writes them, the rest of the track is filled with gaps
+        let mut track = vec![0x4E; 60];
+        for sector in 1..3 {
+            track.extend_from_slice(&[0x00; 12]);
+            track.extend_from_slice(&[0xF5, 0xF5, 0xF5, 0xFE, 0, 0, sector, 2, 0xF7]);
+            track.extend_from_slice(&[0x4E; 22]);
+            track.extend_from_slice(&[0x00; 12]);
+            track.extend_from_slice(&[0xF5, 0xF5, 0xF5, 0xFB]);
+            track.extend(vec![sector; SECTOR_SIZE]);
+            track.extend_from_slice(&[0xF7, 0x4E, 0x4E]);
+        }
+
+        run(&mut fdc, &mut image, 0, 0xF8, &track);
+        assert_eq!(fdc.read_register(0) & BUSY, 0);
+
+        assert_eq!(image.read_sector(1, 0).unwrap(), &[1; 256][..]);
+        assert_eq!(image.read_sector(1, 3).unwrap(), &[2; 256][..]);
+        assert_eq!(image.read_sector(1, 4).unwrap()[..2], [1, 4]);
+    }
+}
@@ -1,5 +1,5 @@
 // bitmap font used in debugger window
-use utils;
+use rust64::utils;
 
 pub struct SysFont {
     data: Vec<u8>
@@ -3,10 +3,10 @@ extern crate minifb;
 
 mod font;
 
-use c64;
 use minifb::*;
+use rust64::c64;
+use rust64::utils;
 use std::io::Write;
-use utils;
 
 const DEBUG_W: usize = 640;
 const DEBUG_H: usize = 432;
@@ -0,0 +1,119 @@
+// "rust64 disk" subcommand - maintains disk images from the command line, like the c1541 tool of other emulators
+use rust64::c64::d64;
+use std::fs;
+use std::path::Path;
+
+const USAGE: &'static str = "Usage: rust64 disk <image> <command>
+Commands:
+  format NAME,ID          create a new D64 image or format an existing one
+  dir                     print the directory
+  write <file> [NAME]     copy a host file to the image (*.seq as SEQ, *.usr as USR, everything else as PRG)
+  delete PATTERN          scratch all files matching the pattern ('*' and '?' wildcards)
+  rename OLD NEW          rename a file
+  validate                rebuild the BAM from the directory and scratch unclosed files";
+
+
+// run the subcommand with the arguments following "disk" - usage is printed if they're incomplete
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.len() < 2 {
+        println!("{}", USAGE);
+        return Ok(())
+    }
+
+    let filename = &args[0];
+
+    match (args[1].as_str(), args.len()) {
+        ("format", 3) => format(filename, &args[2]),
+        ("dir", 2) => {
+            let image = d64::D64::from_filename(filename)?;
+            print_directory(&image)
+        },
+        ("write", 3) | ("write", 4) => {
+            let mut image = d64::D64::from_filename(filename)?;
+            write(&mut image, &args[2], args.get(3))?;
+            image.save(filename)
+        },
+        ("delete", 3) => {
+            let mut image = d64::D64::from_filename(filename)?;
+            let num_deleted = image.delete_file(&d64::ascii_to_petscii(&args[2]))?;
+            image.save(filename)?;
+            println!("{} files scratched", num_deleted);
+            Ok(())
+        },
+        ("rename", 4) => {
+            let mut image = d64::D64::from_filename(filename)?;
+            image.rename_file(&d64::ascii_to_petscii(&args[2]), &d64::ascii_to_petscii(&args[3]))?;
+            image.save(filename)
+        },
+        ("validate", 2) => {
+            let mut image = d64::D64::from_filename(filename)?;
+            let num_fixed = image.validate()?;
+            image.save(filename)?;
+            println!("{} sectors corrected", num_fixed);
+            Ok(())
+        },
+        _ => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
+}
+
+
+// *** private functions *** //
+
+// the header is given as "NAME,ID" like in the format command of the DOS
+fn format(filename: &str, header: &str) -> Result<(), String> {
+    let lower_name = filename.to_lowercase();
+    if lower_name.ends_with(".d71") || lower_name.ends_with(".d81") {
+        return Err("Only D64 images can be formatted".to_string())
+    }
+
+    let mut parts = header.splitn(2, ',');
+    let name = d64::ascii_to_petscii(parts.next().unwrap_or(""));
+    let id = d64::ascii_to_petscii(parts.next().unwrap_or("00"));
+    if name.is_empty() || name.len() > 16 || id.len() > 2 {
+        return Err("Invalid disk header".to_string())
+    }
+
+    d64::D64::new(&name, &id).save(filename)
+}
+
+
+// the file name on the image defaults to the host file name without its extension
+fn write(image: &mut d64::D64, host_filename: &str, name: Option<&String>) -> Result<(), String> {
+    let path = Path::new(host_filename);
+    let contents = fs::read(path).map_err(|e| e.to_string())?;
+
+    let file_type = match path.extension().map(|ext| ext.to_string_lossy().to_lowercase()) {
+        Some(ref ext) if ext == "seq" => d64::FileType::Seq,
+        Some(ref ext) if ext == "usr" => d64::FileType::Usr,
+        _ => d64::FileType::Prg,
+    };
+
+    let name = match name {
+        Some(name) => name.clone(),
+        None => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+    };
+
+    let petscii_name = d64::ascii_to_petscii(&name);
+    if image.find_file(&petscii_name)?.is_some() {
+        return Err("File exists".to_string())
+    }
+
+    image.write_file(&petscii_name, file_type, &contents)
+}
+
+
+// the listing as shown by LOAD"$",8 and LIST
+fn print_directory(image: &d64::D64) -> Result<(), String> {
+    let disk_id = image.disk_id();
+    println!("0 \"{:<16}\" {}", d64::petscii_to_ascii(&image.disk_name()), d64::petscii_to_ascii(&disk_id));
+
+    for entry in image.directory()?.iter().filter(|entry| entry.file_type != d64::FileType::Del) {
+        println!("{}", entry);
+    }
+
+    println!("{} blocks free.", image.free_sectors());
+    Ok(())
+}
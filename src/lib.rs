@@ -0,0 +1,13 @@
+// Rust64 library - the emulated machine without any window or audio device attached
+extern crate byteorder;
+extern crate num;
+
+#[macro_use]
+extern crate enum_primitive;
+
+#[macro_use]
+pub mod utils;
+pub mod c64;
+
+pub use c64::{C64, Config};
+pub use c64::frontend::{Frontend, Headless, Key};
@@ -1,47 +1,170 @@
 extern crate minifb;
-extern crate byteorder;
-extern crate num;
+extern crate rust64;
 
-#[macro_use]
-extern crate enum_primitive;
-
-#[macro_use]
-mod utils;
-mod c64;
 mod debugger;
+mod disk;
+mod window;
 
 use minifb::*;
+use rust64::c64;
+use rust64::c64::d64;
+use rust64::c64::movie;
+use rust64::c64::p00;
+use rust64::Frontend;
 use std::env;
+use std::path::Path;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    let mut prg_to_load  = String::new();
-    let mut crt_to_load  = String::new();
+    // disk image maintenance runs without starting the emulator
+    if args.len() > 1 && args[1] == "disk" {
+        if let Err(e) = disk::run(&args[2..]) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let mut config       = c64::Config::default();
     let mut debugger_on  = false;
+    let mut headless     = false;
     let mut window_scale = Scale::X1;
+    let mut record_file: Option<String> = None;
+    let mut play_file:   Option<String> = None;
 
     // process cmd line params
     for i in 1..args.len() {
         if args[i] == "debugger" {
             debugger_on = true;
         }
+        else if args[i] == "headless" {
+            headless = true;
+        }
+        else if args[i] == "ntsc" {
+            config.model = c64::Model::Ntsc;
+        }
+        else if args[i] == "ntsc-old" {
+            config.model = c64::Model::NtscOld;
+        }
+        else if args[i] == "1541" {
+            config.true_drive = true;
+        }
+        else if args[i] == "1571" {
+            config.true_drive = true;
+            config.drive_model = c64::c1541::DriveModel::C1571;
+        }
+        else if args[i] == "1581" {
+            config.true_drive = true;
+            config.drive_model = c64::c1541::DriveModel::C1581;
+        }
+        else if args[i] == "autostart" {
+            config.autostart = true;
+        }
+        else if args[i].starts_with("autostart_delay=") {
+            match args[i]["autostart_delay=".len()..].parse::<u32>() {
+                Ok(delay) => config.autostart_delay = delay,
+                _ => println!("Invalid autostart delay: {}", args[i]),
+            }
+        }
+        else if args[i] == "warp" {
+            config.warp = true;
+        }
+        else if args[i].starts_with("speed=") {
+            match args[i]["speed=".len()..].parse::<u32>() {
+                Ok(speed) if speed > 0 => config.speed = speed,
+                _ => println!("Invalid speed: {}", args[i]),
+            }
+        }
+        else if args[i].starts_with("seed=") {
+            match args[i]["seed=".len()..].parse::<u32>() {
+                Ok(seed) => config.seed = seed,
+                _ => println!("Invalid seed: {}", args[i]),
+            }
+        }
+        else if args[i].starts_with("drive8=") {
+            config.drive8 = Some(args[i]["drive8=".len()..].to_string());
+        }
+        else if args[i].starts_with("record=") {
+            record_file = Some(args[i]["record=".len()..].to_string());
+        }
+        else if args[i].starts_with("record_tap=") {
+            config.tap_record_file = Some(args[i]["record_tap=".len()..].to_string());
+        }
+        else if args[i].starts_with("play=") {
+            play_file = Some(args[i]["play=".len()..].to_string());
+        }
         else if args[i] == "x2" {
             window_scale = Scale::X2;
         }
-        else if args[i].ends_with(".prg") {
-            prg_to_load = args[i].clone();
+        else if args[i].ends_with(".prg") || p00::file_type(Path::new(&args[i])) == Some(d64::FileType::Prg) {
+            config.prg_file = Some(args[i].clone());
         }
         else if args[i].ends_with(".crt") {
-            crt_to_load = args[i].clone();
+            config.crt_file = Some(args[i].clone());
         }
+        else if args[i].ends_with(".t64") {
+            config.t64_file = Some(args[i].clone());
+        }
+        else if args[i].ends_with(".tap") {
+            config.tap_file = Some(args[i].clone());
+        }
+        else if args[i].ends_with(".d64") || args[i].ends_with(".d71") || args[i].ends_with(".d81") {
+            config.drive8 = Some(args[i].clone());
+        }
+        else if args[i].ends_with(".g64") {
+            config.drive8 = Some(args[i].clone());
+            config.true_drive = true;
+        }
+        else if args[i].ends_with(".snap") {
+            config.snapshot_file = Some(args[i].clone());
+        }
+        else if args[i].starts_with('-') {
+            println!("Unknown option: {} (options are given without dashes, e.g. autostart_delay=100)", args[i]);
+        }
+    }
+
+    let mut frontend: Box<dyn Frontend> = if headless {
+        Box::new(rust64::Headless)
     }
-    
-    let mut c64 = c64::C64::new(window_scale, debugger_on, &prg_to_load, &crt_to_load);
+    else {
+        Box::new(window::WindowFrontend::new(window_scale, debugger_on))
+    };
+
+    let snapshot_file = config.snapshot_file.clone();
+    let mut c64 = c64::C64::new(config);
     c64.reset();
 
+    // resume from an existing snapshot - otherwise it will be created with F10
+    if let Some(ref filename) = snapshot_file {
+        if Path::new(filename).exists() {
+            if let Err(e) = c64.load_snapshot(filename) {
+                println!("Can't restore {}: {}", filename, e);
+            }
+        }
+    }
+
+    if let Some(ref filename) = play_file {
+        match movie::Movie::from_filename(filename).and_then(|movie| c64.start_playback(movie)) {
+            Ok(_)  => println!("Playing back {}", filename),
+            Err(e) => println!("Can't play back {}: {}", filename, e),
+        }
+    }
+
+    if record_file.is_some() {
+        c64.start_recording();
+    }
+
     // main update loop
-    while c64.main_window.is_open() {
-        c64.run();
+    while frontend.is_open() {
+        c64.run(&mut *frontend);
+    }
+
+    if let (Some(filename), Some(movie)) = (record_file, c64.stop_recording()) {
+        match movie.save(&filename) {
+            Ok(_)  => println!("Recorded {} input events to {}", movie.events.len(), filename),
+            Err(e) => println!("Can't save {}: {}", filename, e),
+        }
     }
 }
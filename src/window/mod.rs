@@ -0,0 +1,204 @@
+// minifb window frontend with SDL2 audio output and the optional debugger windows
+extern crate minifb;
+#[cfg(not(target_os = "redox"))]
+extern crate sdl2;
+
+#[cfg(not(target_os = "redox"))]
+use self::sdl2::audio::{ AudioQueue, AudioSpecDesired };
+use debugger;
+use minifb::{ KeyRepeat, Scale, Window, WindowOptions };
+use rust64::c64;
+use rust64::{ Frontend, Key };
+
+
+pub struct WindowFrontend {
+    main_window: minifb::Window,
+    debugger: Option<debugger::Debugger>,
+    #[cfg(not(target_os = "redox"))]
+    audio_queue: AudioQueue<i16>,
+}
+
+impl WindowFrontend {
+    #[cfg(not(target_os = "redox"))]
+    pub fn new(window_scale: Scale, debugger_on: bool) -> WindowFrontend {
+        let sdl_context = sdl2::init().unwrap();
+        let audio_subsystem = sdl_context.audio().unwrap();
+
+        let desired_spec = AudioSpecDesired {
+            freq: Some(c64::sid::SAMPLE_FREQ as i32),
+            channels: Some(1),  // mono
+            samples: Some(512), // default sample size
+        };
+
+        let audio_queue = audio_subsystem.open_queue::<i16, _>(None, &desired_spec).unwrap();
+        audio_queue.resume();
+
+        WindowFrontend {
+            main_window: WindowFrontend::open_main_window(window_scale),
+            debugger: if debugger_on { Some(debugger::Debugger::new()) } else { None },
+            audio_queue: audio_queue,
+        }
+    }
+
+
+    #[cfg(target_os = "redox")]
+    pub fn new(window_scale: Scale, debugger_on: bool) -> WindowFrontend {
+        WindowFrontend {
+            main_window: WindowFrontend::open_main_window(window_scale),
+            debugger: if debugger_on { Some(debugger::Debugger::new()) } else { None },
+        }
+    }
+
+
+    // *** private functions *** //
+
+    fn open_main_window(window_scale: Scale) -> minifb::Window {
+        let mut window = Window::new("Rust64", c64::SCREEN_WIDTH, c64::SCREEN_HEIGHT, WindowOptions { scale: window_scale, ..Default::default() }).unwrap();
+        window.set_position(75, 20);
+        window
+    }
+
+
+    fn to_minifb_key(&self, key: Key) -> minifb::Key {
+        match key {
+            Key::Key0 => minifb::Key::Key0,
+            Key::Key1 => minifb::Key::Key1,
+            Key::Key2 => minifb::Key::Key2,
+            Key::Key3 => minifb::Key::Key3,
+            Key::Key4 => minifb::Key::Key4,
+            Key::Key5 => minifb::Key::Key5,
+            Key::Key6 => minifb::Key::Key6,
+            Key::Key7 => minifb::Key::Key7,
+            Key::Key8 => minifb::Key::Key8,
+            Key::Key9 => minifb::Key::Key9,
+            Key::A => minifb::Key::A,
+            Key::B => minifb::Key::B,
+            Key::C => minifb::Key::C,
+            Key::D => minifb::Key::D,
+            Key::E => minifb::Key::E,
+            Key::F => minifb::Key::F,
+            Key::G => minifb::Key::G,
+            Key::H => minifb::Key::H,
+            Key::I => minifb::Key::I,
+            Key::J => minifb::Key::J,
+            Key::K => minifb::Key::K,
+            Key::L => minifb::Key::L,
+            Key::M => minifb::Key::M,
+            Key::N => minifb::Key::N,
+            Key::O => minifb::Key::O,
+            Key::P => minifb::Key::P,
+            Key::Q => minifb::Key::Q,
+            Key::R => minifb::Key::R,
+            Key::S => minifb::Key::S,
+            Key::T => minifb::Key::T,
+            Key::U => minifb::Key::U,
+            Key::V => minifb::Key::V,
+            Key::W => minifb::Key::W,
+            Key::X => minifb::Key::X,
+            Key::Y => minifb::Key::Y,
+            Key::Z => minifb::Key::Z,
+            Key::F1  => minifb::Key::F1,
+            Key::F2  => minifb::Key::F2,
+            Key::F3  => minifb::Key::F3,
+            Key::F4  => minifb::Key::F4,
+            Key::F5  => minifb::Key::F5,
+            Key::F6  => minifb::Key::F6,
+            Key::F7  => minifb::Key::F7,
+            Key::F8  => minifb::Key::F8,
+            Key::F9  => minifb::Key::F9,
+            Key::F10 => minifb::Key::F10,
+            Key::F11 => minifb::Key::F11,
+            Key::F12 => minifb::Key::F12,
+            Key::Down   => minifb::Key::Down,
+            Key::Up     => minifb::Key::Up,
+            Key::Right  => minifb::Key::Right,
+            Key::Left   => minifb::Key::Left,
+            Key::Space  => minifb::Key::Space,
+            Key::Comma  => minifb::Key::Comma,
+            Key::Period => minifb::Key::Period,
+            Key::Slash  => minifb::Key::Slash,
+            Key::Enter  => minifb::Key::Enter,
+            Key::Backspace  => minifb::Key::Backspace,
+            Key::Backquote  => minifb::Key::Backquote,
+            Key::LeftShift  => minifb::Key::LeftShift,
+            Key::RightShift => minifb::Key::RightShift,
+            Key::LeftCtrl   => minifb::Key::LeftCtrl,
+            Key::RightCtrl  => minifb::Key::RightCtrl,
+            Key::LeftAlt    => minifb::Key::LeftAlt,
+            Key::RightAlt   => minifb::Key::RightAlt,
+            Key::Escape => minifb::Key::Escape,
+            Key::Tab    => minifb::Key::Tab,
+            Key::Minus  => minifb::Key::Minus,
+            Key::Equal  => minifb::Key::Equal,
+            Key::Insert => minifb::Key::Insert,
+            Key::Home   => minifb::Key::Home,
+            Key::End    => minifb::Key::End,
+            Key::Delete => minifb::Key::Delete,
+            Key::PageUp => minifb::Key::PageUp,
+            Key::PageDown => minifb::Key::PageDown,
+            Key::LeftBracket  => minifb::Key::LeftBracket,
+            Key::RightBracket => minifb::Key::RightBracket,
+            Key::Semicolon  => minifb::Key::Semicolon,
+            Key::Apostrophe => minifb::Key::Apostrophe,
+            Key::Backslash  => minifb::Key::Backslash,
+            Key::NumLock => minifb::Key::NumLock,
+            Key::NumPad1 => minifb::Key::NumPad1,
+            Key::NumPad2 => minifb::Key::NumPad2,
+            Key::NumPad3 => minifb::Key::NumPad3,
+            Key::NumPad4 => minifb::Key::NumPad4,
+            Key::NumPad5 => minifb::Key::NumPad5,
+            Key::NumPad6 => minifb::Key::NumPad6,
+            Key::NumPad7 => minifb::Key::NumPad7,
+            Key::NumPad8 => minifb::Key::NumPad8,
+            Key::NumPad9 => minifb::Key::NumPad9,
+        }
+    }
+}
+
+
+impl Frontend for WindowFrontend {
+    fn is_open(&self) -> bool {
+        self.main_window.is_open()
+    }
+
+
+    fn draw_frame(&mut self, buffer: &[u32]) {
+        let _ = self.main_window.update_with_buffer(buffer);
+    }
+
+
+    #[cfg(not(target_os = "redox"))]
+    fn queue_audio(&mut self, samples: &[i16]) {
+        let _ = self.audio_queue.queue(samples);
+    }
+
+
+    #[cfg(target_os = "redox")]
+    fn queue_audio(&mut self, _samples: &[i16]) {}
+
+
+    fn is_key_down(&self, key: Key) -> bool {
+        self.main_window.is_key_down(self.to_minifb_key(key))
+    }
+
+
+    fn is_key_pressed(&self, key: Key) -> bool {
+        self.main_window.is_key_pressed(self.to_minifb_key(key), KeyRepeat::No)
+    }
+
+
+    fn on_cycle(&mut self, vic: &mut c64::vic::VICShared) {
+        match self.debugger {
+            Some(ref mut dbg) => dbg.update_vic_window(vic),
+            None => (),
+        }
+    }
+
+
+    fn on_frame(&mut self, cpu: &mut c64::cpu::CPUShared, memory: &mut c64::memory::MemShared) {
+        match self.debugger {
+            Some(ref mut dbg) => dbg.render(cpu, memory),
+            None => (),
+        }
+    }
+}